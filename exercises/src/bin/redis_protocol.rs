@@ -56,7 +56,7 @@ fn main() {
             let log = log.new(o!());
             client::start(log, DEFAULT_ADDRESS_PORT)
         }
-        other => panic!(format!("unexpected arg `{}`", other)),
+        other => panic!("unexpected arg `{}`", other),
     }
 }
 
@@ -488,7 +488,7 @@ mod serialize {
         Ok(())
     }
 
-    impl<'a, W> serde::ser::Serializer for &'a mut Serializer<W>
+    impl<W> serde::ser::Serializer for &mut Serializer<W>
     where
         W: Write,
     {
@@ -582,9 +582,9 @@ mod serialize {
             Ok(())
         }
 
-        fn serialize_some<T: ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error>
+        fn serialize_some<T>(self, value: &T) -> Result<Self::Ok, Self::Error>
         where
-            T: Serialize,
+            T: ?Sized + Serialize,
         {
             value.serialize(self)
         }
@@ -606,18 +606,18 @@ mod serialize {
             todo!()
         }
 
-        fn serialize_newtype_struct<T: ?Sized>(
+        fn serialize_newtype_struct<T>(
             self,
             _name: &'static str,
             _value: &T,
         ) -> Result<Self::Ok, Self::Error>
         where
-            T: Serialize,
+            T: ?Sized + Serialize,
         {
             todo!()
         }
 
-        fn serialize_newtype_variant<T: ?Sized>(
+        fn serialize_newtype_variant<T>(
             self,
             _name: &'static str,
             _variant_index: u32,
@@ -625,7 +625,7 @@ mod serialize {
             value: &T,
         ) -> Result<Self::Ok, Self::Error>
         where
-            T: Serialize,
+            T: ?Sized + Serialize,
         {
             match variant {
                 "SimpleString" => {
@@ -650,7 +650,7 @@ mod serialize {
 
         fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
             self.writer.write_all(b"*")?;
-            let len = len.ok_or_else(|| Error::ExpectedKnownLength)?;
+            let len = len.ok_or(Error::ExpectedKnownLength)?;
             self.writer.write_all(len.to_string().as_bytes())?;
             self.writer.write_all(CRLF)?;
             Ok(self)
@@ -702,7 +702,7 @@ mod serialize {
         }
     }
 
-    impl<'a, W> serde::ser::SerializeSeq for &'a mut Serializer<W>
+    impl<W> serde::ser::SerializeSeq for &mut Serializer<W>
     where
         W: Write,
     {
@@ -710,9 +710,9 @@ mod serialize {
 
         type Error = Error;
 
-        fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+        fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
         where
-            T: Serialize,
+            T: ?Sized + Serialize,
         {
             value.serialize(&mut **self)?;
             Ok(())
@@ -724,7 +724,7 @@ mod serialize {
         }
     }
 
-    impl<'a, W> serde::ser::SerializeTuple for &'a mut Serializer<W>
+    impl<W> serde::ser::SerializeTuple for &mut Serializer<W>
     where
         W: Write,
     {
@@ -732,9 +732,9 @@ mod serialize {
 
         type Error = Error;
 
-        fn serialize_element<T: ?Sized>(&mut self, _value: &T) -> Result<(), Self::Error>
+        fn serialize_element<T>(&mut self, _value: &T) -> Result<(), Self::Error>
         where
-            T: Serialize,
+            T: ?Sized + Serialize,
         {
             todo!()
         }
@@ -745,16 +745,16 @@ mod serialize {
         }
     }
 
-    impl<'a, W> serde::ser::SerializeTupleStruct for &'a mut Serializer<W>
+    impl<W> serde::ser::SerializeTupleStruct for &mut Serializer<W>
     where
         W: Write,
     {
         type Ok = ();
         type Error = Error;
 
-        fn serialize_field<T: ?Sized>(&mut self, _value: &T) -> Result<(), Self::Error>
+        fn serialize_field<T>(&mut self, _value: &T) -> Result<(), Self::Error>
         where
-            T: Serialize,
+            T: ?Sized + Serialize,
         {
             todo!()
         }
@@ -765,16 +765,16 @@ mod serialize {
         }
     }
 
-    impl<'a, W> serde::ser::SerializeTupleVariant for &'a mut Serializer<W>
+    impl<W> serde::ser::SerializeTupleVariant for &mut Serializer<W>
     where
         W: Write,
     {
         type Ok = ();
         type Error = Error;
 
-        fn serialize_field<T: ?Sized>(&mut self, _value: &T) -> Result<(), Self::Error>
+        fn serialize_field<T>(&mut self, _value: &T) -> Result<(), Self::Error>
         where
-            T: Serialize,
+            T: ?Sized + Serialize,
         {
             todo!()
         }
@@ -785,23 +785,23 @@ mod serialize {
         }
     }
 
-    impl<'a, W> serde::ser::SerializeMap for &'a mut Serializer<W>
+    impl<W> serde::ser::SerializeMap for &mut Serializer<W>
     where
         W: Write,
     {
         type Ok = ();
         type Error = Error;
 
-        fn serialize_key<T: ?Sized>(&mut self, _key: &T) -> Result<(), Self::Error>
+        fn serialize_key<T>(&mut self, _key: &T) -> Result<(), Self::Error>
         where
-            T: Serialize,
+            T: ?Sized + Serialize,
         {
             todo!()
         }
 
-        fn serialize_value<T: ?Sized>(&mut self, _value: &T) -> Result<(), Self::Error>
+        fn serialize_value<T>(&mut self, _value: &T) -> Result<(), Self::Error>
         where
-            T: Serialize,
+            T: ?Sized + Serialize,
         {
             todo!()
         }
@@ -812,20 +812,16 @@ mod serialize {
         }
     }
 
-    impl<'a, W> serde::ser::SerializeStruct for &'a mut Serializer<W>
+    impl<W> serde::ser::SerializeStruct for &mut Serializer<W>
     where
         W: Write,
     {
         type Ok = ();
         type Error = Error;
 
-        fn serialize_field<T: ?Sized>(
-            &mut self,
-            _key: &'static str,
-            _value: &T,
-        ) -> Result<(), Self::Error>
+        fn serialize_field<T>(&mut self, _key: &'static str, _value: &T) -> Result<(), Self::Error>
         where
-            T: Serialize,
+            T: ?Sized + Serialize,
         {
             todo!()
         }
@@ -836,20 +832,16 @@ mod serialize {
         }
     }
 
-    impl<'a, W> serde::ser::SerializeStructVariant for &'a mut Serializer<W>
+    impl<W> serde::ser::SerializeStructVariant for &mut Serializer<W>
     where
         W: Write,
     {
         type Ok = ();
         type Error = Error;
 
-        fn serialize_field<T: ?Sized>(
-            &mut self,
-            _key: &'static str,
-            value: &T,
-        ) -> Result<(), Self::Error>
+        fn serialize_field<T>(&mut self, _key: &'static str, value: &T) -> Result<(), Self::Error>
         where
-            T: Serialize,
+            T: ?Sized + Serialize,
         {
             value.serialize(&mut **self)?;
             Ok(())
@@ -960,9 +952,10 @@ mod deserialize {
             Ok(out)
         }
 
+        #[allow(dead_code)]
         fn read_to_i32_until_crlf(&mut self) -> Result<i32, Self::Error> {
             let s = self.read_to_string_until_crlf()?;
-            let len = i32::from_str_radix(&s, 10)?;
+            let len = s.parse::<i32>()?;
             Ok(len)
         }
     }
@@ -973,6 +966,7 @@ mod deserialize {
     {
         type Error = Error;
 
+        #[allow(clippy::unbuffered_bytes)]
         fn read_until_crlf(&mut self) -> Result<Vec<u8>, Self::Error> {
             let bytes = self.bytes();
             let mut buf = Vec::new();
@@ -1010,6 +1004,7 @@ mod deserialize {
     struct TypeLength(usize);
 
     impl TypeLength {
+        #[allow(clippy::unbuffered_bytes)]
         fn read(reader: &mut impl Read) -> Result<Self, Error> {
             let bytes = reader.bytes();
             let mut buf = Vec::new();
@@ -1023,8 +1018,9 @@ mod deserialize {
             // Trim CRLF.
             buf.truncate(buf.len() - 2);
             let s = String::from_utf8(buf)?;
-            let len =
-                usize::from_str_radix(&s, 10).map_err(|_| Error::ExpectedDigits { found: s })?;
+            let len = s
+                .parse::<usize>()
+                .map_err(|_| Error::ExpectedDigits { found: s })?;
             Ok(TypeLength(len))
         }
     }
@@ -1043,9 +1039,10 @@ mod deserialize {
     }
 
     impl TypeIdent {
+        #[allow(clippy::unbuffered_bytes)]
         fn read(reader: &mut impl Read) -> Result<Self, Error> {
             let mut bytes = reader.bytes();
-            let first_byte = bytes.next().ok_or_else(|| Error::ExpectedByte)??;
+            let first_byte = bytes.next().ok_or(Error::ExpectedByte)??;
             let ty = match &first_byte {
                 b'+' => TypeIdent::SimpleString,
                 b'-' => TypeIdent::Error,
@@ -1060,10 +1057,7 @@ mod deserialize {
                 }
                 _ => {
                     // return Err(Error::UnknownType)
-                    panic!(format!(
-                        "unknown type, first byte is `{}`",
-                        first_byte as char
-                    ))
+                    panic!("unknown type, first byte is `{}`", first_byte as char)
                 }
             };
             Ok(ty)
@@ -1079,7 +1073,7 @@ mod deserialize {
         Ok(t)
     }
 
-    impl<'de, 'a, R> serde::de::Deserializer<'de> for &'a mut Deserializer<'de, R>
+    impl<'de, R> serde::de::Deserializer<'de> for &mut Deserializer<'de, R>
     where
         R: Read,
     {
@@ -1123,7 +1117,8 @@ mod deserialize {
                     let mut buf = self.reader.read_until_crlf()?;
                     buf.trim_crlf();
                     let buf = String::from_utf8(buf)?;
-                    let num = i32::from_str_radix(&buf, 10)
+                    let num = buf
+                        .parse::<i32>()
                         .map_err(|_| Error::ExpectedDigits { found: buf })?;
                     visitor.visit_i32(num)
                 }
@@ -1263,13 +1258,13 @@ mod deserialize {
             todo!()
         }
 
-        fn deserialize_seq<V>(mut self, visitor: V) -> Result<V::Value, Self::Error>
+        fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value, Self::Error>
         where
             V: Visitor<'de>,
         {
             let ty = TypeIdent::read(self.reader)?;
             match ty {
-                TypeIdent::Array(TypeLength(len)) => visitor.visit_seq(Array::new(&mut self, len)),
+                TypeIdent::Array(TypeLength(len)) => visitor.visit_seq(Array::new(self, len)),
                 _ => Err(Error::ExpectedArrayIdent),
             }
         }
@@ -1,30 +1,125 @@
-use crate::{KvsEngine, KvsEngineError};
+use crate::{
+    engine::{BatchOp, Entries, WatchEvent, Watcher},
+    KvsEngine, KvsEngineError,
+};
+use std::{fmt, ops::Bound, path::Path, str::FromStr};
 
 impl From<sled::Error> for KvsEngineError {
     fn from(value: sled::Error) -> Self {
-        KvsEngineError::Other(Box::new(value))
+        match value {
+            sled::Error::Io(err) => KvsEngineError::Io(err),
+            sled::Error::Corruption { at, .. } => {
+                KvsEngineError::Corruption(format!("sled storage at {:?}", at))
+            }
+            other => KvsEngineError::Other(Box::new(other)),
+        }
     }
 }
 
-impl KvsEngine for sled::Db {
-    fn set(&mut self, key: String, value: String) -> Result<(), KvsEngineError> {
-        self.insert(key.as_bytes(), value.as_bytes())?;
-        (self as &sled::Tree).flush()?;
+fn decode(bytes: &[u8]) -> Result<String, KvsEngineError> {
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| KvsEngineError::Corruption("sled entry is not valid utf-8".to_owned()))
+}
+
+/// How sled trades disk space for write throughput.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SledMode {
+    LowSpace,
+    HighThroughput,
+}
+
+impl FromStr for SledMode {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "low-space" => Ok(SledMode::LowSpace),
+            "high-throughput" => Ok(SledMode::HighThroughput),
+            other => Err(format!("unknown sled mode `{}`", other).into()),
+        }
+    }
+}
+
+impl fmt::Display for SledMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SledMode::LowSpace => write!(f, "low-space"),
+            SledMode::HighThroughput => write!(f, "high-throughput"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SledConfig {
+    /// Page cache size in bytes, sled's default when `None`.
+    pub cache_capacity: Option<u64>,
+    /// Compress with zstd. Requires the `sled-compression` feature.
+    pub compression: bool,
+    /// Flush in the background every this many milliseconds instead of after every write.
+    pub flush_interval_ms: Option<u64>,
+    pub mode: Option<SledMode>,
+}
+
+/// [`KvsEngine`] backed by a sled database.
+#[derive(Clone, Debug)]
+pub struct SledKvsEngine {
+    db: sled::Db,
+    flush_every_write: bool,
+}
+
+impl SledKvsEngine {
+    /// Open with sled's default configuration, flushing after every write.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, KvsEngineError> {
+        Self::with_config(dir, &SledConfig::default())
+    }
+
+    pub fn with_config(dir: impl AsRef<Path>, config: &SledConfig) -> Result<Self, KvsEngineError> {
+        let mut sled_config = sled::Config::new()
+            .path(dir.as_ref())
+            .use_compression(config.compression)
+            .flush_every_ms(config.flush_interval_ms);
+        if let Some(capacity) = config.cache_capacity {
+            sled_config = sled_config.cache_capacity(capacity);
+        }
+        if let Some(mode) = config.mode {
+            sled_config = sled_config.mode(match mode {
+                SledMode::LowSpace => sled::Mode::LowSpace,
+                SledMode::HighThroughput => sled::Mode::HighThroughput,
+            });
+        }
+        Ok(Self {
+            db: sled_config.open()?,
+            flush_every_write: config.flush_interval_ms.is_none(),
+        })
+    }
+
+    /// Underlying database, for sled features the engine API doesn't cover.
+    pub fn db(&self) -> &sled::Db {
+        &self.db
+    }
+
+    fn flush_after_write(&self) -> Result<(), KvsEngineError> {
+        if self.flush_every_write {
+            self.db.flush()?;
+        }
         Ok(())
     }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set(&mut self, key: String, value: String) -> Result<(), KvsEngineError> {
+        self.db.insert(key.as_bytes(), value.as_bytes())?;
+        self.flush_after_write()
+    }
 
     fn get(&mut self, key: &str) -> Result<Option<String>, KvsEngineError> {
-        let result = (self as &sled::Tree).get(key.as_bytes())?;
-        let v = match result {
-            Some(v) => Some(String::from_utf8(v.to_vec()).expect("expected value is in utf-8")),
-            None => None,
-        };
-        Ok(v)
+        let result = self.db.get(key.as_bytes())?;
+        result.map(|v| decode(&v)).transpose()
     }
 
     fn remove(&mut self, key: &str) -> Result<(), KvsEngineError> {
-        let result: Option<_> = (self as &sled::Tree).remove(key.as_bytes())?;
-        (self as &sled::Tree).flush()?;
+        let result: Option<_> = self.db.remove(key.as_bytes())?;
+        self.flush_after_write()?;
         match result {
             Some(_) => Ok(()),
             None => Err(KvsEngineError::EntryNotFound {
@@ -32,4 +127,149 @@ impl KvsEngine for sled::Db {
             }),
         }
     }
+
+    fn scan(&mut self, prefix: &str) -> Result<Entries<'_>, KvsEngineError> {
+        let entries = self.db.scan_prefix(prefix.as_bytes()).map(|result| {
+            let (k, v) = result?;
+            Ok((decode(&k)?, decode(&v)?))
+        });
+        Ok(Box::new(entries))
+    }
+
+    fn scan_after(&mut self, prefix: &str, after: &str) -> Result<Entries<'_>, KvsEngineError> {
+        if after < prefix {
+            return self.scan(prefix);
+        }
+        let prefix = prefix.as_bytes().to_vec();
+        let range = (Bound::Excluded(after.as_bytes()), Bound::Unbounded);
+        let entries = self
+            .db
+            .range::<&[u8], _>(range)
+            .take_while(move |result| match result {
+                Ok((k, _)) => k.starts_with(&prefix),
+                Err(_) => true,
+            })
+            .map(|result| {
+                let (k, v) = result?;
+                Ok((decode(&k)?, decode(&v)?))
+            });
+        Ok(Box::new(entries))
+    }
+
+    fn apply_batch(&mut self, ops: Vec<BatchOp>) -> Result<(), KvsEngineError> {
+        let mut batch = sled::Batch::default();
+        for op in ops {
+            match op {
+                BatchOp::Set { key, value } => batch.insert(key.as_bytes(), value.as_bytes()),
+                BatchOp::Remove { key } => batch.remove(key.as_bytes()),
+            }
+        }
+        self.db.apply_batch(batch)?;
+        self.flush_after_write()
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: &str,
+        current: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool, KvsEngineError> {
+        let result = self
+            .db
+            .compare_and_swap(key, current, new.map(String::into_bytes))?;
+        self.flush_after_write()?;
+        Ok(result.is_ok())
+    }
+
+    fn flush(&mut self) -> Result<(), KvsEngineError> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn watch_prefix(&mut self, prefix: &str) -> Result<Watcher, KvsEngineError> {
+        let events = self.db.watch_prefix(prefix.as_bytes()).map(|event| {
+            Ok(match event {
+                sled::Event::Insert { key, value } => WatchEvent::Set {
+                    key: decode(&key)?,
+                    value: decode(&value)?,
+                },
+                sled::Event::Remove { key } => WatchEvent::Removed { key: decode(&key)? },
+            })
+        });
+        Ok(Box::new(events))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_batch_and_compare_and_swap() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = SledKvsEngine::open(dir.path()).unwrap();
+
+        engine
+            .apply_batch(vec![
+                BatchOp::Set {
+                    key: "key1".to_owned(),
+                    value: "value1".to_owned(),
+                },
+                BatchOp::Set {
+                    key: "key2".to_owned(),
+                    value: "value2".to_owned(),
+                },
+                BatchOp::Remove {
+                    key: "key3".to_owned(),
+                },
+            ])
+            .unwrap();
+        assert_eq!(engine.get("key2").unwrap(), Some("value2".to_owned()));
+
+        assert!(!engine
+            .compare_and_swap("key1", Some("nope"), Some("value3".to_owned()))
+            .unwrap());
+        assert!(engine
+            .compare_and_swap("key1", Some("value1"), Some("value3".to_owned()))
+            .unwrap());
+        assert!(engine
+            .compare_and_swap("key4", None, Some("value4".to_owned()))
+            .unwrap());
+        assert_eq!(engine.get("key1").unwrap(), Some("value3".to_owned()));
+        assert_eq!(engine.get("key4").unwrap(), Some("value4".to_owned()));
+    }
+
+    #[test]
+    fn test_watch_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SledConfig {
+            flush_interval_ms: Some(100),
+            mode: Some(SledMode::HighThroughput),
+            ..SledConfig::default()
+        };
+        let mut engine = SledKvsEngine::with_config(dir.path(), &config).unwrap();
+        let mut watcher = engine.watch_prefix("a").unwrap();
+
+        let mut writer = engine.clone();
+        thread::spawn(move || {
+            writer.set("b".to_owned(), "ignored".to_owned()).unwrap();
+            writer.set("a1".to_owned(), "value".to_owned()).unwrap();
+            writer.remove("a1").unwrap();
+        });
+
+        assert_eq!(
+            watcher.next().transpose().unwrap(),
+            Some(WatchEvent::Set {
+                key: "a1".to_owned(),
+                value: "value".to_owned(),
+            })
+        );
+        assert_eq!(
+            watcher.next().transpose().unwrap(),
+            Some(WatchEvent::Removed {
+                key: "a1".to_owned()
+            })
+        );
+    }
 }
@@ -1,10 +1,101 @@
 pub mod logger {
-    use slog::{Drain, Never, SendSyncRefUnwindSafeDrain};
+    use slog::{Drain, Level, Never, OwnedKVList, Record, SendSyncRefUnwindSafeDrain};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
 
     pub fn drain() -> impl 'static + SendSyncRefUnwindSafeDrain<Err = Never, Ok = ()> {
         let decorator = slog_term::TermDecorator::new().stderr().build();
         let drain = slog_term::FullFormat::new(decorator).build().fuse();
-        let drain = slog_async::Async::new(drain).build().fuse();
-        drain
+        slog_async::Async::new(drain).build().fuse()
+    }
+
+    /// Like [`drain`], dropping records less severe than the level of `filter`.
+    pub fn drain_with_level(
+        filter: &LevelFilter,
+    ) -> impl 'static + SendSyncRefUnwindSafeDrain<Err = Never, Ok = ()> {
+        Filtered {
+            drain: drain(),
+            level: filter.clone(),
+        }
+    }
+
+    /// Log level that can be changed while records are logged.
+    #[derive(Clone, Debug)]
+    pub struct LevelFilter(Arc<AtomicUsize>);
+
+    impl LevelFilter {
+        pub fn new(level: Level) -> Self {
+            Self(Arc::new(AtomicUsize::new(level.as_usize())))
+        }
+
+        pub fn set(&self, level: Level) {
+            self.0.store(level.as_usize(), Ordering::Relaxed);
+        }
+
+        pub fn level(&self) -> Level {
+            Level::from_usize(self.0.load(Ordering::Relaxed)).unwrap_or(Level::Info)
+        }
+    }
+
+    struct Filtered<D> {
+        drain: D,
+        level: LevelFilter,
+    }
+
+    impl<D: Drain<Ok = (), Err = Never>> Drain for Filtered<D> {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), Never> {
+            if record.level().is_at_least(self.level.level()) {
+                self.drain.log(record, values)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Subcommands shared by `kvs` and `kvs-client`.
+pub mod cli {
+    use crate::transfer::Format;
+    use clap::Clap;
+    use std::{num::NonZeroUsize, path::PathBuf};
+
+    #[derive(Clap)]
+    #[clap(about = "Export entries as JSON Lines or CSV")]
+    pub struct Export {
+        #[clap(
+            long,
+            default_value = "jsonl",
+            about = "Output format, `jsonl` or `csv`"
+        )]
+        pub format: Format,
+        #[clap(long, about = "Only export keys starting with this prefix")]
+        pub prefix: Option<String>,
+        #[clap(about = "Output file, stdout when omitted")]
+        pub file: Option<PathBuf>,
+    }
+
+    #[derive(Clap)]
+    #[clap(about = "Import entries from JSON Lines or CSV")]
+    pub struct Import {
+        #[clap(
+            long,
+            default_value = "jsonl",
+            about = "Input format, `jsonl` or `csv`"
+        )]
+        pub format: Format,
+        #[clap(long, about = "Only import keys starting with this prefix")]
+        pub prefix: Option<String>,
+        #[clap(
+            long,
+            default_value = "1000",
+            about = "Number of entries written per batch"
+        )]
+        pub batch_size: NonZeroUsize,
+        #[clap(about = "Input file, stdin when omitted")]
+        pub file: Option<PathBuf>,
     }
 }
@@ -1,13 +1,55 @@
 use clap::Clap;
-use kvs::{client::KvsClient, DEFAULT_ADDR, VERSION};
+use kvs::{
+    app::{
+        cli::{Export, Import},
+        logger,
+    },
+    client::{ClientError, KvsClient, KvsClientBuilder},
+    transfer, DEFAULT_ADDR, VERSION,
+};
 use slog::{info, o};
-use std::net::SocketAddr;
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    path::PathBuf,
+};
 
 #[derive(Clap)]
 #[clap(version=VERSION)]
 struct Opts {
-    #[clap(long, default_value = DEFAULT_ADDR, global = true)]
+    #[clap(
+        long,
+        default_value = DEFAULT_ADDR,
+        global = true,
+        about = "Server as `host:port` or `unix:<path>`, or a comma separated list of servers to fail over across"
+    )]
     addr: String,
+    #[clap(long, global = true, about = "Log connection details to stderr")]
+    verbose: bool,
+    #[clap(
+        long,
+        global = true,
+        about = "PEM certificate authorities to trust, enables TLS on TCP connections"
+    )]
+    tls_ca: Option<PathBuf>,
+    #[clap(
+        long,
+        global = true,
+        about = "Name the server certificate must be valid for, host of the first address by default"
+    )]
+    tls_server_name: Option<String>,
+    #[clap(long, global = true, about = "PEM client certificate chain")]
+    tls_cert: Option<PathBuf>,
+    #[clap(
+        long,
+        global = true,
+        about = "PEM private key of the client certificate"
+    )]
+    tls_key: Option<PathBuf>,
+    #[clap(long, global = true, about = "User to authenticate as")]
+    user: Option<String>,
+    #[clap(long, global = true, about = "Token of the user")]
+    token: Option<String>,
     #[clap(subcommand)]
     subcmd: SubCommand,
 }
@@ -17,6 +59,11 @@ enum SubCommand {
     Get(Get),
     Set(Set),
     Rm(Rm),
+    Export(Export),
+    Import(Import),
+    Backup(Backup),
+    Restore(Restore),
+    Permissions(Permissions),
 }
 
 #[derive(Clap)]
@@ -42,19 +89,107 @@ struct Rm {
     key: String,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let log = slog::Logger::root(slog::Discard, o!("version" => VERSION));
+#[derive(Clap)]
+#[clap(about = "Write a consistent copy of the server store into a directory")]
+struct Backup {
+    #[clap(about = "Backup directory, relative to the backup root of the server")]
+    dir: PathBuf,
+}
+
+#[derive(Clap)]
+#[clap(about = "Replace the server store content with a backup")]
+struct Restore {
+    #[clap(about = "Backup directory, relative to the backup root of the server")]
+    dir: PathBuf,
+}
+
+#[derive(Clap)]
+#[clap(about = "List effective permissions, one key prefix per line")]
+struct Permissions {
+    #[clap(about = "User to list permissions of, the authenticated user when omitted")]
+    of: Option<String>,
+}
+
+#[cfg(feature = "tls")]
+fn with_tls(
+    builder: KvsClientBuilder,
+    opts: &Opts,
+) -> Result<KvsClientBuilder, Box<dyn std::error::Error>> {
+    use kvs::tls::TlsClientConfig;
+    use std::fs;
+
+    let ca = match &opts.tls_ca {
+        Some(path) => fs::read(path)?,
+        None if opts.tls_cert.is_none() && opts.tls_key.is_none() => return Ok(builder),
+        None => return Err("`--tls-ca` is required for TLS".into()),
+    };
+    let server_name = match &opts.tls_server_name {
+        Some(name) => name.clone(),
+        None => {
+            let first = opts.addr.split(',').next().unwrap_or_default().trim();
+            let host = first.rsplit_once(':').map_or(first, |(host, _)| host);
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_owned()
+        }
+    };
+    let config = match (&opts.tls_cert, &opts.tls_key) {
+        (Some(cert), Some(key)) => {
+            TlsClientConfig::with_client_cert(&server_name, &ca, &fs::read(cert)?, &fs::read(key)?)?
+        }
+        (None, None) => TlsClientConfig::new(&server_name, &ca)?,
+        _ => return Err("`--tls-cert` and `--tls-key` must be given together".into()),
+    };
+    Ok(builder.tls(config))
+}
 
+#[cfg(not(feature = "tls"))]
+fn with_tls(
+    builder: KvsClientBuilder,
+    opts: &Opts,
+) -> Result<KvsClientBuilder, Box<dyn std::error::Error>> {
+    if opts.tls_ca.is_some()
+        || opts.tls_server_name.is_some()
+        || opts.tls_cert.is_some()
+        || opts.tls_key.is_some()
+    {
+        return Err("kvs-client was built without the `tls` feature".into());
+    }
+    Ok(builder)
+}
+
+/// Connect to the first reachable server of a comma separated list.
+fn connect(log: slog::Logger, opts: &Opts) -> Result<KvsClient, Box<dyn std::error::Error>> {
+    let addrs = &opts.addr;
+    let mut builder = with_tls(KvsClientBuilder::new().log(log), opts)?;
+    match (&opts.user, &opts.token) {
+        (Some(user), Some(token)) => builder = builder.credentials(user, token),
+        (None, None) => {}
+        _ => return Err("`--user` and `--token` must be given together".into()),
+    }
+    for addr in addrs.split(',').map(str::trim) {
+        builder = match addr.strip_prefix("unix:") {
+            Some(path) => builder.unix_endpoint(path),
+            None => builder.endpoint(addr),
+        };
+    }
+    builder
+        .connect()
+        .map_err(|err| format!("failed to connect to `{}`: {}", addrs, err).into())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let opts: Opts = Opts::parse();
 
-    let address = opts
-        .addr
-        .parse::<SocketAddr>()
-        .map_err(|_| format!("failed to parse addr `{}`", opts.addr))?;
+    let log = if opts.verbose {
+        slog::Logger::root(logger::drain(), o!("version" => VERSION))
+    } else {
+        slog::Logger::root(slog::Discard, o!("version" => VERSION))
+    };
 
-    info!(log, "starting"; "address" => address);
+    info!(log, "starting"; "address" => &opts.addr);
 
-    let mut client = KvsClient::new(log, address)?;
+    let client = connect(log, &opts)?;
 
     match opts.subcmd {
         SubCommand::Get(Get { key }) => {
@@ -70,6 +205,58 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         SubCommand::Rm(Rm { key }) => {
             client.rm(key)?;
         }
+        SubCommand::Export(export) => {
+            let writer: Box<dyn Write> = match export.file {
+                Some(path) => Box::new(File::create(path)?),
+                None => Box::new(io::stdout()),
+            };
+            transfer::export(
+                client.scan(export.prefix.unwrap_or_default()),
+                writer,
+                export.format,
+                |n| eprintln!("exported {} entries", n),
+            )?;
+        }
+        SubCommand::Backup(Backup { dir }) => {
+            client.backup(dir.to_string_lossy().into_owned())?;
+        }
+        SubCommand::Restore(Restore { dir }) => {
+            client.restore(dir.to_string_lossy().into_owned())?;
+        }
+        SubCommand::Permissions(Permissions { of }) => {
+            for grant in client.permissions(of)? {
+                let access: Vec<_> = grant
+                    .access
+                    .iter()
+                    .map(|x| format!("{:?}", x).to_lowercase())
+                    .collect();
+                println!("{:?}\t{}", grant.prefix, access.join(","));
+            }
+        }
+        SubCommand::Import(import) => {
+            let reader: Box<dyn Read> = match import.file {
+                Some(path) => Box::new(File::open(path)?),
+                None => Box::new(io::stdin()),
+            };
+            let apply = |batch: Vec<(String, String)>| {
+                let mut pipeline = client.pipeline();
+                for (key, value) in batch {
+                    pipeline.set(key, value);
+                }
+                for result in pipeline.execute()? {
+                    result?;
+                }
+                Ok::<_, ClientError>(())
+            };
+            transfer::import(
+                reader,
+                import.format,
+                import.prefix.as_deref().unwrap_or(""),
+                import.batch_size.get(),
+                apply,
+                |n| eprintln!("imported {} entries", n),
+            )?;
+        }
     }
 
     Ok(())
@@ -1,76 +1,371 @@
 use clap::Clap;
-use kvs::{app::logger, KvsServer, DEFAULT_ADDR, DEFAULT_ENGINE, VERSION};
-use slog::{info, o};
-use std::{error, fmt, net::SocketAddr};
+use kvs::{
+    app::logger::{self, LevelFilter},
+    registry::{EngineOptions, EngineRegistry},
+    server::{
+        AuthConfig, AuthError, Authenticator, ConfigError, LimitsConfig, LoadConfig, ServerConfig,
+        TimeoutsConfig, TlsConfig,
+    },
+    KvsEngine, KvsServer, DEFAULT_ADDR, DEFAULT_ENGINE, VERSION,
+};
+use nix::sys::signal::{SigSet, Signal};
+use slog::{error, info, o, warn, Logger};
+use std::{
+    collections::BTreeMap,
+    error,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
 
 #[derive(Clap)]
 #[clap(version=VERSION)]
 struct Opts {
-    #[clap(long, default_value = DEFAULT_ADDR)]
-    addr: String,
-    #[clap(long, default_value = DEFAULT_ENGINE)]
-    engine: String,
+    #[clap(
+        long,
+        about = "TOML config file, overridden by flags and reloaded on SIGHUP"
+    )]
+    config: Option<PathBuf>,
+    #[clap(
+        long,
+        about = "Address as `ip:port`, or `unix:<path>` for a Unix domain socket, or a comma separated list of addresses to listen on [default: 127.0.0.1:4000]"
+    )]
+    addr: Option<String>,
+    #[clap(
+        long,
+        about = "Permissions of the Unix domain socket files, in octal [default: 660]"
+    )]
+    socket_mode: Option<String>,
+    #[clap(long, about = "Storage engine [default: kvs]")]
+    engine: Option<String>,
+    #[clap(
+        long = "engine-opt",
+        about = "Engine option as `name=value`, may be repeated"
+    )]
+    engine_opts: Vec<String>,
+    #[clap(long, about = "List available engines and their options, then exit")]
+    list_engines: bool,
+    #[clap(
+        long,
+        about = "Least severe records logged, `critical` to `trace` [default: info]"
+    )]
+    log_level: Option<String>,
+    #[clap(long, about = "PEM certificate chain, enables TLS on TCP connections")]
+    tls_cert: Option<PathBuf>,
+    #[clap(long, about = "PEM private key of the TLS certificate")]
+    tls_key: Option<PathBuf>,
+    #[clap(
+        long,
+        about = "PEM certificate authorities, requires clients to present a certificate they signed"
+    )]
+    tls_client_ca: Option<PathBuf>,
+    #[clap(
+        long,
+        about = "TOML file with user tokens, requires clients to authenticate"
+    )]
+    auth_config: Option<PathBuf>,
+    #[clap(
+        long,
+        about = "Directory backup and restore requests are confined to, refused without one"
+    )]
+    backup_root: Option<PathBuf>,
+    #[clap(long, about = "Largest key accepted, in bytes")]
+    max_key_size: Option<usize>,
+    #[clap(long, about = "Largest value accepted, in bytes")]
+    max_value_size: Option<usize>,
+    #[clap(long, about = "Largest encoded request accepted, in bytes")]
+    max_frame_size: Option<u64>,
+    #[clap(
+        long,
+        about = "Seconds requests in flight get to finish on SIGINT or SIGTERM [default: 10]"
+    )]
+    shutdown_timeout: Option<u64>,
+    #[clap(
+        long,
+        about = "Seconds a connection may wait between requests, 0 for no limit [default: 300]"
+    )]
+    idle_timeout: Option<u64>,
+    #[clap(
+        long,
+        about = "Seconds a client may take to send a request, 0 for no limit [default: 30]"
+    )]
+    request_timeout: Option<u64>,
+    #[clap(long, about = "Seconds a connection may stay open")]
+    max_connection_lifetime: Option<u64>,
+    #[clap(long, about = "Connections open at once [default: 256]")]
+    max_connections: Option<usize>,
+    #[clap(long, about = "Connections open at once from one address")]
+    max_connections_per_ip: Option<usize>,
+    #[clap(
+        long,
+        about = "Requests per second of a connection, or of a user across their connections"
+    )]
+    rate_limit: Option<f64>,
+    #[clap(
+        long,
+        about = "Requests over the rate limit allowed in bursts, the rate by default"
+    )]
+    rate_burst: Option<f64>,
+    #[clap(long, about = "Requests of a connection handled at once [default: 64]")]
+    max_in_flight: Option<usize>,
+    #[clap(
+        long,
+        about = "Writes waiting on the engine before clients are held off [default: 256]"
+    )]
+    max_queued_writes: Option<usize>,
 }
 
-fn main() -> Result<(), Box<dyn error::Error>> {
-    let log = slog::Logger::root(logger::drain(), o!());
+impl Opts {
+    /// Settings given as flags, overriding those of the config file.
+    fn overrides(&self) -> Result<ServerConfig, Box<dyn error::Error>> {
+        let mut engine_options = BTreeMap::new();
+        for option in &self.engine_opts {
+            match option.split_once('=') {
+                Some((name, value)) if !name.is_empty() => {
+                    engine_options.insert(name.to_owned(), value.to_owned());
+                }
+                _ => return Err(format!("invalid engine option `{}`", option).into()),
+            }
+        }
+        Ok(ServerConfig {
+            addr: self
+                .addr
+                .iter()
+                .flat_map(|x| x.split(','))
+                .map(|x| x.trim().to_owned())
+                .collect(),
+            socket_mode: self.socket_mode.clone(),
+            engine: self.engine.clone(),
+            engine_options,
+            log_level: self.log_level.clone(),
+            auth_config: self.auth_config.clone(),
+            backup_root: self.backup_root.clone(),
+            tls: TlsConfig {
+                cert: self.tls_cert.clone(),
+                key: self.tls_key.clone(),
+                client_ca: self.tls_client_ca.clone(),
+            },
+            limits: LimitsConfig {
+                max_key_size: self.max_key_size,
+                max_value_size: self.max_value_size,
+                max_frame_size: self.max_frame_size,
+            },
+            timeouts: TimeoutsConfig {
+                idle: self.idle_timeout,
+                request: self.request_timeout,
+                lifetime: self.max_connection_lifetime,
+                shutdown: self.shutdown_timeout,
+            },
+            load: LoadConfig {
+                max_connections: self.max_connections,
+                max_connections_per_ip: self.max_connections_per_ip,
+                rate_limit: self.rate_limit,
+                rate_burst: self.rate_burst,
+                max_in_flight: self.max_in_flight,
+                max_queued_writes: self.max_queued_writes,
+            },
+        })
+    }
+}
 
-    let opts: Opts = Opts::parse();
+/// Read the config file if there's one, apply `overrides` and check the result.
+fn load(path: Option<&Path>, overrides: &ServerConfig) -> Result<ServerConfig, ConfigError> {
+    let config = match path {
+        Some(path) => ServerConfig::from_file(path)?,
+        None => ServerConfig::default(),
+    };
+    let config = config.merge(overrides.clone());
+    config.validate()?;
+    Ok(config)
+}
 
-    let address: SocketAddr = opts
-        .addr
-        .parse()
-        .map_err(|_| format!("failed to parse addr `{}`", opts.addr))?;
+fn authenticator(config: &ServerConfig) -> Result<Option<Authenticator>, AuthError> {
+    let auth = match &config.auth_config {
+        Some(path) => Some(Authenticator::new(&AuthConfig::from_file(path)?)),
+        None => None,
+    };
+    Ok(auth)
+}
 
-    let engine_opt: Engine = opts.engine.parse().map_err(|_| {
-        format!(
-            "failed to parse engine, expected `kvs` or `sled`, found `{}`",
-            opts.engine
-        )
-    })?;
+/// Re-read the config on every SIGHUP, applying what can change while the server runs. Nothing is
+/// applied when the config is invalid.
+fn reload_on_sighup(
+    log: Logger,
+    level: LevelFilter,
+    server: Arc<KvsServer>,
+    path: Option<PathBuf>,
+    overrides: ServerConfig,
+    mut current: ServerConfig,
+) -> Result<(), Box<dyn error::Error>> {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGHUP);
+    loop {
+        signals.wait()?;
+        let reloaded = load(path.as_deref(), &overrides).and_then(|config| {
+            let auth =
+                authenticator(&config).map_err(|err| ConfigError::Invalid(err.to_string()))?;
+            Ok((config, auth))
+        });
+        let (config, auth) = match reloaded {
+            Ok(reloaded) => reloaded,
+            Err(err) => {
+                error!(log, "failed to reload config, keeping the current one"; "error" => %err);
+                continue;
+            }
+        };
+        level.set(config.log_level()?);
+        server.set_limits(config.limits());
+        server.set_timeouts(config.timeouts());
+        server.set_load_limits(config.load_limits());
+        server.set_auth(auth);
+        let restart = config.addr != current.addr
+            || config.socket_mode != current.socket_mode
+            || config.engine != current.engine
+            || config.engine_options != current.engine_options
+            || config.engine_limits() != current.engine_limits()
+            || config.backup_root != current.backup_root
+            || config.tls != current.tls
+            || config.timeouts.shutdown != current.timeouts.shutdown
+            || config.load.max_queued_writes != current.load.max_queued_writes;
+        if restart {
+            warn!(
+                log,
+                "some settings changed only take effect after a restart"
+            );
+        }
+        info!(log, "config reloaded");
+        current = config;
+    }
+}
 
-    info!(log, "starting"; "address" => address, "engine" => %engine_opt);
+#[cfg(feature = "tls")]
+fn with_tls(server: KvsServer, tls: &TlsConfig) -> Result<KvsServer, Box<dyn error::Error>> {
+    use kvs::tls::TlsServerConfig;
+    use std::fs;
 
-    let mut engine: Box<dyn kvs::KvsEngine> = match engine_opt {
-        Engine::KVS => Box::new(kvs::KvStore::open("./")?),
-        Engine::Sled => Box::new(sled::open("./")?),
+    let (cert, key) = match (&tls.cert, &tls.key) {
+        (Some(cert), Some(key)) => (fs::read(cert)?, fs::read(key)?),
+        _ => return Ok(server),
     };
-    let server = KvsServer::new(log, address)?;
-    server.listen(&mut engine)?;
-
-    Ok(())
+    let client_ca = tls.client_ca.as_ref().map(fs::read).transpose()?;
+    let config = TlsServerConfig::new(&cert, &key, client_ca.as_deref())?;
+    Ok(server.with_tls(config))
 }
 
-#[derive(Clone, Debug)]
-pub enum Engine {
-    KVS,
-    Sled,
+#[cfg(not(feature = "tls"))]
+fn with_tls(server: KvsServer, tls: &TlsConfig) -> Result<KvsServer, Box<dyn error::Error>> {
+    if *tls != TlsConfig::default() {
+        return Err("kvs-server was built without the `tls` feature".into());
+    }
+    Ok(server)
 }
 
-impl Default for Engine {
-    fn default() -> Self {
-        Engine::KVS
+fn list_engines(registry: &EngineRegistry) {
+    for engine in registry.engines() {
+        println!("{}\t{}", engine.name, engine.about);
+        for option in engine.options {
+            println!("  {}\t{}", option.name, option.about);
+        }
     }
 }
 
-impl std::str::FromStr for Engine {
-    type Err = Box<dyn std::error::Error>;
+fn main() -> Result<(), Box<dyn error::Error>> {
+    // Blocked before any thread starts, so that only the reload thread receives it.
+    let mut sighup = SigSet::empty();
+    sighup.add(Signal::SIGHUP);
+    sighup.thread_block()?;
+
+    let opts: Opts = Opts::parse();
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let engine = match s.to_lowercase().as_ref() {
-            "kvs" => Engine::KVS,
-            "sled" => Engine::Sled,
-            other => return Err(format!("unknown engine `{}`", other).into()),
-        };
-        Ok(engine)
+    let registry = EngineRegistry::default();
+
+    if opts.list_engines {
+        list_engines(&registry);
+        return Ok(());
     }
-}
 
-impl fmt::Display for Engine {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Engine::KVS => write!(f, "kvs"),
-            Engine::Sled => write!(f, "sled"),
+    let overrides = opts.overrides()?;
+    let config = load(opts.config.as_deref(), &overrides)?;
+    let level = LevelFilter::new(config.log_level()?);
+    let log = slog::Logger::root(logger::drain_with_level(&level), o!());
+
+    let addrs = match config.addr.as_slice() {
+        [] => vec![DEFAULT_ADDR.to_owned()],
+        addrs => addrs.to_vec(),
+    };
+    let engine_name = config.engine.as_deref().unwrap_or(DEFAULT_ENGINE);
+    let mut engine_options = EngineOptions::default();
+    for (name, value) in &config.engine_options {
+        engine_options.set(name, value);
+    }
+    // Engines enforcing size limits of their own get those of the server.
+    if let Some(engine) = registry.get(engine_name) {
+        for (name, limit) in config.engine_limits().iter() {
+            if engine.options.iter().any(|x| x.name == *name) {
+                engine_options.set(*name, limit.to_string());
+            }
         }
     }
+
+    info!(log, "starting"; "address" => addrs.join(","), "engine" => engine_name);
+
+    let mode = config.socket_mode()?;
+    let mut server: Option<KvsServer> = None;
+    for addr in &addrs {
+        let address = || {
+            addr.parse::<SocketAddr>()
+                .map_err(|_| format!("failed to parse addr `{}`", addr))
+        };
+        server = Some(match (server, addr.strip_prefix("unix:")) {
+            (None, Some(path)) => KvsServer::unix(log.clone(), path, mode)?,
+            (None, None) => KvsServer::new(log.clone(), address()?)?,
+            (Some(server), Some(path)) => server.add_unix(path, mode)?,
+            (Some(server), None) => server.add_address(address()?)?,
+        });
+    }
+    let server = server.expect("there's always an address to listen on");
+    let server = with_tls(server, &config.tls)?
+        .with_limits(config.limits())
+        .with_timeouts(config.timeouts())
+        .with_load_limits(config.load_limits())
+        .with_shutdown_timeout(config.shutdown_timeout());
+    let server = match authenticator(&config)? {
+        Some(auth) => server.with_auth(auth),
+        None => server,
+    };
+    let server = match &config.backup_root {
+        Some(root) => server.with_backup_root(root),
+        None => server,
+    };
+    server.shutdown_handle().on_signals()?;
+    let server = Arc::new(server);
+    {
+        let log = log.clone();
+        let server = server.clone();
+        let config = config.clone();
+        thread::spawn(move || {
+            if let Err(err) =
+                reload_on_sighup(log.clone(), level, server, opts.config, overrides, config)
+            {
+                error!(log, "stopped reloading config"; "error" => %err);
+            }
+        });
+    }
+    let mut engine = registry.open(engine_name, Path::new("./"), &engine_options)?;
+    server.listen(&mut engine)?;
+
+    info!(log, "flushing engine");
+    engine.flush()?;
+    drop(engine);
+    let stats = server.stats();
+    info!(log, "stopped";
+        "connections" => stats.connections,
+        "rejected_connections" => stats.rejected_connections,
+        "throttled_requests" => stats.throttled_requests,
+        "idle_timeouts" => stats.idle_timeouts,
+        "request_timeouts" => stats.request_timeouts,
+        "lifetime_expirations" => stats.lifetime_expirations);
+
+    Ok(())
 }
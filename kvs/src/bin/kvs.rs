@@ -1,6 +1,17 @@
 use clap::Clap;
-use kvs::{KvStore, VERSION};
-use std::{path::Path, process::exit};
+use kvs::{
+    app::cli::{Export, Import},
+    registry::{EngineOptions, EngineRegistry},
+    store::{backup, maintenance, Command},
+    transfer, KvStore, KvsEngine, VERSION,
+};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    process::exit,
+};
 
 #[derive(Clap)]
 #[clap(version=VERSION)]
@@ -16,6 +27,21 @@ enum SubCommand {
     Rm(Rm),
     #[clap(about = "Show all entries")]
     List,
+    #[clap(about = "Force log compaction")]
+    Compact,
+    #[clap(about = "Check every log record for corruption")]
+    Verify,
+    #[clap(about = "Print raw log records with their offsets")]
+    Dump,
+    #[clap(about = "Show live keys, dead bytes and file sizes")]
+    Stats,
+    #[clap(about = "Rebuild the log from its readable records, dropping corrupted bytes for good")]
+    Repair,
+    Export(Export),
+    Import(Import),
+    Migrate(Migrate),
+    Backup(Backup),
+    Restore(Restore),
 }
 
 #[derive(Clap)]
@@ -41,12 +67,129 @@ struct Rm {
     key: String,
 }
 
+#[derive(Clap)]
+#[clap(about = "Copy every entry from one engine to another")]
+struct Migrate {
+    #[clap(long, about = "Source engine, see `kvs-server --list-engines`")]
+    from: String,
+    #[clap(long, about = "Destination engine, see `kvs-server --list-engines`")]
+    to: String,
+    #[clap(long, about = "Source data directory")]
+    src: PathBuf,
+    #[clap(long, about = "Destination data directory")]
+    dst: PathBuf,
+    #[clap(
+        long,
+        about = "Progress file used to resume an interrupted migration, defaults to `<dst>/migrate.checkpoint`"
+    )]
+    checkpoint: Option<PathBuf>,
+    #[clap(
+        long,
+        default_value = "1000",
+        about = "Number of entries copied per checkpoint"
+    )]
+    batch_size: NonZeroUsize,
+}
+
+#[derive(Clap)]
+#[clap(about = "Write a consistent copy of the store into a directory")]
+struct Backup {
+    #[clap(about = "Backup directory")]
+    dir: PathBuf,
+}
+
+#[derive(Clap)]
+#[clap(about = "Replace the store content with a backup")]
+struct Restore {
+    #[clap(about = "Backup directory")]
+    dir: PathBuf,
+}
+
+fn open_engine(name: &str, dir: &Path) -> Result<Box<dyn KvsEngine>, Box<dyn std::error::Error>> {
+    fs::create_dir_all(dir)?;
+    let engine = EngineRegistry::default().open(name, dir, &EngineOptions::default())?;
+    Ok(engine)
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut store = KvStore::open(Path::new("./"))?;
+    let dir = Path::new("./");
 
     let opts = Opts::parse();
+
+    // Maintenance commands read the log directly, they must work on a store that can't be opened,
+    // so the store is only opened by the commands going through it.
     match opts.subcmd {
-        SubCommand::Get(get) => match store.get(get.key)? {
+        SubCommand::Verify => {
+            let report = maintenance::verify(dir)?;
+            for (offset, size) in report.corrupt.iter() {
+                println!("corrupted record at offset {} ({} bytes)", offset, size);
+            }
+            println!(
+                "{} records, {} corrupted",
+                report.records,
+                report.corrupt.len()
+            );
+            if !report.is_ok() {
+                exit(1);
+            }
+        }
+        SubCommand::Dump => {
+            for entry in maintenance::dump(dir)? {
+                match entry {
+                    maintenance::Entry::Record {
+                        offset,
+                        command: Command::Set(set),
+                        ..
+                    } => println!("{}\tSet\t{}\t{}", offset, set.key, set.value),
+                    maintenance::Entry::Record {
+                        offset,
+                        command: Command::Rm(rm),
+                        ..
+                    } => println!("{}\tRm\t{}", offset, rm.key),
+                    maintenance::Entry::Corrupt { offset, size } => {
+                        println!("{}\tCorrupted\t{} bytes", offset, size)
+                    }
+                }
+            }
+        }
+        SubCommand::Stats => {
+            let stats = maintenance::stats(dir)?;
+            println!("records: {}", stats.records);
+            println!("live keys: {}", stats.live_keys);
+            println!("live bytes: {}", stats.live_bytes);
+            println!("dead bytes: {}", stats.dead_bytes);
+            println!("log file size: {}", stats.log_file_size);
+        }
+        SubCommand::Migrate(migrate) => {
+            let mut src = open_engine(&migrate.from, &migrate.src)?;
+            let mut dst = open_engine(&migrate.to, &migrate.dst)?;
+            let dst_dir = migrate.dst;
+            let checkpoint = migrate
+                .checkpoint
+                .unwrap_or_else(|| dst_dir.join("migrate.checkpoint"));
+            let report = transfer::migrate(
+                &mut src,
+                &mut dst,
+                &checkpoint,
+                migrate.batch_size.get(),
+                |n| eprintln!("migrated {} entries", n),
+            )?;
+            println!(
+                "{} entries copied, {} already copied, {} entries verified with checksum {:016x}",
+                report.copied, report.resumed, report.entries, report.checksum
+            );
+        }
+        SubCommand::Restore(restore) => {
+            backup::restore(&restore.dir, dir)?;
+        }
+        SubCommand::Repair => {
+            let report = maintenance::repair(dir)?;
+            println!(
+                "{} records recovered, {} corrupted bytes dropped",
+                report.records, report.dropped_bytes
+            );
+        }
+        SubCommand::Get(get) => match KvStore::open(dir)?.get(get.key)? {
             Some(value) => {
                 println!("{}", value);
             }
@@ -54,8 +197,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 println!("Key not found");
             }
         },
-        SubCommand::Set(set) => store.set(set.key, set.value)?,
-        SubCommand::Rm(rm) => match store.remove(rm.key) {
+        SubCommand::Set(set) => KvStore::open(dir)?.set(set.key, set.value)?,
+        SubCommand::Rm(rm) => match KvStore::open(dir)?.remove(rm.key) {
             Ok(_) => {}
             Err(error) => match error {
                 kvs::store::KvStoreError::KeyNotFound { .. } => {
@@ -66,11 +209,48 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             },
         },
         SubCommand::List => {
-            let entries = store.list()?;
+            let entries = KvStore::open(dir)?.list()?;
             for (key, value) in entries {
                 println!("{} -> {}", key, value);
             }
         }
+        SubCommand::Compact => {
+            let mut store = KvStore::open(dir)?;
+            let before = maintenance::stats(dir)?.log_file_size;
+            store.compact()?;
+            let after = maintenance::stats(dir)?.log_file_size;
+            println!("log file size: {} -> {}", before, after);
+        }
+        SubCommand::Export(export) => {
+            let writer: Box<dyn Write> = match export.file {
+                Some(path) => Box::new(File::create(path)?),
+                None => Box::new(io::stdout()),
+            };
+            transfer::export_engine(
+                &mut KvStore::open(dir)?,
+                export.prefix.as_deref().unwrap_or(""),
+                writer,
+                export.format,
+                |n| eprintln!("exported {} entries", n),
+            )?;
+        }
+        SubCommand::Import(import) => {
+            let reader: Box<dyn Read> = match import.file {
+                Some(path) => Box::new(File::open(path)?),
+                None => Box::new(io::stdin()),
+            };
+            transfer::import_engine(
+                &mut KvStore::open(dir)?,
+                reader,
+                import.format,
+                import.prefix.as_deref().unwrap_or(""),
+                import.batch_size.get(),
+                |n| eprintln!("imported {} entries", n),
+            )?;
+        }
+        SubCommand::Backup(backup) => {
+            KvStore::open(dir)?.backup_to(backup.dir)?;
+        }
     }
 
     Ok(())
@@ -1,94 +1,889 @@
-use crate::protocol::{Request, Response, Serialization};
-use slog::{debug, info, o, Discard, Logger};
-use std::{io, net::SocketAddr, net::TcpStream, time::Duration};
+use crate::{
+    protocol::{
+        Envelope, ErrorKind, Grant, Request, Response, Secret, Serialization, MAX_SCAN_LIMIT,
+    },
+    transport::{Endpoint, Stream},
+};
+use slog::{debug, error, info, o, warn, Discard, Logger};
+use std::{
+    collections::HashMap,
+    io::{self, BufReader, BufWriter, Write},
+    net::{Shutdown, ToSocketAddrs},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
 use thiserror::Error;
 
+#[cfg(feature = "tls")]
+use crate::tls::{TlsClientConfig, TlsStream};
+
 #[derive(Error, Debug)]
 pub enum ClientError {
     #[error("{0}")]
-    ErrorResponse(String),
+    NotFound(String),
+    #[error("invalid request, {0}")]
+    InvalidRequest(String),
+    #[error("storage error, {0}")]
+    Storage(String),
+    #[error("server is busy, {0}")]
+    Busy(String),
+    #[error("unauthorized, {0}")]
+    Unauthorized(String),
+    #[error("too large, {0}")]
+    TooLarge(String),
     #[error("failed to write request, caused by {0}")]
     RequestError(Box<dyn std::error::Error>),
     #[error("failed to read response, caused by {0}")]
     ResponseError(Box<dyn std::error::Error>),
+    #[error("failed to connect, caused by {0}")]
+    Connect(io::Error),
     #[error("no response")]
     NoResponse,
+    #[error("timed out waiting for response")]
+    Timeout,
     #[error("unexpected response, response was {0}")]
     UnexpectedResponse(Response),
 }
 
+impl ClientError {
+    fn from_failure(kind: ErrorKind, message: String) -> Self {
+        match kind {
+            ErrorKind::NotFound => ClientError::NotFound(message),
+            ErrorKind::InvalidRequest => ClientError::InvalidRequest(message),
+            ErrorKind::Storage => ClientError::Storage(message),
+            ErrorKind::Busy => ClientError::Busy(message),
+            ErrorKind::Unauthorized => ClientError::Unauthorized(message),
+            ErrorKind::TooLarge => ClientError::TooLarge(message),
+        }
+    }
+
+    /// Whether the request may succeed when sent again.
+    fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ClientError::Busy(_)
+                | ClientError::RequestError(_)
+                | ClientError::ResponseError(_)
+                | ClientError::Connect(_)
+                | ClientError::NoResponse
+                | ClientError::Timeout
+        )
+    }
+}
+
+/// Number of entries requested per `Scan` round trip, as many as a server returns.
+const SCAN_PAGE_SIZE: u32 = MAX_SCAN_LIMIT;
+
+/// Callers waiting for a response, by request ID. `None` once the connection is closed.
+type Pending = Mutex<Option<HashMap<u64, mpsc::Sender<Response>>>>;
+
+/// Retries of idempotent requests after connection failures, and of any request a busy server
+/// turned away, with exponential backoff.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt, `0` to never retry.
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for every retry after that.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Policy sending every request only once.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Config {
+    connect_timeout: Duration,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    retry: RetryPolicy,
+    credentials: Option<(String, Secret)>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsClientConfig>,
+}
+
+impl Config {
+    /// Wrap a new connection in TLS when it's enabled. The handshake shares the connect timeout.
+    fn secure(&self, stream: Stream) -> Result<Stream, io::Error> {
+        #[cfg(feature = "tls")]
+        if let (Some(config), Stream::Tcp(tcp)) = (&self.tls, &stream) {
+            let tls = TlsStream::connect(tcp.try_clone()?, config, self.connect_timeout)?;
+            return Ok(Stream::Tls(Arc::new(tls)));
+        }
+        Ok(stream)
+    }
+}
+
+/// Builder of a [`KvsClient`], created with [`KvsClient::builder`].
+pub struct KvsClientBuilder {
+    log: Option<Logger>,
+    /// Resolved endpoints, or the first resolution failure.
+    endpoints: Result<Vec<Endpoint>, io::Error>,
+    config: Config,
+}
+
+impl Default for KvsClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KvsClientBuilder {
+    /// Builder without any endpoint yet.
+    pub fn new() -> Self {
+        Self {
+            log: None,
+            endpoints: Ok(Vec::new()),
+            config: Config {
+                connect_timeout: Duration::from_millis(100),
+                read_timeout: None,
+                write_timeout: None,
+                retry: RetryPolicy::default(),
+                credentials: None,
+                #[cfg(feature = "tls")]
+                tls: None,
+            },
+        }
+    }
+
+    /// Add a server to fail over to when the previous ones are unreachable.
+    pub fn endpoint(mut self, address: impl ToSocketAddrs) -> Self {
+        if let Ok(endpoints) = &mut self.endpoints {
+            match address.to_socket_addrs() {
+                Ok(addresses) => endpoints.extend(addresses.map(Endpoint::Tcp)),
+                Err(err) => self.endpoints = Err(err),
+            }
+        }
+        self
+    }
+
+    /// Add a server listening on the Unix domain socket at `path`.
+    pub fn unix_endpoint(mut self, path: impl Into<PathBuf>) -> Self {
+        if let Ok(endpoints) = &mut self.endpoints {
+            endpoints.push(Endpoint::Unix(path.into()));
+        }
+        self
+    }
+
+    pub fn log(mut self, log: impl Into<Option<Logger>>) -> Self {
+        self.log = log.into();
+        self
+    }
+
+    /// Longest wait for a connection to the server, 100 ms by default.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = timeout;
+        self
+    }
+
+    /// Longest wait for the response to a request, unlimited by default.
+    ///
+    /// A request timing out closes the connection, failing the other requests in flight on it.
+    /// The next request reconnects.
+    pub fn read_timeout(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.config.read_timeout = timeout.into();
+        self
+    }
+
+    /// Longest wait for a request to be written to the socket, unlimited by default.
+    pub fn write_timeout(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.config.write_timeout = timeout.into();
+        self
+    }
+
+    /// How idempotent requests are retried, see [`RetryPolicy::default`].
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.config.retry = policy;
+        self
+    }
+
+    /// Authenticate every connection as `user`, for servers with authentication enabled.
+    pub fn credentials(mut self, user: impl Into<String>, token: impl Into<String>) -> Self {
+        self.config.credentials = Some((user.into(), Secret(token.into())));
+        self
+    }
+
+    /// Use TLS on TCP connections. Connections over a Unix domain socket stay plaintext.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: TlsClientConfig) -> Self {
+        self.config.tls = Some(config);
+        self
+    }
+
+    /// Connect to the first reachable endpoint.
+    pub fn connect(self) -> Result<KvsClient, io::Error> {
+        self.connector()?.connect()
+    }
+
+    /// Check the endpoints, keeping the settings to open any number of clients with.
+    pub(super) fn connector(self) -> Result<Connector, io::Error> {
+        let endpoints = self.endpoints?;
+        if endpoints.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no address to connect to",
+            ));
+        }
+        Ok(Connector {
+            log: self.log.unwrap_or_else(|| Logger::root(Discard, o!())),
+            endpoints,
+            config: self.config,
+        })
+    }
+}
+
+/// Settings of a [`KvsClientBuilder`] whose endpoints were checked, opening clients on demand.
+pub(super) struct Connector {
+    pub(super) log: Logger,
+    endpoints: Vec<Endpoint>,
+    config: Config,
+}
+
+impl Connector {
+    /// Connect a new client to the first reachable endpoint.
+    pub(super) fn connect(&self) -> Result<KvsClient, io::Error> {
+        let connection = Connection::open(&self.log, &self.endpoints, &self.config)?;
+        Ok(KvsClient {
+            log: self.log.clone(),
+            endpoints: self.endpoints.clone(),
+            config: self.config.clone(),
+            connection: Mutex::new(Arc::new(connection)),
+            next_id: AtomicU64::new(1),
+        })
+    }
+}
+
+/// Client of a [`KvsServer`](crate::KvsServer).
+///
+/// Every request is tagged with an ID and a background thread hands responses to their callers
+/// as they arrive, so one client can be shared by several threads with requests in flight at
+/// the same time.
+///
+/// When the connection breaks, for example because the server restarted, the next request opens a
+/// new one to the first reachable endpoint. Idempotent requests are retried according to the
+/// [`RetryPolicy`], others fail with the connection error since the server may have handled them.
 pub struct KvsClient {
     log: Logger,
-    stream: TcpStream,
+    endpoints: Vec<Endpoint>,
+    config: Config,
+    connection: Mutex<Arc<Connection>>,
+    next_id: AtomicU64,
 }
 
 impl KvsClient {
+    /// Connect with the default timeouts and retry policy.
+    ///
+    /// `address` can be anything resolving to socket addresses, like `"localhost:4000"`, every
+    /// resolved address is tried in turn.
     pub fn new(
         log: impl Into<Option<Logger>>,
-        address: impl Into<SocketAddr>,
+        address: impl ToSocketAddrs,
     ) -> Result<Self, io::Error> {
-        let log = log.into().unwrap_or_else(|| Logger::root(Discard, o!()));
-        debug!(log, "connecting");
-        let address = address.into();
-        let stream = TcpStream::connect_timeout(&address, Duration::from_millis(100))?;
-        info!(log, "connected");
-        let client = Self { log, stream };
-        Ok(client)
-    }
-
-    pub fn get(&mut self, key: String) -> Result<Option<String>, ClientError> {
-        let log = &self.log;
-
-        let request = Request::Get { key };
-        debug!(log, "sending request"; "request" => ?request);
-        request
-            .to_writer(&mut self.stream)
-            .map_err(|x| ClientError::RequestError(Box::new(x)))?;
-
-        let response = Response::from_reader(&mut self.stream)
-            .map_err(|x| ClientError::ResponseError(Box::new(x)))?;
-        match response {
-            Some(Response::Success(v)) => Ok(v),
-            Some(Response::Failure(m)) => Err(ClientError::ErrorResponse(m)),
-            None => Err(ClientError::NoResponse),
+        Self::builder(address).log(log).connect()
+    }
+
+    pub fn builder(address: impl ToSocketAddrs) -> KvsClientBuilder {
+        KvsClientBuilder::new().endpoint(address)
+    }
+
+    /// Connect to a server listening on a Unix domain socket, with the default timeouts and retry
+    /// policy.
+    pub fn unix(
+        log: impl Into<Option<Logger>>,
+        path: impl Into<PathBuf>,
+    ) -> Result<Self, io::Error> {
+        KvsClientBuilder::new()
+            .unix_endpoint(path)
+            .log(log)
+            .connect()
+    }
+
+    /// Current connection, reconnecting first if it's broken.
+    fn connection(&self) -> Result<Arc<Connection>, ClientError> {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_closed() {
+            debug!(self.log, "reconnecting");
+            let new = Connection::open(&self.log, &self.endpoints, &self.config).map_err(
+                |err| match err.kind() {
+                    io::ErrorKind::PermissionDenied => ClientError::Unauthorized(err.to_string()),
+                    _ => ClientError::Connect(err),
+                },
+            )?;
+            *connection = Arc::new(new);
         }
+        Ok(connection.clone())
+    }
+
+    /// Buffer a request, it's sent on the next flush.
+    fn send(
+        &self,
+        connection: &Connection,
+        request: Request,
+    ) -> Result<mpsc::Receiver<Response>, ClientError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        connection.send(id, request)
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<(), ClientError> {
-        let log = &self.log;
+    fn receive(
+        &self,
+        connection: &Connection,
+        receiver: mpsc::Receiver<Response>,
+    ) -> Result<Response, ClientError> {
+        self.receive_within(connection, receiver, self.config.read_timeout)
+    }
 
-        let request = Request::Set { key, value };
-        debug!(log, "sending request"; "request" => ?request);
-        request
-            .to_writer(&mut self.stream)
-            .map_err(|x| ClientError::RequestError(Box::new(x)))?;
+    fn receive_within(
+        &self,
+        connection: &Connection,
+        receiver: mpsc::Receiver<Response>,
+        timeout: Option<Duration>,
+    ) -> Result<Response, ClientError> {
+        let response = match timeout {
+            Some(timeout) => receiver.recv_timeout(timeout).map_err(|err| match err {
+                mpsc::RecvTimeoutError::Timeout => {
+                    // The server might never answer, don't wait on it again.
+                    connection.close();
+                    ClientError::Timeout
+                }
+                mpsc::RecvTimeoutError::Disconnected => ClientError::NoResponse,
+            })?,
+            None => receiver.recv().map_err(|_| ClientError::NoResponse)?,
+        };
+        if let Response::Failure {
+            kind: ErrorKind::TooLarge,
+            ..
+        } = response
+        {
+            // The server hangs up after a request too large, don't send it anything else.
+            connection.close();
+        }
+        Ok(response)
+    }
 
-        let response = Response::from_reader(&mut self.stream)
-            .map_err(|x| ClientError::ResponseError(Box::new(x)))?;
-        match response {
-            Some(Response::Success(None)) => Ok(()),
-            Some(Response::Failure(m)) => Err(ClientError::ErrorResponse(m)),
-            Some(response) => Err(ClientError::UnexpectedResponse(response)),
-            None => Err(ClientError::NoResponse),
+    fn call_once(&self, request: Request) -> Result<Response, ClientError> {
+        let connection = self.connection()?;
+        let receiver = self.send(&connection, request)?;
+        connection.flush()?;
+        match self.receive(&connection, receiver)? {
+            Response::Failure {
+                kind: ErrorKind::Busy,
+                message,
+            } => Err(ClientError::Busy(message)),
+            response => Ok(response),
+        }
+    }
+
+    fn call(&self, request: Request) -> Result<Response, ClientError> {
+        for retry in 0..self.config.retry.max_retries {
+            match self.call_once(request.clone()) {
+                // Busy servers turn requests away without handling them, so any request can be
+                // retried then.
+                Err(err)
+                    if err.is_retryable()
+                        && (request.is_idempotent() || matches!(err, ClientError::Busy(_))) =>
+                {
+                    let backoff = self.config.retry.backoff(retry);
+                    debug!(self.log, "retrying request"; "error" => %err, "backoff" => ?backoff);
+                    thread::sleep(backoff);
+                }
+                result => return result,
+            }
+        }
+        self.call_once(request)
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>, ClientError> {
+        match self.call(Request::Get { key })? {
+            Response::Success(v) => Ok(v),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<(), ClientError> {
+        match self.call(Request::Set { key, value })? {
+            Response::Success(None) => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn rm(&self, key: String) -> Result<(), ClientError> {
+        match self.call(Request::Rm { key })? {
+            Response::Success(None) => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Check the server is up and answering.
+    pub fn ping(&self) -> Result<(), ClientError> {
+        self.admin(Request::Ping)
+    }
+
+    /// Ping once on the current connection, without reconnecting or retrying, waiting at most
+    /// `timeout` or the read timeout for the answer, whichever is shorter.
+    pub(super) fn ping_within(&self, timeout: Duration) -> Result<(), ClientError> {
+        let connection = self.connection.lock().unwrap().clone();
+        if connection.is_closed() {
+            return Err(ClientError::NoResponse);
+        }
+        let receiver = self.send(&connection, Request::Ping)?;
+        connection.flush()?;
+        let timeout = self.config.read_timeout.map_or(timeout, |x| x.min(timeout));
+        match self.receive_within(&connection, receiver, Some(timeout))? {
+            Response::Success(None) => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Whether the connection is known to be broken. The next request reconnects.
+    pub fn is_closed(&self) -> bool {
+        self.connection.lock().unwrap().is_closed()
+    }
+
+    /// Effective permissions of `user`, or of the user this client authenticates as when `None`.
+    pub fn permissions(&self, user: Option<String>) -> Result<Vec<Grant>, ClientError> {
+        match self.call(Request::Permissions { user })? {
+            Response::Permissions(grants) => Ok(grants),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Ask the server to back its engine up into `dir`, relative to its backup root.
+    pub fn backup(&self, dir: String) -> Result<(), ClientError> {
+        self.admin(Request::Backup { dir })
+    }
+
+    /// Ask the server to restore its engine from a backup in `dir`, relative to its backup root.
+    pub fn restore(&self, dir: String) -> Result<(), ClientError> {
+        self.admin(Request::Restore { dir })
+    }
+
+    fn admin(&self, request: Request) -> Result<(), ClientError> {
+        match self.call(request)? {
+            Response::Success(None) => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Fetch one page of entries whose key starts with `prefix` and is greater than `after`.
+    pub fn scan_page(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: u32,
+    ) -> Result<Vec<(String, String)>, ClientError> {
+        let request = Request::Scan {
+            prefix,
+            after,
+            limit,
+        };
+        match self.call(request)? {
+            Response::Entries(entries) => Ok(entries),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Iterate entries whose key starts with `prefix`, in ascending key order.
+    ///
+    /// Entries are fetched from the server a page at a time.
+    pub fn scan(&self, prefix: impl Into<String>) -> Scan<'_> {
+        Scan {
+            client: self,
+            prefix: prefix.into(),
+            after: None,
+            page: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+
+    /// Start queueing requests to send in one go.
+    pub fn pipeline(&self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+}
+
+/// Error for a response that doesn't answer the request, failures included.
+fn unexpected(response: Response) -> ClientError {
+    match response {
+        Response::Failure { kind, message } => ClientError::from_failure(kind, message),
+        response => ClientError::UnexpectedResponse(response),
+    }
+}
+
+/// Requests queued with [`KvsClient::pipeline`].
+///
+/// Nothing is sent until [`execute`](Pipeline::execute), which writes every request before reading
+/// any response, so the whole pipeline costs a single round trip.
+pub struct Pipeline<'a> {
+    client: &'a KvsClient,
+    requests: Vec<Request>,
+}
+
+impl Pipeline<'_> {
+    pub fn get(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Get { key });
+        self
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.requests.push(Request::Set { key, value });
+        self
+    }
+
+    pub fn rm(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Rm { key });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Send every queued request, then read the responses back in order.
+    ///
+    /// Returns one result per request: the value for gets, `None` for sets and removes. The outer
+    /// error is for failures of the connection itself, pipelines are never retried.
+    ///
+    /// Responses aren't read until every request is written, so very large pipelines of gets can
+    /// stall once their responses fill the socket buffers. Split those into several pipelines.
+    pub fn execute(self) -> Result<Vec<Result<Option<String>, ClientError>>, ClientError> {
+        let connection = self.client.connection()?;
+        let mut receivers = Vec::with_capacity(self.requests.len());
+        for request in self.requests {
+            let is_get = matches!(request, Request::Get { .. });
+            receivers.push((is_get, self.client.send(&connection, request)?));
+        }
+        connection.flush()?;
+
+        let mut results = Vec::with_capacity(receivers.len());
+        for (is_get, receiver) in receivers {
+            let response = self.client.receive(&connection, receiver)?;
+            let result = match response {
+                Response::Success(v) if is_get => Ok(v),
+                Response::Success(None) => Ok(None),
+                response => Err(unexpected(response)),
+            };
+            results.push(result);
         }
+        Ok(results)
+    }
+}
+
+/// One connection to the server and the thread reading its responses.
+struct Connection {
+    log: Logger,
+    stream: Stream,
+    writer: Mutex<BufWriter<Stream>>,
+    pending: Arc<Pending>,
+    /// Failure the server closed the connection with, answer to requests sent after it.
+    rejection: Arc<Mutex<Option<Response>>>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl Connection {
+    /// Connect to the first reachable of `endpoints`.
+    fn open(log: &Logger, endpoints: &[Endpoint], config: &Config) -> Result<Self, io::Error> {
+        let mut last_err = None;
+        for endpoint in endpoints {
+            debug!(log, "connecting"; "address" => %endpoint);
+            let connection = endpoint
+                .connect(config.connect_timeout)
+                .and_then(|stream| config.secure(stream))
+                .and_then(|stream| {
+                    let log = log.new(o!("address" => endpoint.to_string()));
+                    Self::start(log, stream, config)
+                })
+                .and_then(|connection| connection.authenticate(config));
+            match connection {
+                Ok(connection) => {
+                    info!(log, "connected"; "address" => %endpoint);
+                    return Ok(connection);
+                }
+                Err(err) => {
+                    warn!(log, "endpoint unreachable"; "address" => %endpoint, "error" => %err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("endpoints are never empty"))
     }
 
-    pub fn rm(&mut self, key: String) -> Result<(), ClientError> {
-        let log = &self.log;
+    fn start(log: Logger, stream: Stream, config: &Config) -> Result<Self, io::Error> {
+        stream.set_write_timeout(config.write_timeout)?;
 
-        let request = Request::Rm { key };
-        debug!(log, "sending request"; "request" => ?request);
-        request
-            .to_writer(&mut self.stream)
-            .map_err(|x| ClientError::RequestError(Box::new(x)))?;
+        let pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let rejection = Arc::new(Mutex::new(None));
+        let reader = {
+            let log = log.clone();
+            let stream = stream.try_clone()?;
+            let pending = pending.clone();
+            let rejection = rejection.clone();
+            thread::spawn(move || read_responses(log, stream, pending, rejection))
+        };
+        Ok(Self {
+            log,
+            writer: Mutex::new(BufWriter::new(stream.try_clone()?)),
+            stream,
+            pending,
+            rejection,
+            reader: Some(reader),
+        })
+    }
 
-        let response = Response::from_reader(&mut self.stream)
-            .map_err(|x| ClientError::ResponseError(Box::new(x)))?;
+    /// Authenticate with the configured credentials, if any. Rejected credentials are a
+    /// `PermissionDenied` error.
+    fn authenticate(self, config: &Config) -> Result<Self, io::Error> {
+        let (user, token) = match &config.credentials {
+            Some(x) => x,
+            None => return Ok(self),
+        };
+        let request = Request::Auth {
+            user: user.clone(),
+            token: token.clone(),
+        };
+        // Requests of the client are numbered from 1, the ID can't be taken.
+        let response = self
+            .send(0, request)
+            .and_then(|receiver| {
+                self.flush()?;
+                match config.read_timeout {
+                    Some(timeout) => receiver.recv_timeout(timeout).ok(),
+                    None => receiver.recv().ok(),
+                }
+                .ok_or(ClientError::NoResponse)
+            })
+            .map_err(|err| io::Error::new(io::ErrorKind::ConnectionAborted, err.to_string()))?;
         match response {
-            Some(Response::Success(None)) => Ok(()),
-            Some(Response::Failure(m)) => Err(ClientError::ErrorResponse(m)),
-            Some(response) => Err(ClientError::UnexpectedResponse(response)),
-            None => Err(ClientError::NoResponse),
+            Response::Success(None) => Ok(self),
+            Response::Failure {
+                kind: ErrorKind::Unauthorized,
+                message,
+            } => Err(io::Error::new(io::ErrorKind::PermissionDenied, message)),
+            response => Err(io::Error::other(unexpected(response).to_string())),
+        }
+    }
+
+    fn send(&self, id: u64, request: Request) -> Result<mpsc::Receiver<Response>, ClientError> {
+        let (sender, receiver) = mpsc::channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.insert(id, sender),
+            None => {
+                return Err(match self.rejection.lock().unwrap().clone() {
+                    Some(response) => unexpected(response),
+                    None => ClientError::NoResponse,
+                })
+            }
+        };
+
+        debug!(self.log, "sending request"; "id" => id, "request" => ?request);
+        let result = Envelope::new(id, request).to_writer(&mut *self.writer.lock().unwrap());
+        if let Err(x) = result {
+            self.close();
+            return Err(ClientError::RequestError(Box::new(x)));
         }
+        Ok(receiver)
+    }
+
+    fn flush(&self) -> Result<(), ClientError> {
+        self.writer.lock().unwrap().flush().map_err(|x| {
+            self.close();
+            ClientError::RequestError(Box::new(x))
+        })
+    }
+
+    fn is_closed(&self) -> bool {
+        self.pending.lock().unwrap().is_none()
+    }
+
+    /// Fail every request in flight and stop accepting new ones.
+    fn close(&self) {
+        self.pending.lock().unwrap().take();
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Unblocks the response reader.
+        let _ = self.stream.shutdown(Shutdown::Both);
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+/// Hand responses to the callers waiting for them, until the connection closes.
+///
+/// A failure with ID 0 nobody waits for is about the whole connection, like a busy server turning
+/// it away. It answers every request in flight and any sent later.
+fn read_responses(
+    log: Logger,
+    stream: Stream,
+    pending: Arc<Pending>,
+    rejection: Arc<Mutex<Option<Response>>>,
+) {
+    let mut reader = BufReader::new(stream);
+    loop {
+        match Envelope::<Response>::from_reader(&mut reader) {
+            Ok(Some(Envelope { id, body })) => {
+                let sender = pending.lock().unwrap().as_mut().and_then(|x| x.remove(&id));
+                match sender {
+                    Some(sender) => {
+                        let _ = sender.send(body);
+                    }
+                    None if id == 0 && matches!(body, Response::Failure { .. }) => {
+                        warn!(log, "connection closed by server"; "response" => ?body);
+                        *rejection.lock().unwrap() = Some(body.clone());
+                        let senders = pending.lock().unwrap().take().unwrap_or_default();
+                        for sender in senders.values() {
+                            let _ = sender.send(body.clone());
+                        }
+                        break;
+                    }
+                    None => {
+                        debug!(log, "response without waiting request"; "id" => id, "response" => ?body)
+                    }
+                }
+            }
+            Ok(None) => {
+                debug!(log, "connection closed");
+                break;
+            }
+            Err(err) => {
+                error!(log, "failed to read response"; "error" => %err);
+                break;
+            }
+        }
+    }
+    // Drops the senders, every caller still waiting gets `NoResponse`.
+    pending.lock().unwrap().take();
+}
+
+/// Iterator returned by [`KvsClient::scan`].
+pub struct Scan<'a> {
+    client: &'a KvsClient,
+    prefix: String,
+    after: Option<String>,
+    page: std::vec::IntoIter<(String, String)>,
+    done: bool,
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(String, String), ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.page.next() {
+            return Some(Ok(entry));
+        }
+        if self.done {
+            return None;
+        }
+        let page = self
+            .client
+            .scan_page(self.prefix.clone(), self.after.take(), SCAN_PAGE_SIZE);
+        match page {
+            Ok(page) => {
+                self.done = page.len() < SCAN_PAGE_SIZE as usize;
+                self.after = page.last().map(|(key, _)| key.clone());
+                self.page = page.into_iter();
+                self.page.next().map(Ok)
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Accept connections on `listener`, answering every get on them with `value`.
+    fn answer_gets(listener: &TcpListener, value: &str) {
+        let stream = listener.accept().unwrap().0;
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = BufWriter::new(stream);
+        while let Ok(Some(request)) = Envelope::<Request>::from_reader(&mut reader) {
+            let response = Response::Success(Some(value.to_owned()));
+            Envelope::new(request.id, response)
+                .to_writer(&mut writer)
+                .unwrap();
+            writer.flush().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_reconnect_after_server_restart() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            // First connection is dropped right away, like a server going down.
+            drop(listener.accept().unwrap());
+            answer_gets(&listener, "value");
+        });
+
+        let client = KvsClient::builder(address)
+            .retry(RetryPolicy::none())
+            .connect()
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(client.is_closed());
+
+        assert_eq!(
+            client.get("key".to_owned()).unwrap(),
+            Some("value".to_owned())
+        );
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+        };
+        assert_eq!(policy.backoff(0), Duration::from_millis(10));
+        assert_eq!(policy.backoff(2), Duration::from_millis(40));
+        assert_eq!(policy.backoff(3), Duration::from_millis(50));
+        assert_eq!(policy.backoff(40), Duration::from_millis(50));
     }
 }
@@ -1,4 +1,7 @@
+#[allow(clippy::module_inception)]
 mod client;
+mod pool;
 
-pub use crate::protocol::Request;
-pub use client::KvsClient;
+pub use crate::protocol::{Access, Grant, Request, Secret, MAX_SCAN_LIMIT};
+pub use client::{ClientError, KvsClient, KvsClientBuilder, Pipeline, RetryPolicy, Scan};
+pub use pool::{KvsClientPool, PoolConfig, PoolError, PooledClient};
@@ -0,0 +1,193 @@
+use super::{client::Connector, KvsClient, KvsClientBuilder};
+use slog::debug;
+use std::{
+    io,
+    ops::Deref,
+    sync::{Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PoolError {
+    #[error("timed out waiting for a connection")]
+    Timeout,
+    #[error("failed to connect, caused by {0}")]
+    Connect(io::Error),
+}
+
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    /// Most connections open at once, checked out or idle.
+    pub max_size: usize,
+    /// Idle connections are closed after this long.
+    pub idle_timeout: Duration,
+    /// Idle connections are pinged before reuse once they've been idle this long.
+    pub health_check_after: Duration,
+    /// How long a checkout waits for a connection when all of them are in use.
+    pub checkout_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 8,
+            idle_timeout: Duration::from_secs(60),
+            health_check_after: Duration::from_secs(1),
+            checkout_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+struct Idle {
+    client: KvsClient,
+    since: Instant,
+}
+
+struct State {
+    /// Most recently returned last.
+    idle: Vec<Idle>,
+    /// Connections open, checked out or idle.
+    open: usize,
+}
+
+/// Pool of connections to one server, shareable between threads.
+///
+/// Connections are opened on demand up to the maximum size, each with the endpoints, timeouts,
+/// retry policy and credentials of the builder the pool was created from. Broken ones are dropped
+/// when they're returned or fail their health check, and replaced by a new connection on the next
+/// checkout.
+pub struct KvsClientPool {
+    connector: Connector,
+    config: PoolConfig,
+    state: Mutex<State>,
+    returned: Condvar,
+}
+
+impl KvsClientPool {
+    /// Pool opening its connections like `builder` would, failing if it has no endpoint.
+    pub fn new(builder: KvsClientBuilder, config: PoolConfig) -> Result<Self, io::Error> {
+        Ok(Self {
+            connector: builder.connector()?,
+            config,
+            state: Mutex::new(State {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            returned: Condvar::new(),
+        })
+    }
+
+    /// Check a connection out, it goes back to the pool when dropped.
+    ///
+    /// The health check pings once, without retries, within the time left on the checkout timeout.
+    pub fn get(&self) -> Result<PooledClient<'_>, PoolError> {
+        let deadline = Instant::now() + self.config.checkout_timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            self.evict_idle(&mut state);
+
+            if let Some(idle) = state.idle.pop() {
+                drop(state);
+                if idle.since.elapsed() < self.config.health_check_after
+                    || idle
+                        .client
+                        .ping_within(deadline.saturating_duration_since(Instant::now()))
+                        .is_ok()
+                {
+                    return Ok(self.pooled(idle.client));
+                }
+                debug!(
+                    self.connector.log,
+                    "dropping connection failing health check"
+                );
+                state = self.state.lock().unwrap();
+                state.open -= 1;
+                continue;
+            }
+
+            if state.open < self.config.max_size {
+                state.open += 1;
+                drop(state);
+                return match self.connector.connect() {
+                    Ok(client) => Ok(self.pooled(client)),
+                    Err(err) => {
+                        self.release_slot();
+                        Err(PoolError::Connect(err))
+                    }
+                };
+            }
+
+            let timeout = match deadline.checked_duration_since(Instant::now()) {
+                Some(x) if !x.is_zero() => x,
+                _ => return Err(PoolError::Timeout),
+            };
+            state = self.returned.wait_timeout(state, timeout).unwrap().0;
+        }
+    }
+
+    /// Number of connections open, checked out or idle.
+    pub fn size(&self) -> usize {
+        self.state.lock().unwrap().open
+    }
+
+    fn pooled(&self, client: KvsClient) -> PooledClient<'_> {
+        PooledClient {
+            pool: self,
+            client: Some(client),
+        }
+    }
+
+    fn evict_idle(&self, state: &mut MutexGuard<'_, State>) {
+        let idle_timeout = self.config.idle_timeout;
+        let before = state.idle.len();
+        state
+            .idle
+            .retain(|idle| idle.since.elapsed() < idle_timeout);
+        let evicted = before - state.idle.len();
+        if evicted > 0 {
+            debug!(self.connector.log, "evicted idle connections"; "count" => evicted);
+            state.open -= evicted;
+        }
+    }
+
+    fn release_slot(&self) {
+        self.state.lock().unwrap().open -= 1;
+        self.returned.notify_one();
+    }
+
+    fn put_back(&self, client: KvsClient) {
+        if client.is_closed() {
+            debug!(self.connector.log, "dropping broken connection");
+            self.release_slot();
+            return;
+        }
+        self.state.lock().unwrap().idle.push(Idle {
+            client,
+            since: Instant::now(),
+        });
+        self.returned.notify_one();
+    }
+}
+
+/// Connection checked out of a [`KvsClientPool`].
+pub struct PooledClient<'a> {
+    pool: &'a KvsClientPool,
+    client: Option<KvsClient>,
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().expect("client is only taken on drop")
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.put_back(client);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! Behaviour every [`KvsEngine`] is expected to share.
+//!
+//! Engine implementations, in this crate or not, can prove they behave like the others by running
+//! the suite from a test:
+//!
+//! ```no_run
+//! let dir = tempfile::tempdir().unwrap();
+//! kvs::conformance::run_conformance(dir.path(), |dir| kvs::KvStore::open(dir).unwrap());
+//! ```
+//!
+//! The factory is given a directory and must open the engine stored there. It's called again with
+//! the same directory after the previous engine is dropped, to check that writes persist. A failed
+//! check panics like an assertion, with the name of the check in the message.
+
+use crate::{KvsEngine, KvsEngineError};
+use std::{
+    fs,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::Mutex,
+    thread,
+};
+
+type Check<E> = fn(&mut dyn FnMut(&Path) -> E, &Path);
+
+/// Run every check against engines made by `factory`, each check in a fresh subdirectory of
+/// `dir`, which should be empty.
+pub fn run_conformance<E, F>(dir: &Path, mut factory: F)
+where
+    E: KvsEngine + Send,
+    F: FnMut(&Path) -> E,
+{
+    let checks: &[(&str, Check<E>)] = &[
+        ("get_stored_value", get_stored_value),
+        ("overwrite_value", overwrite_value),
+        ("get_non_existent_value", get_non_existent_value),
+        ("remove_key", remove_key),
+        ("remove_non_existent_key", remove_non_existent_key),
+        ("reopen_persists", reopen_persists),
+        ("large_value", large_value),
+        ("unicode_keys", unicode_keys),
+        ("scan_in_order", scan_in_order),
+        ("scan_after_key", scan_after_key),
+        ("concurrent_access", concurrent_access),
+    ];
+    for (name, check) in checks {
+        let dir = dir.join(name);
+        fs::create_dir(&dir).expect("unable to create check directory");
+        let result = panic::catch_unwind(AssertUnwindSafe(|| check(&mut factory, &dir)));
+        if let Err(payload) = result {
+            let message = match payload.downcast_ref::<&str>() {
+                Some(message) => *message,
+                None => match payload.downcast_ref::<String>() {
+                    Some(message) => message,
+                    None => panic::resume_unwind(payload),
+                },
+            };
+            panic!("conformance check `{}` failed: {}", name, message);
+        }
+    }
+}
+
+fn get_stored_value<E: KvsEngine>(factory: &mut dyn FnMut(&Path) -> E, dir: &Path) {
+    let mut engine = factory(dir);
+    engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    engine.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(engine.get("key1").unwrap(), Some("value1".to_owned()));
+    assert_eq!(engine.get("key2").unwrap(), Some("value2".to_owned()));
+}
+
+fn overwrite_value<E: KvsEngine>(factory: &mut dyn FnMut(&Path) -> E, dir: &Path) {
+    let mut engine = factory(dir);
+    engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    engine.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(engine.get("key1").unwrap(), Some("value2".to_owned()));
+}
+
+fn get_non_existent_value<E: KvsEngine>(factory: &mut dyn FnMut(&Path) -> E, dir: &Path) {
+    let mut engine = factory(dir);
+    engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(engine.get("key2").unwrap(), None);
+}
+
+fn remove_key<E: KvsEngine>(factory: &mut dyn FnMut(&Path) -> E, dir: &Path) {
+    let mut engine = factory(dir);
+    engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    engine.remove("key1").unwrap();
+    assert_eq!(engine.get("key1").unwrap(), None);
+}
+
+fn remove_non_existent_key<E: KvsEngine>(factory: &mut dyn FnMut(&Path) -> E, dir: &Path) {
+    let mut engine = factory(dir);
+    match engine.remove("key1") {
+        Err(KvsEngineError::EntryNotFound { key }) => assert_eq!(key, "key1"),
+        other => panic!("expected EntryNotFound, found {:?}", other),
+    }
+}
+
+fn reopen_persists<E: KvsEngine>(factory: &mut dyn FnMut(&Path) -> E, dir: &Path) {
+    let mut engine = factory(dir);
+    engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    engine.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    engine.set("key2".to_owned(), "value3".to_owned()).unwrap();
+    engine.set("key3".to_owned(), "value4".to_owned()).unwrap();
+    engine.remove("key3").unwrap();
+    drop(engine);
+
+    let mut engine = factory(dir);
+    assert_eq!(engine.get("key1").unwrap(), Some("value1".to_owned()));
+    assert_eq!(engine.get("key2").unwrap(), Some("value3".to_owned()));
+    assert_eq!(engine.get("key3").unwrap(), None);
+}
+
+fn large_value<E: KvsEngine>(factory: &mut dyn FnMut(&Path) -> E, dir: &Path) {
+    let value = "x".repeat(4 * 1024 * 1024);
+    let mut engine = factory(dir);
+    engine.set("key1".to_owned(), value.clone()).unwrap();
+    drop(engine);
+
+    let mut engine = factory(dir);
+    assert_eq!(engine.get("key1").unwrap(), Some(value));
+}
+
+fn unicode_keys<E: KvsEngine>(factory: &mut dyn FnMut(&Path) -> E, dir: &Path) {
+    let entries = [
+        ("ключ", "значение"),
+        ("鍵", "値"),
+        ("🔑", "🗝️"),
+        ("", "empty"),
+    ];
+    let mut engine = factory(dir);
+    for (key, value) in &entries {
+        engine.set(key.to_string(), value.to_string()).unwrap();
+    }
+    for (key, value) in &entries {
+        assert_eq!(engine.get(key).unwrap().as_deref(), Some(*value));
+    }
+}
+
+fn scan_in_order<E: KvsEngine>(factory: &mut dyn FnMut(&Path) -> E, dir: &Path) {
+    let mut engine = factory(dir);
+    for key in &["b2", "a1", "b1", "c1", "b3"] {
+        engine.set(key.to_string(), key.to_uppercase()).unwrap();
+    }
+    engine.remove("b3").unwrap();
+
+    let entries: Vec<(String, String)> =
+        engine.scan("b").unwrap().collect::<Result<_, _>>().unwrap();
+    assert_eq!(
+        entries,
+        vec![
+            ("b1".to_owned(), "B1".to_owned()),
+            ("b2".to_owned(), "B2".to_owned()),
+        ]
+    );
+    assert_eq!(engine.scan("").unwrap().count(), 4);
+}
+
+fn scan_after_key<E: KvsEngine>(factory: &mut dyn FnMut(&Path) -> E, dir: &Path) {
+    let mut engine = factory(dir);
+    for key in &["a1", "b1", "b2", "b3", "c1"] {
+        engine.set(key.to_string(), key.to_uppercase()).unwrap();
+    }
+
+    let keys = |entries: crate::engine::Entries<'_>| -> Vec<String> {
+        entries.map(|entry| entry.unwrap().0).collect()
+    };
+    assert_eq!(
+        keys(engine.scan_after("b", "b1").unwrap()),
+        vec!["b2", "b3"]
+    );
+    assert_eq!(
+        keys(engine.scan_after("b", "b11").unwrap()),
+        vec!["b2", "b3"]
+    );
+    assert_eq!(
+        keys(engine.scan_after("b", "a9").unwrap()),
+        vec!["b1", "b2", "b3"]
+    );
+    assert!(keys(engine.scan_after("b", "b3").unwrap()).is_empty());
+    assert_eq!(keys(engine.scan_after("", "b3").unwrap()), vec!["c1"]);
+}
+
+/// Engines take `&mut self`, so concurrent callers share one through a lock.
+fn concurrent_access<E: KvsEngine + Send>(factory: &mut dyn FnMut(&Path) -> E, dir: &Path) {
+    let engine = Mutex::new(factory(dir));
+    thread::scope(|scope| {
+        for t in 0..4 {
+            let engine = &engine;
+            scope.spawn(move || {
+                for i in 0..100 {
+                    let key = format!("key{}-{}", t, i);
+                    let mut engine = engine.lock().unwrap();
+                    engine.set(key.clone(), format!("{}", i)).unwrap();
+                    assert_eq!(engine.get(&key).unwrap(), Some(format!("{}", i)));
+                }
+            });
+        }
+    });
+
+    let mut engine = engine.lock().unwrap();
+    assert_eq!(engine.scan("key").unwrap().count(), 400);
+}
@@ -1,3 +1,7 @@
+pub mod registry;
+
+use crate::limits::LimitError;
+use std::{collections::BTreeMap, io, ops::Bound, path::Path};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -5,10 +9,62 @@ pub enum KvsEngineError {
     #[error("entry with key `{key}` not found")]
     EntryNotFound { key: String },
 
+    #[error("operation `{0}` is not supported by this engine")]
+    Unsupported(&'static str),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    TooLarge(#[from] LimitError),
+
+    #[error("data is corrupted: {0}")]
+    Corruption(String),
+
     #[error("{0}")]
     Other(Box<dyn std::error::Error>),
 }
 
+/// Iterator over `(key, value)` entries of an engine.
+pub type Entries<'a> = Box<dyn Iterator<Item = Result<(String, String), KvsEngineError>> + 'a>;
+
+/// Entries of `map` whose key starts with `prefix` and is greater than `after`, in key order.
+///
+/// Starts right at the first of them, so paging through a map costs only what each page returns.
+pub(crate) fn prefix_range<'a, V>(
+    map: &'a BTreeMap<String, V>,
+    prefix: &str,
+    after: Option<&str>,
+) -> impl Iterator<Item = (&'a String, &'a V)> + 'a {
+    let start = match after {
+        Some(after) if after >= prefix => Bound::Excluded(after.to_owned()),
+        _ => Bound::Included(prefix.to_owned()),
+    };
+    let prefix = prefix.to_owned();
+    map.range((start, Bound::Unbounded))
+        .take_while(move |(key, _)| key.starts_with(&prefix))
+}
+
+/// Single write of a batch.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchOp {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+/// Change made to an entry, reported to watchers.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WatchEvent {
+    Set { key: String, value: String },
+    Removed { key: String },
+}
+
+/// Blocking iterator over changes, ends when the engine is dropped.
+pub type Watcher = Box<dyn Iterator<Item = Result<WatchEvent, KvsEngineError>> + Send>;
+
+/// Rest of a backup started by [`KvsEngine::start_backup`], runs without the engine.
+pub type BackupJob = Box<dyn FnOnce() -> Result<(), KvsEngineError> + Send>;
+
 pub trait KvsEngine {
     /// Set the value of a string key to a string. Return an error if the value is not written
     /// successfully.
@@ -21,6 +77,66 @@ pub trait KvsEngine {
     /// Remove a given string key. Return an error if the key does not exit or value is not read
     /// successfully.
     fn remove(&mut self, key: &str) -> Result<(), KvsEngineError>;
+
+    /// Iterate entries whose key starts with `prefix`, in ascending key order. Pass an empty
+    /// prefix to iterate every entry.
+    fn scan(&mut self, prefix: &str) -> Result<Entries<'_>, KvsEngineError>;
+
+    /// Like [`scan`](Self::scan), only entries with key greater than `after`. Engines with a
+    /// sorted index should start right after it instead of skipping what comes before.
+    fn scan_after(&mut self, prefix: &str, after: &str) -> Result<Entries<'_>, KvsEngineError> {
+        let after = after.to_owned();
+        let entries = self
+            .scan(prefix)?
+            .skip_while(move |entry| matches!(entry, Ok((key, _)) if *key <= after));
+        Ok(Box::new(entries))
+    }
+
+    /// Apply every write or none of them. Removing a missing key in a batch is not an error.
+    fn apply_batch(&mut self, _ops: Vec<BatchOp>) -> Result<(), KvsEngineError> {
+        Err(KvsEngineError::Unsupported("batch"))
+    }
+
+    /// Set `key` to `new`, or remove it if `new` is `None`, only if its value is `current`. Returns
+    /// whether the swap happened.
+    fn compare_and_swap(
+        &mut self,
+        _key: &str,
+        _current: Option<&str>,
+        _new: Option<String>,
+    ) -> Result<bool, KvsEngineError> {
+        Err(KvsEngineError::Unsupported("compare_and_swap"))
+    }
+
+    /// Subscribe to changes of entries whose key starts with `prefix`.
+    fn watch_prefix(&mut self, _prefix: &str) -> Result<Watcher, KvsEngineError> {
+        Err(KvsEngineError::Unsupported("watch_prefix"))
+    }
+
+    /// Write a consistent copy of the engine data into a directory. Writes may continue while the
+    /// backup runs but won't be part of it.
+    fn backup(&mut self, _dir: &Path) -> Result<(), KvsEngineError> {
+        Err(KvsEngineError::Unsupported("backup"))
+    }
+
+    /// Like [`backup`](Self::backup), only capturing what the backup needs and leaving the copy
+    /// to the returned job, so the engine is free for other requests while it runs. Engines that
+    /// can't split the work write the whole backup right away.
+    fn start_backup(&mut self, dir: &Path) -> Result<BackupJob, KvsEngineError> {
+        self.backup(dir)?;
+        Ok(Box::new(|| Ok(())))
+    }
+
+    /// Replace the engine data with a backup previously written by `backup`.
+    fn restore(&mut self, _dir: &Path) -> Result<(), KvsEngineError> {
+        Err(KvsEngineError::Unsupported("restore"))
+    }
+
+    /// Write out whatever the engine keeps in memory only, so it survives the process. Engines
+    /// persisting every write have nothing to do.
+    fn flush(&mut self) -> Result<(), KvsEngineError> {
+        Ok(())
+    }
 }
 
 impl<T> KvsEngine for Box<T>
@@ -38,4 +154,45 @@ where
     fn remove(&mut self, key: &str) -> Result<(), KvsEngineError> {
         (self as &mut T).remove(key)
     }
+
+    fn scan(&mut self, prefix: &str) -> Result<Entries<'_>, KvsEngineError> {
+        (self as &mut T).scan(prefix)
+    }
+
+    fn scan_after(&mut self, prefix: &str, after: &str) -> Result<Entries<'_>, KvsEngineError> {
+        (self as &mut T).scan_after(prefix, after)
+    }
+
+    fn apply_batch(&mut self, ops: Vec<BatchOp>) -> Result<(), KvsEngineError> {
+        (self as &mut T).apply_batch(ops)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: &str,
+        current: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool, KvsEngineError> {
+        (self as &mut T).compare_and_swap(key, current, new)
+    }
+
+    fn watch_prefix(&mut self, prefix: &str) -> Result<Watcher, KvsEngineError> {
+        (self as &mut T).watch_prefix(prefix)
+    }
+
+    fn backup(&mut self, dir: &Path) -> Result<(), KvsEngineError> {
+        (self as &mut T).backup(dir)
+    }
+
+    fn start_backup(&mut self, dir: &Path) -> Result<BackupJob, KvsEngineError> {
+        (self as &mut T).start_backup(dir)
+    }
+
+    fn restore(&mut self, dir: &Path) -> Result<(), KvsEngineError> {
+        (self as &mut T).restore(dir)
+    }
+
+    fn flush(&mut self) -> Result<(), KvsEngineError> {
+        (self as &mut T).flush()
+    }
 }
@@ -0,0 +1,324 @@
+//! Engines selectable by name.
+//!
+//! Every engine registers a name, the options it accepts and a function opening it in a
+//! directory. [`EngineRegistry::default`] knows the engines of this crate, downstream crates can
+//! [`register`](EngineRegistry::register) their own on top.
+
+use super::{KvsEngine, KvsEngineError};
+use crate::{KvStore, Limits, LsmStore, MemoryEngine, SledConfig, SledKvsEngine};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+use thiserror::Error;
+
+/// Engine as returned by the registry.
+pub type BoxedEngine = Box<dyn KvsEngine + Send>;
+
+/// Opens the engine stored in a directory with the given options.
+pub type OpenFn = fn(&Path, &EngineOptions) -> Result<BoxedEngine, KvsEngineError>;
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("unknown engine `{name}`, expected one of: {}", .available.join(", "))]
+    UnknownEngine {
+        name: String,
+        available: Vec<&'static str>,
+    },
+
+    #[error("engine `{0}` is already registered")]
+    Duplicate(&'static str),
+
+    #[error("engine name `{0}` isn't lowercase")]
+    InvalidName(&'static str),
+
+    #[error("engine `{engine}` has no option `{option}`")]
+    UnknownOption {
+        engine: &'static str,
+        option: String,
+    },
+
+    #[error("invalid engine option `{0}`, expected `name=value`")]
+    InvalidOption(String),
+
+    #[error(transparent)]
+    Open(#[from] KvsEngineError),
+}
+
+/// Option accepted by an engine.
+#[derive(Clone, Debug)]
+pub struct EngineOption {
+    pub name: &'static str,
+    pub about: &'static str,
+}
+
+#[derive(Clone, Debug)]
+pub struct EngineDescriptor {
+    pub name: &'static str,
+    pub about: &'static str,
+    pub options: &'static [EngineOption],
+    pub open: OpenFn,
+}
+
+/// Options passed to an engine when it's opened.
+#[derive(Clone, Default, Debug)]
+pub struct EngineOptions {
+    values: HashMap<String, String>,
+}
+
+impl EngineOptions {
+    /// Parse options written as `name=value`.
+    pub fn parse<'a>(options: impl IntoIterator<Item = &'a str>) -> Result<Self, RegistryError> {
+        let mut values = HashMap::new();
+        for option in options {
+            match option.split_once('=') {
+                Some((name, value)) if !name.is_empty() => {
+                    values.insert(name.to_owned(), value.to_owned());
+                }
+                _ => return Err(RegistryError::InvalidOption(option.to_owned())),
+            }
+        }
+        Ok(Self { values })
+    }
+
+    pub fn set(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.values.insert(name.into(), value.into());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    /// Parse an option value, returning `None` when it isn't set.
+    pub fn parse_value<T>(&self, name: &str) -> Result<Option<T>, KvsEngineError>
+    where
+        T: std::str::FromStr,
+        T::Err: std::fmt::Display,
+    {
+        self.get(name)
+            .map(|value| {
+                value.parse().map_err(|err| {
+                    let message = format!("invalid engine option `{}`: {}", name, err);
+                    KvsEngineError::Other(message.into())
+                })
+            })
+            .transpose()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EngineRegistry {
+    engines: BTreeMap<&'static str, EngineDescriptor>,
+}
+
+impl EngineRegistry {
+    /// Create a registry without any engine.
+    pub fn empty() -> Self {
+        Self {
+            engines: BTreeMap::new(),
+        }
+    }
+
+    /// Add an engine, its name must be lowercase since lookups ignore case.
+    pub fn register(&mut self, engine: EngineDescriptor) -> Result<(), RegistryError> {
+        if engine.name != engine.name.to_lowercase() {
+            return Err(RegistryError::InvalidName(engine.name));
+        }
+        if self.engines.contains_key(engine.name) {
+            return Err(RegistryError::Duplicate(engine.name));
+        }
+        self.engines.insert(engine.name, engine);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&EngineDescriptor> {
+        self.engines.get(name.to_lowercase().as_str())
+    }
+
+    /// Registered engines, ordered by name.
+    pub fn engines(&self) -> impl Iterator<Item = &EngineDescriptor> {
+        self.engines.values()
+    }
+
+    /// Open engine `name` in `dir`, rejecting options the engine doesn't declare.
+    pub fn open(
+        &self,
+        name: &str,
+        dir: &Path,
+        options: &EngineOptions,
+    ) -> Result<BoxedEngine, RegistryError> {
+        let engine = self.get(name).ok_or_else(|| RegistryError::UnknownEngine {
+            name: name.to_owned(),
+            available: self.engines.keys().copied().collect(),
+        })?;
+        for option in options.values.keys() {
+            if !engine.options.iter().any(|x| x.name == option) {
+                return Err(RegistryError::UnknownOption {
+                    engine: engine.name,
+                    option: option.to_owned(),
+                });
+            }
+        }
+        Ok((engine.open)(dir, options)?)
+    }
+}
+
+impl Default for EngineRegistry {
+    /// Registry with every engine of this crate.
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        for engine in builtin() {
+            registry
+                .register(engine)
+                .expect("builtin engine names are unique");
+        }
+        registry
+    }
+}
+
+/// Options of the engines enforcing size limits of their own.
+const LIMIT_OPTIONS: &[EngineOption] = &[
+    EngineOption {
+        name: "max_key_size",
+        about: "Largest key accepted, in bytes",
+    },
+    EngineOption {
+        name: "max_value_size",
+        about: "Largest value accepted, in bytes",
+    },
+];
+
+/// Limits set by [`LIMIT_OPTIONS`], the defaults for those left out.
+fn limits(options: &EngineOptions) -> Result<Limits, KvsEngineError> {
+    let mut limits = Limits::default();
+    if let Some(size) = options.parse_value("max_key_size")? {
+        limits.max_key_size = size;
+    }
+    if let Some(size) = options.parse_value("max_value_size")? {
+        limits.max_value_size = size;
+    }
+    Ok(limits)
+}
+
+fn builtin() -> Vec<EngineDescriptor> {
+    vec![
+        EngineDescriptor {
+            name: "kvs",
+            about: "Log-structured store with an in-memory ordered index",
+            options: LIMIT_OPTIONS,
+            open: |dir, options| {
+                let limits = limits(options)?;
+                Ok(Box::new(KvStore::open(dir)?.with_limits(limits)))
+            },
+        },
+        EngineDescriptor {
+            name: "sled",
+            about: "sled embedded database",
+            options: &[
+                EngineOption {
+                    name: "cache_capacity",
+                    about: "Page cache size in bytes",
+                },
+                EngineOption {
+                    name: "compression",
+                    about: "`true` to compress with zstd, needs the `sled-compression` feature",
+                },
+                EngineOption {
+                    name: "flush_interval_ms",
+                    about: "Flush in the background at this interval instead of after every write",
+                },
+                EngineOption {
+                    name: "mode",
+                    about: "`low-space` or `high-throughput`",
+                },
+            ],
+            open: |dir, options| {
+                let config = SledConfig {
+                    cache_capacity: options.parse_value("cache_capacity")?,
+                    compression: options.parse_value("compression")?.unwrap_or(false),
+                    flush_interval_ms: options.parse_value("flush_interval_ms")?,
+                    mode: options.parse_value("mode")?,
+                };
+                Ok(Box::new(SledKvsEngine::with_config(dir, &config)?))
+            },
+        },
+        EngineDescriptor {
+            name: "lsm",
+            about: "Log-structured merge tree",
+            options: LIMIT_OPTIONS,
+            open: |dir, options| Ok(Box::new(LsmStore::open_with_limits(dir, limits(options)?)?)),
+        },
+        EngineDescriptor {
+            name: "memory",
+            about: "Keeps every entry in memory",
+            options: &[EngineOption {
+                name: "snapshot",
+                about: "Snapshot file loaded on start and written on shutdown",
+            }],
+            open: |dir, options| {
+                let engine = match options.get("snapshot") {
+                    Some(path) => MemoryEngine::with_snapshot(dir.join(path))?,
+                    None => MemoryEngine::new(),
+                };
+                Ok(Box::new(engine))
+            },
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_rejects_unknown_names() {
+        let registry = EngineRegistry::default();
+        let dir = tempfile::tempdir().unwrap();
+
+        let err = registry
+            .open("nope", dir.path(), &EngineOptions::default())
+            .err()
+            .unwrap();
+        assert!(matches!(err, RegistryError::UnknownEngine { .. }));
+
+        let options = EngineOptions::parse(vec!["cache=1"]).unwrap();
+        let err = registry.open("kvs", dir.path(), &options).err().unwrap();
+        assert!(matches!(err, RegistryError::UnknownOption { .. }));
+
+        assert!(EngineOptions::parse(vec!["cache"]).is_err());
+    }
+
+    #[test]
+    fn test_register_custom_engine() {
+        let mut registry = EngineRegistry::default();
+        let custom = EngineDescriptor {
+            name: "custom",
+            about: "",
+            options: &[],
+            open: |_, _| Ok(Box::new(MemoryEngine::new())),
+        };
+        registry.register(custom.clone()).unwrap();
+        assert!(registry.register(custom).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = registry
+            .open("custom", dir.path(), &EngineOptions::default())
+            .unwrap();
+        engine.set("key".to_owned(), "value".to_owned()).unwrap();
+        assert_eq!(engine.get("key").unwrap(), Some("value".to_owned()));
+    }
+
+    #[test]
+    fn test_register_rejects_mixed_case_name() {
+        let mut registry = EngineRegistry::default();
+        let custom = EngineDescriptor {
+            name: "Custom",
+            about: "",
+            options: &[],
+            open: |_, _| Ok(Box::new(MemoryEngine::new())),
+        };
+        let err = registry.register(custom).err().unwrap();
+        assert!(matches!(err, RegistryError::InvalidName("Custom")));
+        assert!(registry.get("custom").is_none());
+    }
+}
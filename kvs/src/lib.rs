@@ -1,16 +1,30 @@
 mod alt;
 mod engine;
 mod protocol;
+mod transport;
 
 pub mod app;
 pub mod client;
+pub mod conformance;
+pub mod limits;
+pub mod lsm;
+pub mod memory;
 pub mod server;
 pub mod store;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transfer;
 
-pub use client::KvsClient;
-pub use engine::{KvsEngine, KvsEngineError};
+pub use alt::{SledConfig, SledKvsEngine, SledMode};
+pub use client::{KvsClient, KvsClientPool};
+pub use engine::{
+    registry, registry::EngineRegistry, BackupJob, BatchOp, Entries, KvsEngine, KvsEngineError,
+    WatchEvent, Watcher,
+};
+pub use limits::Limits;
+pub use lsm::LsmStore;
+pub use memory::MemoryEngine;
 pub use server::KvsServer;
-pub use sled::Db as SledKvsEngine;
 pub use store::KvStore;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -0,0 +1,55 @@
+//! Size limits on keys, values and requests.
+
+use thiserror::Error;
+
+#[derive(Error, Clone, Debug, PartialEq)]
+pub enum LimitError {
+    #[error("key of {size} bytes is larger than the limit of {limit} bytes")]
+    Key { size: usize, limit: usize },
+
+    #[error("value of {size} bytes is larger than the limit of {limit} bytes")]
+    Value { size: usize, limit: usize },
+}
+
+/// Largest keys, values and requests accepted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+    /// Largest encoded request body a server reads. Decoding stops as soon as a request claims
+    /// to be larger, so nothing is allocated for it.
+    pub max_frame_size: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_key_size: 64 * 1024,
+            max_value_size: 32 * 1024 * 1024,
+            max_frame_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    pub fn check_key(&self, key: &str) -> Result<(), LimitError> {
+        if key.len() > self.max_key_size {
+            return Err(LimitError::Key {
+                size: key.len(),
+                limit: self.max_key_size,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_entry(&self, key: &str, value: &str) -> Result<(), LimitError> {
+        self.check_key(key)?;
+        if value.len() > self.max_value_size {
+            return Err(LimitError::Value {
+                size: value.len(),
+                limit: self.max_value_size,
+            });
+        }
+        Ok(())
+    }
+}
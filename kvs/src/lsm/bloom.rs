@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+
+const BITS_PER_KEY: usize = 10;
+const HASHES: u32 = 7;
+
+/// FNV-1a. Filters are persisted, so the hash must not change between builds.
+pub fn hash(key: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key.as_bytes() {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Bloom filter over the keys of an SSTable.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Bloom {
+    hashes: u32,
+    bits: Vec<u64>,
+}
+
+impl Bloom {
+    /// Build a filter from key hashes computed with [`hash`].
+    pub fn from_hashes(key_hashes: &[u64]) -> Self {
+        let len = (key_hashes.len() * BITS_PER_KEY).max(64);
+        let mut bloom = Self {
+            hashes: HASHES,
+            bits: vec![0; len.div_ceil(64)],
+        };
+        for &h in key_hashes {
+            for bit in bloom.bit_positions(h) {
+                bloom.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        bloom
+    }
+
+    /// Returns false when the key is definitely absent.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bit_positions(hash(key))
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Double hashing, derive every probe from the two halves of one hash.
+    fn bit_positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 64;
+        let h1 = hash & 0xffff_ffff;
+        let h2 = hash >> 32;
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_false_negatives() {
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{}", i)).collect();
+        let hashes: Vec<u64> = keys.iter().map(|k| hash(k)).collect();
+        let bloom = Bloom::from_hashes(&hashes);
+        assert!(keys.iter().all(|k| bloom.may_contain(k)));
+
+        let false_positives = (1000..2000)
+            .filter(|i| bloom.may_contain(&format!("key{}", i)))
+            .count();
+        assert!(false_positives < 50, "{} false positives", false_positives);
+    }
+}
@@ -0,0 +1,77 @@
+use super::{sstable::Entry, LsmStoreError};
+use std::iter::Peekable;
+
+pub type Source<'a> = Box<dyn Iterator<Item = Result<Entry, LsmStoreError>> + 'a>;
+
+/// Merges sorted sources into one sorted stream.
+///
+/// Sources are ordered newest first. When several of them hold the same key, the entry of the
+/// newest one wins and the others are skipped.
+pub struct Merge<'a> {
+    sources: Vec<Peekable<Source<'a>>>,
+}
+
+impl<'a> Merge<'a> {
+    pub fn new(sources: Vec<Source<'a>>) -> Self {
+        Self {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = Result<Entry, LsmStoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut min: Option<(usize, String)> = None;
+        for i in 0..self.sources.len() {
+            match self.sources[i].peek() {
+                None => {}
+                Some(Err(_)) => return self.sources[i].next(),
+                Some(Ok((key, _))) if min.as_ref().is_none_or(|(_, min_key)| key < min_key) => {
+                    min = Some((i, key.clone()));
+                }
+                Some(Ok(_)) => {}
+            }
+        }
+
+        let (i, key) = min?;
+        let entry = self.sources[i].next();
+        for source in &mut self.sources[i + 1..] {
+            if matches!(source.peek(), Some(Ok((k, _))) if *k == key) {
+                source.next();
+            }
+        }
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(entries: &[(&str, Option<&str>)]) -> Source<'static> {
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|(k, v)| Ok((k.to_string(), v.map(str::to_owned))))
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    #[test]
+    fn test_newest_wins() {
+        let newest = source(&[("a", Some("new")), ("c", None)]);
+        let oldest = source(&[("a", Some("old")), ("b", Some("b")), ("c", Some("c"))]);
+        let merged: Vec<_> = Merge::new(vec![newest, oldest])
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            merged,
+            vec![
+                ("a".to_owned(), Some("new".to_owned())),
+                ("b".to_owned(), Some("b".to_owned())),
+                ("c".to_owned(), None),
+            ]
+        );
+    }
+}
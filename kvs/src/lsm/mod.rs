@@ -0,0 +1,434 @@
+//! Log-structured merge tree engine.
+//!
+//! Writes go to a write-ahead log and a sorted in-memory memtable. Once the log grows past its
+//! threshold the memtable is flushed into an immutable SSTable and the log is emptied. Reads look
+//! at the memtable first, then at the SSTables from newest to oldest.
+//!
+//! SSTables are compacted size-tiered: whenever enough tables of similar size pile up they're
+//! merged into one, which then belongs to the next tier. Tombstones are dropped once they're
+//! merged into the oldest table, since there's nothing left below them to shadow.
+//!
+//! Only the memtable, block indexes and bloom filters are kept in memory, so the key set doesn't
+//! have to fit in RAM.
+
+mod bloom;
+mod merge;
+mod sstable;
+mod wal;
+
+use crate::engine::{prefix_range, Entries};
+use crate::limits::{LimitError, Limits};
+use crate::KvsEngine;
+use crate::KvsEngineError;
+use merge::{Merge, Source};
+use sstable::{SsTable, SsTableWriter};
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use wal::Wal;
+
+const WAL_FILE_NAME: &str = "wal.lsm";
+const MANIFEST_FILE_NAME: &str = "manifest.lsm";
+
+/// Memtable is flushed once its write-ahead log grows past this many bytes.
+const WAL_SIZE_LIMIT: u64 = 1024 * 1024;
+
+/// Number of tables in a tier that triggers its compaction.
+const TIER_WIDTH: usize = 4;
+
+/// Tables are in the same tier when the biggest is at most this many times the smallest.
+const TIER_RATIO: u64 = 4;
+
+#[derive(Error, Debug)]
+pub enum LsmStoreError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Serialization(#[from] bincode::Error),
+
+    #[error("Path is not a directory")]
+    InvalidPath,
+
+    #[error("Key does not exists")]
+    KeyNotFound { key: String },
+
+    #[error("SSTable `{path}` is corrupted")]
+    Corrupted { path: PathBuf },
+
+    #[error(transparent)]
+    TooLarge(#[from] LimitError),
+}
+
+#[derive(Debug)]
+pub struct LsmStore {
+    directory: PathBuf,
+    wal: Wal,
+    wal_size: u64,
+    memtable: BTreeMap<String, Option<String>>,
+    /// Ordered oldest first.
+    tables: Vec<SsTable>,
+    next_table_id: u64,
+    limits: Limits,
+}
+
+impl LsmStore {
+    pub fn open(dir_path: impl Into<PathBuf>) -> Result<Self, LsmStoreError> {
+        Self::open_with_limits(dir_path, Limits::default())
+    }
+
+    /// Open the store, rejecting keys and values larger than `limits` allow on
+    /// [`set`](Self::set).
+    ///
+    /// The write-ahead log is replayed with the same limits, so a record claiming to be larger is
+    /// reported as corrupted instead of being allocated.
+    pub fn open_with_limits(
+        dir_path: impl Into<PathBuf>,
+        limits: Limits,
+    ) -> Result<Self, LsmStoreError> {
+        let directory: PathBuf = dir_path.into();
+
+        if !directory.is_dir() {
+            return Err(LsmStoreError::InvalidPath);
+        }
+
+        let names = read_manifest(&directory)?;
+        let mut tables = Vec::with_capacity(names.len());
+        for name in &names {
+            tables.push(SsTable::open(&directory.join(name))?);
+        }
+        remove_orphans(&directory, &names)?;
+
+        let next_table_id = names
+            .iter()
+            .filter_map(|name| name.trim_end_matches(".sst").parse::<u64>().ok())
+            .max()
+            .map_or(0, |id| id + 1);
+
+        let (wal, memtable) = Wal::open(&directory.join(WAL_FILE_NAME), &limits)?;
+        let wal_size = fs::metadata(wal.path())?.len();
+
+        Ok(LsmStore {
+            directory,
+            wal,
+            wal_size,
+            memtable,
+            tables,
+            next_table_id,
+            limits,
+        })
+    }
+
+    /// Set value for a key.
+    ///
+    /// If the key already exists, it will replace the value.
+    pub fn set(&mut self, key: String, value: String) -> Result<(), LsmStoreError> {
+        self.limits.check_entry(&key, &value)?;
+        self.wal_size += self.wal.put(&key, &value)?;
+        self.memtable.insert(key, Some(value));
+        self.maybe_flush()
+    }
+
+    /// Get value of a key.
+    ///
+    /// Returns None when entry doesn't exist.
+    pub fn get(&self, key: String) -> Result<Option<String>, LsmStoreError> {
+        if let Some(value) = self.memtable.get(&key) {
+            return Ok(value.clone());
+        }
+        for table in self.tables.iter().rev() {
+            if let Some(value) = table.get(&key)? {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Remove entry.
+    pub fn remove(&mut self, key: String) -> Result<(), LsmStoreError> {
+        if (self as &LsmStore).get(key.clone())?.is_none() {
+            return Err(LsmStoreError::KeyNotFound { key });
+        }
+        self.wal_size += self.wal.delete(&key)?;
+        self.memtable.insert(key, None);
+        self.maybe_flush()
+    }
+
+    /// Iterate entries whose key starts with `prefix`, ordered by key.
+    ///
+    /// Merges the memtable with every SSTable as the iterator advances, reading one block of each
+    /// table at a time.
+    pub fn scan<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> impl Iterator<Item = Result<(String, String), LsmStoreError>> + 'a {
+        self.scan_range(prefix, None)
+    }
+
+    /// Like [`scan`](Self::scan), starting right after the key `after`.
+    pub fn scan_after<'a>(
+        &'a self,
+        prefix: &str,
+        after: &str,
+    ) -> impl Iterator<Item = Result<(String, String), LsmStoreError>> + 'a {
+        self.scan_range(prefix, Some(after))
+    }
+
+    fn scan_range<'a>(
+        &'a self,
+        prefix: &str,
+        after: Option<&str>,
+    ) -> impl Iterator<Item = Result<(String, String), LsmStoreError>> + 'a {
+        let memtable = prefix_range(&self.memtable, prefix, after)
+            .map(|(key, value)| Ok((key.clone(), value.clone())));
+
+        let mut sources: Vec<Source<'a>> = vec![Box::new(memtable)];
+        for table in self.tables.iter().rev() {
+            sources.push(Box::new(table.scan(prefix, after)));
+        }
+        Merge::new(sources).filter_map(|entry| match entry {
+            Ok((key, Some(value))) => Some(Ok((key, value))),
+            Ok((_, None)) => None,
+            Err(err) => Some(Err(err)),
+        })
+    }
+
+    fn maybe_flush(&mut self) -> Result<(), LsmStoreError> {
+        if self.wal_size > WAL_SIZE_LIMIT {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write the memtable into a new SSTable and empty the write-ahead log.
+    pub fn flush(&mut self) -> Result<(), LsmStoreError> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+
+        let mut writer = SsTableWriter::create(&self.new_table_path())?;
+        for (key, value) in &self.memtable {
+            writer.add(key, value.as_deref())?;
+        }
+        self.tables.push(writer.finish()?);
+        self.write_manifest()?;
+
+        self.wal.clear()?;
+        self.wal_size = 0;
+        self.memtable.clear();
+
+        while let Some(range) = self.pick_tier() {
+            self.compact(range)?;
+        }
+        Ok(())
+    }
+
+    /// Find the newest run of tables that forms a full tier.
+    fn pick_tier(&self) -> Option<Range<usize>> {
+        let sizes: Vec<u64> = self.tables.iter().map(SsTable::size).collect();
+        (TIER_WIDTH..=sizes.len()).rev().find_map(|end| {
+            let start = end - TIER_WIDTH;
+            let tier = &sizes[start..end];
+            let min = tier.iter().min().copied().unwrap_or(0);
+            let max = tier.iter().max().copied().unwrap_or(0);
+            if max <= min.saturating_mul(TIER_RATIO) {
+                Some(start..end)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Merge a run of tables into one that takes their place.
+    fn compact(&mut self, range: Range<usize>) -> Result<(), LsmStoreError> {
+        let drop_tombstones = range.start == 0;
+        let path = self.new_table_path();
+        let sources: Vec<Source<'_>> = self.tables[range.clone()]
+            .iter()
+            .rev()
+            .map(|table| Box::new(table.scan("", None)) as Source<'_>)
+            .collect();
+
+        let mut writer = SsTableWriter::create(&path)?;
+        for entry in Merge::new(sources) {
+            let (key, value) = entry?;
+            if value.is_none() && drop_tombstones {
+                continue;
+            }
+            writer.add(&key, value.as_deref())?;
+        }
+        let table = writer.finish()?;
+
+        let merged: Vec<SsTable> = self.tables.splice(range, Some(table)).collect();
+        self.write_manifest()?;
+        for table in merged {
+            fs::remove_file(table.path())?;
+        }
+        Ok(())
+    }
+
+    fn new_table_path(&mut self) -> PathBuf {
+        let id = self.next_table_id;
+        self.next_table_id += 1;
+        self.directory.join(format!("{:016}.sst", id))
+    }
+
+    /// Record the live tables, oldest first.
+    ///
+    /// Tables only become part of the store once they're in the manifest, so a crash halfway
+    /// through a flush or compaction leaves the previous state intact.
+    fn write_manifest(&self) -> Result<(), LsmStoreError> {
+        let path = self.directory.join(MANIFEST_FILE_NAME);
+        let tmp_path = path.with_extension("lsm.tmp");
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        for table in &self.tables {
+            if let Some(name) = table.path().file_name() {
+                writeln!(file, "{}", name.to_string_lossy())?;
+            }
+        }
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+fn read_manifest(dir: &Path) -> Result<Vec<String>, LsmStoreError> {
+    match fs::read_to_string(dir.join(MANIFEST_FILE_NAME)) {
+        Ok(content) => Ok(content.lines().map(str::to_owned).collect()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Delete tables left behind by an interrupted flush or compaction.
+fn remove_orphans(dir: &Path, live: &[String]) -> Result<(), LsmStoreError> {
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        let is_table = name.ends_with(".sst") || name.ends_with(".sst.tmp");
+        if is_table && !live.contains(&name) {
+            fs::remove_file(dir.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+impl KvsEngine for LsmStore {
+    fn set(&mut self, key: String, value: String) -> Result<(), KvsEngineError> {
+        Ok((self as &mut LsmStore).set(key, value)?)
+    }
+
+    fn get(&mut self, key: &str) -> Result<Option<String>, KvsEngineError> {
+        Ok((self as &LsmStore).get(key.to_owned())?)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), KvsEngineError> {
+        Ok((self as &mut LsmStore).remove(key.to_owned())?)
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Entries<'_>, KvsEngineError> {
+        let entries = (self as &LsmStore).scan(prefix).map(|result| Ok(result?));
+        Ok(Box::new(entries))
+    }
+
+    fn scan_after(&mut self, prefix: &str, after: &str) -> Result<Entries<'_>, KvsEngineError> {
+        let entries = (self as &LsmStore)
+            .scan_after(prefix, after)
+            .map(|result| Ok(result?));
+        Ok(Box::new(entries))
+    }
+
+    fn flush(&mut self) -> Result<(), KvsEngineError> {
+        Ok((self as &mut LsmStore).flush()?)
+    }
+}
+
+impl From<LsmStoreError> for KvsEngineError {
+    fn from(value: LsmStoreError) -> Self {
+        match value {
+            LsmStoreError::KeyNotFound { key } => KvsEngineError::EntryNotFound { key },
+            LsmStoreError::TooLarge(err) => KvsEngineError::TooLarge(err),
+            _ => KvsEngineError::Other(Box::new(value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lsmstore_impls() {
+        static_assertions::assert_impl_all!(LsmStore: Send, Sync);
+    }
+
+    #[test]
+    fn test_reads_across_tables_and_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = LsmStore::open(dir.path()).unwrap();
+
+        for round in 0..(TIER_WIDTH * 2) {
+            for i in 0..100 {
+                store
+                    .set(format!("key{:03}", i), format!("{}", round))
+                    .unwrap();
+            }
+            store.remove(format!("key{:03}", round)).unwrap();
+            store.flush().unwrap();
+        }
+        assert!(store.tables.len() < TIER_WIDTH);
+
+        store
+            .set("key050".to_owned(), "memtable".to_owned())
+            .unwrap();
+        drop(store);
+        let store = LsmStore::open(dir.path()).unwrap();
+
+        let last = format!("{}", TIER_WIDTH * 2 - 1);
+        assert_eq!(store.get("key007".to_owned()).unwrap(), None);
+        assert_eq!(store.get("key006".to_owned()).unwrap(), Some(last.clone()));
+        assert_eq!(
+            store.get("key050".to_owned()).unwrap(),
+            Some("memtable".to_owned())
+        );
+
+        let entries: Vec<_> = store.scan("key05").collect::<Result<_, _>>().unwrap();
+        assert_eq!(entries.len(), 10);
+        assert_eq!(entries[0], ("key050".to_owned(), "memtable".to_owned()));
+        assert_eq!(entries[9], ("key059".to_owned(), last));
+        assert_eq!(store.scan("").count(), 100 - 1);
+    }
+
+    #[test]
+    fn test_set_over_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let limits = Limits {
+            max_key_size: 8,
+            max_value_size: 16,
+            ..Limits::default()
+        };
+        let mut store = LsmStore::open_with_limits(dir.path(), limits).unwrap();
+
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        assert!(matches!(
+            store.set("k".repeat(9), "value".to_owned()),
+            Err(LsmStoreError::TooLarge(LimitError::Key { .. }))
+        ));
+        assert!(matches!(
+            store.set("key1".to_owned(), "v".repeat(17)),
+            Err(LsmStoreError::TooLarge(LimitError::Value { .. }))
+        ));
+        assert_eq!(
+            store.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+    }
+}
@@ -0,0 +1,377 @@
+//! Sorted string tables.
+//!
+//! An SSTable is an immutable file of entries sorted by key, laid out as:
+//!
+//! ```text
+//! [block]... [block index] [bloom filter] [footer]
+//! ```
+//!
+//! Blocks hold bincode encoded `(key, value)` pairs, where a `None` value is a tombstone. The block
+//! index maps the first key of every block to its location. Both the index and the bloom filter
+//! are loaded on open, data blocks are read on demand.
+
+use super::{
+    bloom::{self, Bloom},
+    LsmStoreError,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    vec,
+};
+
+/// Blocks are cut once they grow past this many bytes.
+const BLOCK_SIZE: usize = 4 * 1024;
+
+const MAGIC: u64 = 0x6b76_735f_6c73_6d31; // "kvs_lsm1"
+const FOOTER_SIZE: u64 = 5 * 8;
+
+pub type Entry = (String, Option<String>);
+
+#[derive(Deserialize, Serialize, Debug)]
+struct BlockHandle {
+    first_key: String,
+    offset: u64,
+    len: u64,
+}
+
+/// Writes entries, in ascending key order, into a new SSTable.
+pub struct SsTableWriter {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    writer: BufWriter<File>,
+    offset: u64,
+    block: Vec<u8>,
+    block_first_key: Option<String>,
+    index: Vec<BlockHandle>,
+    key_hashes: Vec<u64>,
+}
+
+impl SsTableWriter {
+    pub fn create(path: &Path) -> Result<Self, LsmStoreError> {
+        let tmp_path = path.with_extension("sst.tmp");
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        Ok(Self {
+            path: path.to_owned(),
+            tmp_path,
+            writer: BufWriter::new(file),
+            offset: 0,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            block_first_key: None,
+            index: Vec::new(),
+            key_hashes: Vec::new(),
+        })
+    }
+
+    pub fn add(&mut self, key: &str, value: Option<&str>) -> Result<(), LsmStoreError> {
+        if self.block_first_key.is_none() {
+            self.block_first_key = Some(key.to_owned());
+        }
+        bincode::serialize_into(&mut self.block, &(key, value))?;
+        self.key_hashes.push(bloom::hash(key));
+        if self.block.len() >= BLOCK_SIZE {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> Result<(), LsmStoreError> {
+        let first_key = match self.block_first_key.take() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        self.writer.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            first_key,
+            offset: self.offset,
+            len: self.block.len() as u64,
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Write the index, filter and footer, then move the table into place.
+    pub fn finish(mut self) -> Result<SsTable, LsmStoreError> {
+        self.flush_block()?;
+
+        let index = bincode::serialize(&self.index)?;
+        let bloom = bincode::serialize(&Bloom::from_hashes(&self.key_hashes))?;
+        let index_offset = self.offset;
+        let bloom_offset = index_offset + index.len() as u64;
+        self.writer.write_all(&index)?;
+        self.writer.write_all(&bloom)?;
+        for x in &[
+            index_offset,
+            index.len() as u64,
+            bloom_offset,
+            bloom.len() as u64,
+            MAGIC,
+        ] {
+            self.writer.write_all(&x.to_le_bytes())?;
+        }
+
+        let file = self.writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&self.tmp_path, &self.path)?;
+        SsTable::open(&self.path)
+    }
+}
+
+/// Whether `len` bytes at `offset` end no later than `end`.
+fn fits(offset: u64, len: u64, end: u64) -> bool {
+    matches!(offset.checked_add(len), Some(x) if x <= end)
+}
+
+/// Read handle of an SSTable.
+#[derive(Debug)]
+pub struct SsTable {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    index: Vec<BlockHandle>,
+    bloom: Bloom,
+}
+
+impl SsTable {
+    pub fn open(path: &Path) -> Result<Self, LsmStoreError> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let corrupted = || LsmStoreError::Corrupted {
+            path: path.to_owned(),
+        };
+        if size < FOOTER_SIZE {
+            return Err(corrupted());
+        }
+
+        let mut footer = [0; FOOTER_SIZE as usize];
+        file.read_exact_at(&mut footer, size - FOOTER_SIZE)?;
+        let mut fields = footer
+            .chunks(8)
+            .map(|x| u64::from_le_bytes([x[0], x[1], x[2], x[3], x[4], x[5], x[6], x[7]]));
+        let mut next = || fields.next().unwrap();
+        let (index_offset, index_len, bloom_offset, bloom_len, magic) =
+            (next(), next(), next(), next(), next());
+        // Checked before allocating, a corrupted length mustn't make us reserve gigabytes.
+        let end = size - FOOTER_SIZE;
+        if magic != MAGIC
+            || !fits(index_offset, index_len, bloom_offset)
+            || !fits(bloom_offset, bloom_len, end)
+        {
+            return Err(corrupted());
+        }
+
+        let index: Vec<BlockHandle> =
+            bincode::deserialize(&read_at(&file, index_offset, index_len)?)?;
+        if !index.iter().all(|x| fits(x.offset, x.len, index_offset)) {
+            return Err(corrupted());
+        }
+        let bloom = bincode::deserialize(&read_at(&file, bloom_offset, bloom_len)?)?;
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+            size,
+            index,
+            bloom,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Size of the table file in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Look up a key.
+    ///
+    /// Returns `None` when the table knows nothing about the key, `Some(None)` when it holds a
+    /// tombstone for it.
+    pub fn get(&self, key: &str) -> Result<Option<Option<String>>, LsmStoreError> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let block = match self.block_for(key) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        for entry in self.read_block(block)? {
+            let (k, v) = entry;
+            if k == key {
+                return Ok(Some(v));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Index of the only block that may hold `key`.
+    fn block_for(&self, key: &str) -> Option<usize> {
+        let n = self
+            .index
+            .partition_point(|handle| handle.first_key.as_str() <= key);
+        n.checked_sub(1)
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<Entry>, LsmStoreError> {
+        let handle = &self.index[block];
+        let bytes = read_at(&self.file, handle.offset, handle.len)?;
+        let mut slice = bytes.as_slice();
+        let mut entries = Vec::new();
+        while !slice.is_empty() {
+            entries.push(bincode::deserialize_from(&mut slice)?);
+        }
+        Ok(entries)
+    }
+
+    /// Iterate entries, tombstones included, whose key starts with `prefix` and is greater than
+    /// `after`.
+    ///
+    /// Reads one block at a time, starting at the block of the first key wanted.
+    pub fn scan<'a>(&'a self, prefix: &str, after: Option<&str>) -> Scan<'a> {
+        let after = after.filter(|after| *after >= prefix);
+        Scan {
+            table: self,
+            prefix: prefix.to_owned(),
+            after: after.map(str::to_owned),
+            next_block: self.block_for(after.unwrap_or(prefix)).unwrap_or(0),
+            entries: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+}
+
+fn read_at(file: &File, offset: u64, len: u64) -> Result<Vec<u8>, LsmStoreError> {
+    let mut buf = vec![0; len as usize];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf)
+}
+
+pub struct Scan<'a> {
+    table: &'a SsTable,
+    prefix: String,
+    after: Option<String>,
+    next_block: usize,
+    entries: vec::IntoIter<Entry>,
+    done: bool,
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<Entry, LsmStoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if let Some((key, value)) = self.entries.next() {
+                if self.after.as_ref().is_some_and(|after| key <= *after) {
+                    continue;
+                }
+                if key.starts_with(&self.prefix) {
+                    return Some(Ok((key, value)));
+                }
+                if key.as_str() > self.prefix.as_str() {
+                    self.done = true;
+                }
+                continue;
+            }
+            if self.next_block >= self.table.index.len() {
+                self.done = true;
+                break;
+            }
+            match self.table.read_block(self.next_block) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+            self.next_block += 1;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.sst");
+        let mut writer = SsTableWriter::create(&path).unwrap();
+        for i in 0..2000 {
+            let key = format!("key{:05}", i);
+            let value = if i % 10 == 0 {
+                None
+            } else {
+                Some(format!("value{}", i))
+            };
+            writer.add(&key, value.as_deref()).unwrap();
+        }
+        let table = writer.finish().unwrap();
+        assert!(table.index.len() > 1);
+
+        assert_eq!(
+            table.get("key00001").unwrap(),
+            Some(Some("value1".to_owned()))
+        );
+        assert_eq!(
+            table.get("key01999").unwrap(),
+            Some(Some("value1999".to_owned()))
+        );
+        assert_eq!(table.get("key00010").unwrap(), Some(None));
+        assert_eq!(table.get("key02000").unwrap(), None);
+        assert_eq!(table.get("a").unwrap(), None);
+
+        let keys: Vec<String> = table
+            .scan("key015", None)
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(keys.len(), 100);
+        assert_eq!(keys.first().unwrap(), "key01500");
+        assert_eq!(keys.last().unwrap(), "key01599");
+
+        let keys: Vec<String> = table
+            .scan("key01", Some("key01949"))
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(keys.len(), 50);
+        assert_eq!(keys.first().unwrap(), "key01950");
+
+        let reopened = SsTable::open(&path).unwrap();
+        assert_eq!(reopened.scan("", None).count(), 2000);
+    }
+
+    #[test]
+    fn test_open_rejects_bad_footer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.sst");
+        let mut writer = SsTableWriter::create(&path).unwrap();
+        writer.add("key", Some("value")).unwrap();
+        writer.finish().unwrap();
+
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        let size = file.metadata().unwrap().len();
+        // Index length, then bloom offset.
+        for &field in &[1, 2] {
+            let original = fs::read(&path).unwrap();
+            file.write_all_at(&u64::MAX.to_le_bytes(), size - FOOTER_SIZE + field * 8)
+                .unwrap();
+            assert!(matches!(
+                SsTable::open(&path),
+                Err(LsmStoreError::Corrupted { .. })
+            ));
+            file.write_all_at(&original, 0).unwrap();
+        }
+        assert!(SsTable::open(&path).is_ok());
+    }
+}
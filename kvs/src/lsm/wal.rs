@@ -0,0 +1,171 @@
+use super::LsmStoreError;
+use crate::Limits;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+#[derive(Deserialize, Serialize, Debug)]
+enum Record {
+    Put { key: String, value: String },
+    Delete { key: String },
+}
+
+/// Encoded size of the enum tag and the two length prefixes of a record.
+const RECORD_OVERHEAD: u64 = 4 + 8 + 8;
+
+/// Write-ahead log of the memtable.
+///
+/// Every write lands here before it's applied to the memtable, so the memtable can be rebuilt
+/// after a crash. Emptied once the memtable is flushed into an SSTable.
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Wal {
+    /// Open the log at `path` and replay it into a memtable.
+    ///
+    /// A torn record at the tail is cut off, so appends continue right after the last good one.
+    /// Records larger than `limits` allow are taken for corruption, a garbage length prefix never
+    /// gets to ask for a huge allocation.
+    pub fn open(
+        path: &Path,
+        limits: &Limits,
+    ) -> Result<(Self, BTreeMap<String, Option<String>>), LsmStoreError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .append(true)
+            .open(path)?;
+
+        // Same encoding as `bincode::serialize`, which appends records.
+        let limit = (limits.max_key_size as u64)
+            .saturating_add(limits.max_value_size as u64)
+            .saturating_add(RECORD_OVERHEAD);
+        let options = || {
+            bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .allow_trailing_bytes()
+                .with_limit(limit)
+        };
+        let mut memtable = BTreeMap::new();
+        let mut reader = BufReader::new(&file);
+        // End of the last complete record.
+        let mut good = 0;
+        loop {
+            if reader.fill_buf()?.is_empty() {
+                break;
+            }
+            match options().deserialize_from(&mut reader) {
+                Ok(Record::Put { key, value }) => {
+                    memtable.insert(key, Some(value));
+                }
+                Ok(Record::Delete { key }) => {
+                    memtable.insert(key, None);
+                }
+                // A torn write at the tail, the write it belongs to was never acknowledged.
+                Err(err) if matches!(&*err, bincode::ErrorKind::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof) =>
+                {
+                    file.set_len(good)?;
+                    file.sync_data()?;
+                    break;
+                }
+                Err(err) => return Err(err.into()),
+            }
+            good = reader.stream_position()?;
+        }
+
+        let wal = Self {
+            path: path.to_owned(),
+            file,
+        };
+        Ok((wal, memtable))
+    }
+
+    /// Append a set, returns the number of bytes written.
+    pub fn put(&mut self, key: &str, value: &str) -> Result<u64, LsmStoreError> {
+        self.append(&Record::Put {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        })
+    }
+
+    /// Append a removal, returns the number of bytes written.
+    pub fn delete(&mut self, key: &str) -> Result<u64, LsmStoreError> {
+        self.append(&Record::Delete {
+            key: key.to_owned(),
+        })
+    }
+
+    fn append(&mut self, record: &Record) -> Result<u64, LsmStoreError> {
+        let bytes = bincode::serialize(record)?;
+        (&self.file).write_all(&bytes)?;
+        self.file.sync_data()?;
+        Ok(bytes.len() as u64)
+    }
+
+    /// Drop every record, called once they're safe in an SSTable.
+    pub fn clear(&mut self) -> Result<(), LsmStoreError> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_torn_tail_is_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.lsm");
+        let (mut wal, _) = Wal::open(&path, &Limits::default()).unwrap();
+        let len = wal.put("key1", "value1").unwrap();
+        let mut torn = bincode::serialize(&Record::Put {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        })
+        .unwrap();
+        torn.truncate(torn.len() / 2);
+        (&wal.file).write_all(&torn).unwrap();
+        drop(wal);
+
+        let (mut wal, memtable) = Wal::open(&path, &Limits::default()).unwrap();
+        assert_eq!(memtable.len(), 1);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        wal.delete("key1").unwrap();
+        drop(wal);
+
+        let (_, memtable) = Wal::open(&path, &Limits::default()).unwrap();
+        assert_eq!(memtable.get("key1"), Some(&None));
+    }
+
+    #[test]
+    fn test_oversized_record_is_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.lsm");
+        // Put tag, then a key length prefix claiming an exabyte.
+        let mut record = 0u32.to_le_bytes().to_vec();
+        record.extend_from_slice(&(1u64 << 60).to_le_bytes());
+        fs::write(&path, &record).unwrap();
+
+        let err = Wal::open(&path, &Limits::default()).err().unwrap();
+        assert!(matches!(
+            err,
+            LsmStoreError::Serialization(err) if matches!(*err, bincode::ErrorKind::SizeLimit)
+        ));
+    }
+}
@@ -0,0 +1,200 @@
+//! Engine that keeps every entry in memory.
+//!
+//! Nothing is written until the engine is dropped or [`MemoryEngine::save`] is called, and only
+//! when it was opened with a snapshot file. Without one it's a pure cache.
+
+use crate::{
+    engine::{prefix_range, BackupJob, Entries},
+    KvsEngine, KvsEngineError,
+};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// Name of the snapshot file written by [`KvsEngine::backup`].
+const BACKUP_FILE_NAME: &str = "memory.snapshot";
+
+#[derive(Error, Debug)]
+pub enum MemoryEngineError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Serialization(#[from] bincode::Error),
+}
+
+#[derive(Default, Debug)]
+pub struct MemoryEngine {
+    entries: BTreeMap<String, String>,
+    snapshot_path: Option<PathBuf>,
+}
+
+impl MemoryEngine {
+    /// Create an empty engine that is never persisted.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an engine backed by a snapshot file.
+    ///
+    /// Entries are loaded from the file if it exists, and written back to it on drop.
+    pub fn with_snapshot(path: impl Into<PathBuf>) -> Result<Self, MemoryEngineError> {
+        let path = path.into();
+        let entries = if path.exists() {
+            load(&path)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self {
+            entries,
+            snapshot_path: Some(path),
+        })
+    }
+
+    /// Write entries to the snapshot file. Does nothing if the engine has none.
+    pub fn save(&self) -> Result<(), MemoryEngineError> {
+        match &self.snapshot_path {
+            Some(path) => save(&self.entries, path),
+            None => Ok(()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+fn load(path: &Path) -> Result<BTreeMap<String, String>, MemoryEngineError> {
+    let reader = BufReader::new(File::open(path)?);
+    Ok(bincode::deserialize_from(reader)?)
+}
+
+/// Written to a temporary file first and renamed once synced, so a crash never leaves a partial
+/// snapshot behind.
+fn save(entries: &BTreeMap<String, String>, path: &Path) -> Result<(), MemoryEngineError> {
+    let tmp_path = path.with_extension("tmp");
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    let mut writer = BufWriter::new(file);
+    bincode::serialize_into(&mut writer, entries)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+impl Drop for MemoryEngine {
+    fn drop(&mut self) {
+        // Nowhere to report the error to, callers that care should call `save` themselves.
+        let _ = self.save();
+    }
+}
+
+impl KvsEngine for MemoryEngine {
+    fn set(&mut self, key: String, value: String) -> Result<(), KvsEngineError> {
+        self.entries.insert(key, value);
+        Ok(())
+    }
+
+    fn get(&mut self, key: &str) -> Result<Option<String>, KvsEngineError> {
+        Ok(self.entries.get(key).cloned())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), KvsEngineError> {
+        match self.entries.remove(key) {
+            Some(_) => Ok(()),
+            None => Err(KvsEngineError::EntryNotFound {
+                key: key.to_owned(),
+            }),
+        }
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Entries<'_>, KvsEngineError> {
+        let entries = prefix_range(&self.entries, prefix, None)
+            .map(|(key, value)| Ok((key.clone(), value.clone())));
+        Ok(Box::new(entries))
+    }
+
+    fn scan_after(&mut self, prefix: &str, after: &str) -> Result<Entries<'_>, KvsEngineError> {
+        let entries = prefix_range(&self.entries, prefix, Some(after))
+            .map(|(key, value)| Ok((key.clone(), value.clone())));
+        Ok(Box::new(entries))
+    }
+
+    fn backup(&mut self, dir: &Path) -> Result<(), KvsEngineError> {
+        fs::create_dir_all(dir).map_err(MemoryEngineError::from)?;
+        Ok(save(&self.entries, &dir.join(BACKUP_FILE_NAME))?)
+    }
+
+    fn start_backup(&mut self, dir: &Path) -> Result<BackupJob, KvsEngineError> {
+        let entries = self.entries.clone();
+        let dir = dir.to_owned();
+        Ok(Box::new(move || {
+            fs::create_dir_all(&dir).map_err(MemoryEngineError::from)?;
+            Ok(save(&entries, &dir.join(BACKUP_FILE_NAME))?)
+        }))
+    }
+
+    fn restore(&mut self, dir: &Path) -> Result<(), KvsEngineError> {
+        self.entries = load(&dir.join(BACKUP_FILE_NAME))?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), KvsEngineError> {
+        Ok(self.save()?)
+    }
+}
+
+impl From<MemoryEngineError> for KvsEngineError {
+    fn from(value: MemoryEngineError) -> Self {
+        KvsEngineError::Other(Box::new(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_survives_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot");
+
+        let mut engine = MemoryEngine::with_snapshot(&path).unwrap();
+        engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        engine.set("key2".to_owned(), "value2".to_owned()).unwrap();
+        engine.remove("key2").unwrap();
+        drop(engine);
+
+        let mut engine = MemoryEngine::with_snapshot(&path).unwrap();
+        assert_eq!(engine.get("key1").unwrap(), Some("value1".to_owned()));
+        assert_eq!(engine.get("key2").unwrap(), None);
+        assert!(engine.remove("key2").is_err());
+    }
+
+    #[test]
+    fn test_scan_is_ordered() {
+        let mut engine = MemoryEngine::new();
+        for key in &["b2", "a1", "b1", "c1"] {
+            engine.set(key.to_string(), key.to_string()).unwrap();
+        }
+        let keys: Vec<String> = engine.scan("b").unwrap().map(|x| x.unwrap().0).collect();
+        assert_eq!(keys, vec!["b1".to_owned(), "b2".to_owned()]);
+    }
+
+    #[test]
+    fn test_memory_engine_impls() {
+        static_assertions::assert_impl_all!(MemoryEngine: Send, Sync);
+    }
+}
@@ -0,0 +1,70 @@
+use serde::{Deserialize, Serialize};
+
+/// What a user may do with keys.
+#[derive(Eq, PartialEq, Deserialize, Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Read,
+    Write,
+    /// Read and write, plus admin requests when granted on every key.
+    Admin,
+}
+
+/// Access granted on the keys starting with a prefix.
+#[derive(Eq, PartialEq, Deserialize, Serialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Grant {
+    pub prefix: String,
+    pub access: Vec<Access>,
+}
+
+impl Grant {
+    /// Grant of every access on every key.
+    pub fn all() -> Self {
+        Self {
+            prefix: String::new(),
+            access: vec![Access::Admin],
+        }
+    }
+
+    /// Grant of reads and writes on every key, but no admin requests.
+    pub fn read_write() -> Self {
+        Self {
+            prefix: String::new(),
+            access: vec![Access::Read, Access::Write],
+        }
+    }
+
+    /// Whether the grant gives `access` on every key starting with `prefix`, a single key being
+    /// a prefix too.
+    pub fn allows(&self, prefix: &str, access: Access) -> bool {
+        prefix.starts_with(&self.prefix)
+            && self
+                .access
+                .iter()
+                .any(|x| *x == access || *x == Access::Admin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        let grant = Grant {
+            prefix: "app1/".to_owned(),
+            access: vec![Access::Read],
+        };
+        assert!(grant.allows("app1/key", Access::Read));
+        assert!(grant.allows("app1/", Access::Read));
+        assert!(!grant.allows("app1", Access::Read));
+        assert!(!grant.allows("app2/key", Access::Read));
+        assert!(!grant.allows("app1/key", Access::Write));
+
+        assert!(Grant::all().allows("", Access::Admin));
+        assert!(Grant::all().allows("app1/key", Access::Write));
+        assert!(Grant::read_write().allows("app1/key", Access::Write));
+        assert!(!Grant::read_write().allows("", Access::Admin));
+    }
+}
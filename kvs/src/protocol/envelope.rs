@@ -0,0 +1,142 @@
+use super::SerializationError;
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("body of message {id} is larger than the limit of {limit} bytes")]
+    TooLarge { id: u64, limit: u64 },
+    #[error(transparent)]
+    Invalid(#[from] SerializationError),
+}
+
+/// Request or response tagged with an ID.
+///
+/// Clients pick the ID of every request and the server echoes it in the response, so responses
+/// can come back in any order.
+#[derive(Eq, PartialEq, Deserialize, Serialize, Clone, Debug)]
+pub struct Envelope<T> {
+    pub id: u64,
+    pub body: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(id: u64, body: T) -> Self {
+        Self { id, body }
+    }
+}
+
+impl<T: DeserializeOwned> Envelope<T> {
+    /// Read an envelope whose body takes at most `limit` bytes encoded.
+    ///
+    /// A body claiming to be larger fails before anything is allocated for it. The ID is read
+    /// by then, so the failure can still be answered. Returns `None` when the stream ends before
+    /// an envelope starts, a stream ending inside one is invalid.
+    pub fn from_reader_with_limit(
+        reader: &mut impl io::Read,
+        limit: u64,
+    ) -> Result<Option<Self>, FrameError> {
+        let mut id = [0; 8];
+        let mut read = 0;
+        while read < id.len() {
+            match reader.read(&mut id[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err(truncated()),
+                Ok(n) => read += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(SerializationError::from(err).into()),
+            }
+        }
+        let id = u64::from_le_bytes(id);
+        // Same encoding as `bincode::deserialize_from`, plus the limit.
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(limit);
+        match options.deserialize_from(reader) {
+            Ok(body) => Ok(Some(Self { id, body })),
+            Err(err) => match *err {
+                bincode::ErrorKind::SizeLimit => Err(FrameError::TooLarge { id, limit }),
+                bincode::ErrorKind::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    Err(truncated())
+                }
+                _ => Err(SerializationError::from(err).into()),
+            },
+        }
+    }
+}
+
+/// Failure of a stream ending in the middle of an envelope.
+fn truncated() -> FrameError {
+    let err = io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "stream ended inside a message",
+    );
+    SerializationError::from(err).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Request, Serialization};
+    use quickcheck_macros::quickcheck;
+    use std::io::Cursor;
+
+    #[quickcheck]
+    fn prop_ser_de_is_identical(id: u64, request: Request) -> bool {
+        let envelope = Envelope::new(id, request);
+        let mut buf = Cursor::new(Vec::new());
+        envelope.to_writer(&mut buf).unwrap();
+
+        buf.set_position(0);
+        envelope == Envelope::from_reader(&mut buf).unwrap().unwrap()
+    }
+
+    #[quickcheck]
+    fn prop_limited_de_is_identical(id: u64, request: Request) -> bool {
+        let envelope = Envelope::new(id, request);
+        let mut buf = Cursor::new(Vec::new());
+        envelope.to_writer(&mut buf).unwrap();
+
+        buf.set_position(0);
+        envelope
+            == Envelope::from_reader_with_limit(&mut buf, u64::MAX)
+                .unwrap()
+                .unwrap()
+    }
+
+    #[test]
+    fn test_limit_rejects_huge_length() {
+        // Set with a key claiming to be 1 TiB long.
+        let mut buf = Vec::new();
+        7u64.to_writer(&mut buf).unwrap();
+        0u32.to_writer(&mut buf).unwrap();
+        (1u64 << 40).to_writer(&mut buf).unwrap();
+
+        let result = Envelope::<Request>::from_reader_with_limit(&mut Cursor::new(buf), 1024);
+        assert!(matches!(
+            result,
+            Err(FrameError::TooLarge { id: 7, limit: 1024 })
+        ));
+    }
+
+    #[test]
+    fn test_limit_truncated_frame_is_invalid() {
+        let mut buf = Vec::new();
+        Envelope::new(7, Request::Ping).to_writer(&mut buf).unwrap();
+
+        let result = Envelope::<Request>::from_reader_with_limit(&mut Cursor::new(&[][..]), 1024);
+        assert!(matches!(result, Ok(None)));
+        for len in &[3, 8, buf.len() - 1] {
+            let result =
+                Envelope::<Request>::from_reader_with_limit(&mut Cursor::new(&buf[..*len]), 1024);
+            assert!(
+                matches!(result, Err(FrameError::Invalid(_))),
+                "{} bytes",
+                len
+            );
+        }
+    }
+}
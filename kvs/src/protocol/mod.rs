@@ -1,7 +1,11 @@
+mod acl;
+mod envelope;
 mod format;
 mod request;
 mod response;
 
+pub use acl::{Access, Grant};
+pub use envelope::{Envelope, FrameError};
 pub use format::{Serialization, SerializationError};
-pub use request::Request;
-pub use response::Response;
+pub use request::{Request, Secret, MAX_SCAN_LIMIT};
+pub use response::{ErrorKind, Response};
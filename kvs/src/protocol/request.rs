@@ -1,10 +1,84 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Most entries a server returns for one `Scan` request, whatever its `limit`.
+pub const MAX_SCAN_LIMIT: u32 = 1000;
+
+/// Secret sent over the wire, kept out of logs.
+#[derive(Eq, PartialEq, Deserialize, Serialize, Clone)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
 
 #[derive(Eq, PartialEq, Deserialize, Serialize, Clone, Debug)]
 pub enum Request {
-    Set { key: String, value: String },
-    Get { key: String },
-    Rm { key: String },
+    Set {
+        key: String,
+        value: String,
+    },
+    Get {
+        key: String,
+    },
+    Rm {
+        key: String,
+    },
+    /// Page through entries whose key starts with `prefix`, in ascending key order. Returns at
+    /// most `limit` entries with key greater than `after`, and never more than
+    /// [`MAX_SCAN_LIMIT`].
+    Scan {
+        prefix: String,
+        after: Option<String>,
+        limit: u32,
+    },
+    /// Admin request to back the engine up into a directory under the backup root of the server.
+    /// Refused by servers without authentication or backup root.
+    Backup {
+        dir: String,
+    },
+    /// Admin request to replace the engine data with a backup under the backup root of the
+    /// server. Refused by servers without authentication or backup root.
+    Restore {
+        dir: String,
+    },
+    /// Health check, always succeeds.
+    Ping,
+    /// Authenticate the connection, required before any other request when the server has
+    /// authentication enabled.
+    Auth {
+        user: String,
+        token: Secret,
+    },
+    /// Effective permissions of `user`, or of the connection's user when `None`. Listing those of
+    /// another user is an admin request.
+    Permissions {
+        user: Option<String>,
+    },
+}
+
+impl Request {
+    /// Whether sending the request again can't change the outcome, so it's safe to retry after a
+    /// failure that leaves unknown whether the server handled it.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            Request::Get { .. }
+                | Request::Scan { .. }
+                | Request::Ping
+                | Request::Permissions { .. }
+        )
+    }
+
+    /// Whether the request changes what the engine stores.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Request::Set { .. } | Request::Rm { .. } | Request::Restore { .. }
+        )
+    }
 }
 
 #[cfg(test)]
@@ -16,7 +90,7 @@ mod tests {
 
     impl quickcheck::Arbitrary for Request {
         fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
-            match g.size() % 3 {
+            match g.size() % 9 {
                 0 => Request::Set {
                     key: String::arbitrary(g),
                     value: String::arbitrary(g),
@@ -24,9 +98,28 @@ mod tests {
                 1 => Request::Get {
                     key: String::arbitrary(g),
                 },
-                3 => Request::Rm {
+                2 => Request::Rm {
                     key: String::arbitrary(g),
                 },
+                3 => Request::Scan {
+                    prefix: String::arbitrary(g),
+                    after: Option::arbitrary(g),
+                    limit: u32::arbitrary(g),
+                },
+                4 => Request::Backup {
+                    dir: String::arbitrary(g),
+                },
+                5 => Request::Restore {
+                    dir: String::arbitrary(g),
+                },
+                6 => Request::Ping,
+                7 => Request::Auth {
+                    user: String::arbitrary(g),
+                    token: Secret(String::arbitrary(g)),
+                },
+                8 => Request::Permissions {
+                    user: Option::arbitrary(g),
+                },
                 _ => unimplemented!(),
             }
         }
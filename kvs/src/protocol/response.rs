@@ -1,11 +1,34 @@
 use std::fmt;
 
+use super::Grant;
 use serde::{Deserialize, Serialize};
 
+/// Why a request failed.
+#[derive(Eq, PartialEq, Deserialize, Serialize, Clone, Copy, Debug)]
+pub enum ErrorKind {
+    NotFound,
+    InvalidRequest,
+    Storage,
+    Busy,
+    Unauthorized,
+    TooLarge,
+}
+
 #[derive(Eq, PartialEq, Deserialize, Serialize, Clone, Debug)]
 pub enum Response {
     Success(Option<String>),
-    Failure(String),
+    Failure { kind: ErrorKind, message: String },
+    Entries(Vec<(String, String)>),
+    Permissions(Vec<Grant>),
+}
+
+impl Response {
+    pub fn failure(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Response::Failure {
+            kind,
+            message: message.into(),
+        }
+    }
 }
 
 impl fmt::Display for Response {
@@ -17,19 +40,41 @@ impl fmt::Display for Response {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::protocol::Serialization;
+    use crate::protocol::{Access, Serialization};
     use quickcheck_macros::quickcheck;
     use std::io::Cursor;
 
+    impl quickcheck::Arbitrary for ErrorKind {
+        fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+            match g.size() % 6 {
+                0 => ErrorKind::NotFound,
+                1 => ErrorKind::InvalidRequest,
+                2 => ErrorKind::Storage,
+                3 => ErrorKind::Busy,
+                4 => ErrorKind::Unauthorized,
+                5 => ErrorKind::TooLarge,
+                _ => unimplemented!(),
+            }
+        }
+    }
+
     impl quickcheck::Arbitrary for Response {
         fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
-            match g.size() % 2 {
-                0 => Response::Success(if g.size() % 2 == 0 {
+            match g.size() % 4 {
+                0 => Response::Success(if g.size().is_multiple_of(2) {
                     None
                 } else {
                     Some(String::arbitrary(g))
                 }),
-                1 => Response::Failure(String::arbitrary(g)),
+                1 => Response::failure(ErrorKind::arbitrary(g), String::arbitrary(g)),
+                2 => Response::Entries(Vec::arbitrary(g)),
+                3 => Response::Permissions(vec![
+                    Grant {
+                        prefix: String::arbitrary(g),
+                        access: vec![Access::Read, Access::Write],
+                    },
+                    Grant::all(),
+                ]),
                 _ => unimplemented!(),
             }
         }
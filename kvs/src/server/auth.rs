@@ -0,0 +1,239 @@
+use crate::protocol::{Access, Grant, Request};
+use serde::Deserialize;
+use slog::{info, warn, Logger};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AuthError {
+    #[error("failed to read auth config, caused by {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid auth config, caused by {0}")]
+    Parse(#[from] toml::de::Error),
+}
+
+/// Auth config file, in TOML:
+///
+/// ```toml
+/// [users.alice]
+/// token = "s3cret"
+/// permissions = [
+///     { prefix = "alice/", access = ["read", "write"] },
+///     { prefix = "shared/", access = ["read"] },
+/// ]
+///
+/// [users.bob]
+/// token = "hunter2"
+///
+/// [users.root]
+/// token = "t0ken"
+/// permissions = [{ prefix = "", access = ["admin"] }]
+/// ```
+///
+/// Users without `permissions` may read and write every key. Admin requests, like backups, need
+/// `admin` access on the empty prefix, which is only ever granted explicitly.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub users: BTreeMap<String, UserConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub token: String,
+    pub permissions: Option<Vec<Grant>>,
+}
+
+impl AuthConfig {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, AuthError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(config: &str) -> Result<Self, AuthError> {
+        Ok(toml::from_str(config)?)
+    }
+}
+
+struct User {
+    token: Vec<u8>,
+    grants: Vec<Grant>,
+}
+
+/// Checks the credentials connections authenticate with, and what their users may do.
+pub struct Authenticator {
+    users: HashMap<String, User>,
+    failed_attempts: AtomicU64,
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig) -> Self {
+        let users = config
+            .users
+            .iter()
+            .map(|(name, config)| {
+                let user = User {
+                    token: config.token.as_bytes().to_vec(),
+                    grants: config
+                        .permissions
+                        .clone()
+                        .unwrap_or_else(|| vec![Grant::read_write()]),
+                };
+                (name.clone(), user)
+            })
+            .collect();
+        Self {
+            users,
+            failed_attempts: AtomicU64::new(0),
+        }
+    }
+
+    /// Effective permissions of `user`, none for unknown users.
+    pub(crate) fn grants(&self, user: &str) -> &[Grant] {
+        self.users.get(user).map_or(&[], |x| x.grants.as_slice())
+    }
+
+    /// Whether `user` may make `request`. Denials are logged for auditing.
+    pub(crate) fn authorize(&self, log: &Logger, user: &str, request: &Request) -> bool {
+        let (prefix, access) = match request {
+            Request::Get { key } => (key.as_str(), Access::Read),
+            Request::Scan { prefix, .. } => (prefix.as_str(), Access::Read),
+            Request::Set { key, .. } | Request::Rm { key } => (key.as_str(), Access::Write),
+            Request::Backup { .. } | Request::Restore { .. } => ("", Access::Admin),
+            Request::Permissions { user: Some(other) } if other != user => ("", Access::Admin),
+            Request::Permissions { .. } | Request::Ping | Request::Auth { .. } => return true,
+        };
+        if self.grants(user).iter().any(|x| x.allows(prefix, access)) {
+            return true;
+        }
+        warn!(log, "access denied";
+            "audit" => true, "user" => user, "access" => ?access, "prefix" => prefix);
+        false
+    }
+
+    /// Whether `token` is the token of `user`. Failures are logged with the number of failed
+    /// attempts since the server started.
+    pub(crate) fn authenticate(&self, log: &Logger, user: &str, token: &str) -> bool {
+        // Unknown users still pay for a comparison, so timing doesn't tell whether a user exists.
+        let (expected, known) = match self.users.get(user) {
+            Some(expected) => (expected.token.as_slice(), true),
+            None => (&[][..], false),
+        };
+        if constant_time_eq(expected, token.as_bytes()) && known {
+            info!(log, "authenticated"; "user" => user);
+            return true;
+        }
+        let failed_attempts = self.failed_attempts.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(log, "authentication failed"; "user" => user, "failed_attempts" => failed_attempts);
+        false
+    }
+}
+
+/// Compare in time depending only on the lengths of the inputs.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = a.len() ^ b.len();
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        diff |= (x ^ y) as usize;
+    }
+    diff == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::{o, Discard};
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+        assert!(!constant_time_eq(b"", b"\0"));
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let log = Logger::root(Discard, o!());
+        let config = AuthConfig::parse("[users.alice]\ntoken = \"s3cret\"\n").unwrap();
+        let auth = Authenticator::new(&config);
+
+        assert!(auth.authenticate(&log, "alice", "s3cret"));
+        assert!(!auth.authenticate(&log, "alice", "secret"));
+        assert!(!auth.authenticate(&log, "bob", ""));
+        assert!(!auth.authenticate(&log, "bob", "s3cret"));
+        assert_eq!(auth.failed_attempts.load(Ordering::Relaxed), 3);
+
+        assert!(AuthConfig::parse("[users.alice]\npassword = \"x\"\n").is_err());
+    }
+
+    #[test]
+    fn test_authorize() {
+        let log = Logger::root(Discard, o!());
+        let config = AuthConfig::parse(
+            r#"
+            [users.alice]
+            token = "a"
+            permissions = [
+                { prefix = "alice/", access = ["read", "write"] },
+                { prefix = "shared/", access = ["read"] },
+            ]
+
+            [users.bob]
+            token = "b"
+
+            [users.root]
+            token = "r"
+            permissions = [{ prefix = "", access = ["admin"] }]
+            "#,
+        )
+        .unwrap();
+        let auth = Authenticator::new(&config);
+        let get = |key: &str| Request::Get {
+            key: key.to_owned(),
+        };
+        let set = |key: &str| Request::Set {
+            key: key.to_owned(),
+            value: String::new(),
+        };
+        let scan = |prefix: &str| Request::Scan {
+            prefix: prefix.to_owned(),
+            after: None,
+            limit: 10,
+        };
+        let backup = Request::Backup { dir: String::new() };
+        let permissions = |user: &str| Request::Permissions {
+            user: Some(user.to_owned()),
+        };
+
+        assert!(auth.authorize(&log, "alice", &set("alice/key")));
+        assert!(auth.authorize(&log, "alice", &get("shared/key")));
+        assert!(auth.authorize(&log, "alice", &scan("alice/")));
+        assert!(auth.authorize(&log, "alice", &permissions("alice")));
+        assert!(!auth.authorize(&log, "alice", &set("shared/key")));
+        assert!(!auth.authorize(&log, "alice", &get("root/key")));
+        assert!(!auth.authorize(&log, "alice", &scan("")));
+        assert!(!auth.authorize(&log, "alice", &backup));
+        assert!(!auth.authorize(&log, "alice", &permissions("root")));
+
+        assert!(auth.authorize(&log, "bob", &set("alice/key")));
+        assert!(auth.authorize(&log, "bob", &scan("")));
+        assert!(!auth.authorize(&log, "bob", &backup));
+        assert!(!auth.authorize(&log, "bob", &permissions("root")));
+        assert_eq!(auth.grants("bob"), &[Grant::read_write()]);
+
+        assert!(auth.authorize(&log, "root", &set("alice/key")));
+        assert!(auth.authorize(&log, "root", &backup));
+        assert!(auth.authorize(&log, "root", &permissions("alice")));
+        assert_eq!(auth.grants("root"), &[Grant::all()]);
+        assert!(auth.grants("nobody").is_empty());
+    }
+}
@@ -1,31 +1,474 @@
-use std::{fmt, str};
+use super::{LoadLimits, RateLimit, Timeouts};
+use crate::Limits;
+use serde::{Deserialize, Deserializer};
+use std::{collections::BTreeMap, fs, io, path::Path, path::PathBuf, time::Duration};
+use thiserror::Error;
 
-#[derive(Clone, Debug)]
-pub enum EngineOpt {
-    KVS,
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to read server config, caused by {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid server config, caused by {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("invalid server config, {0}")]
+    Invalid(String),
 }
 
-impl Default for EngineOpt {
-    fn default() -> Self {
-        EngineOpt::KVS
+/// Server config file, in TOML:
+///
+/// ```toml
+/// addr = ["127.0.0.1:4000", "unix:/run/kvs/kvs.sock"]
+/// engine = "sled"
+/// log_level = "info"
+/// auth_config = "users.toml"
+/// backup_root = "/var/backups/kvs"
+///
+/// [engine_options]
+/// cache_capacity = "1073741824"
+///
+/// [tls]
+/// cert = "server.pem"
+/// key = "server.key"
+///
+/// [limits]
+/// max_value_size = 1048576
+///
+/// [timeouts]
+/// idle = 60
+///
+/// [load]
+/// max_connections = 512
+/// rate_limit = 100.0
+/// ```
+///
+/// Everything is optional, with the defaults of the matching `kvs-server` flags. Timeouts are in
+/// seconds, 0 for none. The log level, limits, timeouts, load limits but `max_queued_writes`, and
+/// the auth config can be reloaded while the server runs, the rest needs a restart. Engines
+/// enforcing size limits of their own are opened with the key and value limits of `[limits]`,
+/// which they only pick up again on restart. Setting those in `[engine_options]` as well is only
+/// accepted when both agree.
+#[derive(Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Addresses to listen on, each `ip:port`, or `unix:<path>` for a Unix domain socket. A
+    /// single address can be given as a string.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub addr: Vec<String>,
+    /// Permissions of the Unix domain socket files, in octal.
+    pub socket_mode: Option<String>,
+    pub engine: Option<String>,
+    #[serde(default)]
+    pub engine_options: BTreeMap<String, String>,
+    /// One of `critical`, `error`, `warning`, `info`, `debug` or `trace`.
+    pub log_level: Option<String>,
+    /// File with user tokens, see [`AuthConfig`](super::AuthConfig).
+    pub auth_config: Option<PathBuf>,
+    /// Directory backup and restore requests are confined to, they're refused without one.
+    pub backup_root: Option<PathBuf>,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub timeouts: TimeoutsConfig,
+    #[serde(default)]
+    pub load: LoadConfig,
+}
+
+/// PEM files of the server certificate, and of the authorities client certificates must be
+/// signed by.
+#[derive(Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_key_size: Option<usize>,
+    pub max_value_size: Option<usize>,
+    pub max_frame_size: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub idle: Option<u64>,
+    pub request: Option<u64>,
+    pub lifetime: Option<u64>,
+    pub shutdown: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LoadConfig {
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub rate_limit: Option<f64>,
+    pub rate_burst: Option<f64>,
+    pub max_in_flight: Option<usize>,
+    pub max_queued_writes: Option<usize>,
+}
+
+/// List given as is, or as its only item.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
     }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(x) => vec![x],
+        OneOrMany::Many(x) => x,
+    })
+}
+
+/// Take `override` if set, `base` otherwise.
+fn or<T>(base: Option<T>, r#override: Option<T>) -> Option<T> {
+    r#override.or(base)
 }
 
-impl str::FromStr for EngineOpt {
-    type Err = Box<dyn std::error::Error + Send + Sync>;
+/// Timeout of `secs` seconds, none for 0.
+fn timeout(secs: u64) -> Option<Duration> {
+    Some(Duration::from_secs(secs)).filter(|x| !x.is_zero())
+}
+
+impl ServerConfig {
+    /// Read the config at `path`. Relative paths in it are relative to the directory of the file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let mut config = Self::parse(&fs::read_to_string(path)?)?;
+        if let Some(dir) = path.parent() {
+            let paths = vec![
+                &mut config.auth_config,
+                &mut config.backup_root,
+                &mut config.tls.cert,
+                &mut config.tls.key,
+                &mut config.tls.client_ca,
+            ];
+            for path in paths.into_iter().flatten() {
+                *path = dir.join(&path);
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn parse(config: &str) -> Result<Self, ConfigError> {
+        Ok(toml::from_str(config)?)
+    }
+
+    /// Settings of `self`, replaced by those set in `overrides`.
+    pub fn merge(mut self, overrides: Self) -> Self {
+        self.engine_options.extend(overrides.engine_options);
+        Self {
+            addr: if overrides.addr.is_empty() {
+                self.addr
+            } else {
+                overrides.addr
+            },
+            socket_mode: or(self.socket_mode, overrides.socket_mode),
+            engine: or(self.engine, overrides.engine),
+            engine_options: self.engine_options,
+            log_level: or(self.log_level, overrides.log_level),
+            auth_config: or(self.auth_config, overrides.auth_config),
+            backup_root: or(self.backup_root, overrides.backup_root),
+            tls: TlsConfig {
+                cert: or(self.tls.cert, overrides.tls.cert),
+                key: or(self.tls.key, overrides.tls.key),
+                client_ca: or(self.tls.client_ca, overrides.tls.client_ca),
+            },
+            limits: LimitsConfig {
+                max_key_size: or(self.limits.max_key_size, overrides.limits.max_key_size),
+                max_value_size: or(self.limits.max_value_size, overrides.limits.max_value_size),
+                max_frame_size: or(self.limits.max_frame_size, overrides.limits.max_frame_size),
+            },
+            timeouts: TimeoutsConfig {
+                idle: or(self.timeouts.idle, overrides.timeouts.idle),
+                request: or(self.timeouts.request, overrides.timeouts.request),
+                lifetime: or(self.timeouts.lifetime, overrides.timeouts.lifetime),
+                shutdown: or(self.timeouts.shutdown, overrides.timeouts.shutdown),
+            },
+            load: LoadConfig {
+                max_connections: or(self.load.max_connections, overrides.load.max_connections),
+                max_connections_per_ip: or(
+                    self.load.max_connections_per_ip,
+                    overrides.load.max_connections_per_ip,
+                ),
+                rate_limit: or(self.load.rate_limit, overrides.load.rate_limit),
+                rate_burst: or(self.load.rate_burst, overrides.load.rate_burst),
+                max_in_flight: or(self.load.max_in_flight, overrides.load.max_in_flight),
+                max_queued_writes: or(
+                    self.load.max_queued_writes,
+                    overrides.load.max_queued_writes,
+                ),
+            },
+        }
+    }
+
+    /// Check the settings that can't be checked by their type.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.log_level()?;
+        self.socket_mode()?;
+        if let Some(rate) = self.load.rate_limit {
+            if !(rate > 0.0 && rate.is_finite()) {
+                return Err(ConfigError::Invalid(format!(
+                    "rate limit `{}` isn't a positive number",
+                    rate
+                )));
+            }
+        }
+        if let Some(burst) = self.load.rate_burst {
+            if !burst.is_finite() || burst < 1.0 {
+                return Err(ConfigError::Invalid(format!(
+                    "rate burst `{}` is lower than 1",
+                    burst
+                )));
+            }
+        }
+        if self.load.max_in_flight == Some(0) || self.load.max_queued_writes == Some(0) {
+            return Err(ConfigError::Invalid(
+                "in flight requests and queued writes must be at least 1".to_owned(),
+            ));
+        }
+        for (name, limit) in self.engine_limits().iter() {
+            if let Some(value) = self.engine_options.get(*name) {
+                if value.parse() != Ok(*limit) {
+                    return Err(ConfigError::Invalid(format!(
+                        "engine option `{}` = `{}` disagrees with the server limit of {}",
+                        name, value, limit
+                    )));
+                }
+            }
+        }
+        if self.tls.cert.is_some() != self.tls.key.is_some()
+            || (self.tls.client_ca.is_some() && self.tls.cert.is_none())
+        {
+            return Err(ConfigError::Invalid(
+                "TLS needs both a certificate and its key".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> Result<slog::Level, ConfigError> {
+        match &self.log_level {
+            Some(level) => level
+                .parse()
+                .map_err(|_| ConfigError::Invalid(format!("unknown log level `{}`", level))),
+            None => Ok(slog::Level::Info),
+        }
+    }
+
+    pub fn socket_mode(&self) -> Result<u32, ConfigError> {
+        match &self.socket_mode {
+            Some(mode) => u32::from_str_radix(mode, 8)
+                .map_err(|_| ConfigError::Invalid(format!("invalid socket mode `{}`", mode))),
+            None => Ok(0o660),
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        let defaults = Limits::default();
+        Limits {
+            max_key_size: self.limits.max_key_size.unwrap_or(defaults.max_key_size),
+            max_value_size: self
+                .limits
+                .max_value_size
+                .unwrap_or(defaults.max_value_size),
+            max_frame_size: self
+                .limits
+                .max_frame_size
+                .unwrap_or(defaults.max_frame_size),
+        }
+    }
+
+    /// Engine options matching the key and value size limits of the server, with their values.
+    pub fn engine_limits(&self) -> [(&'static str, usize); 2] {
+        let limits = self.limits();
+        [
+            ("max_key_size", limits.max_key_size),
+            ("max_value_size", limits.max_value_size),
+        ]
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        let defaults = Timeouts::default();
+        Timeouts {
+            idle: self.timeouts.idle.map_or(defaults.idle, timeout),
+            request: self.timeouts.request.map_or(defaults.request, timeout),
+            lifetime: self.timeouts.lifetime.map(Duration::from_secs),
+        }
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        self.timeouts
+            .shutdown
+            .map_or(super::server::DEFAULT_SHUTDOWN_TIMEOUT, Duration::from_secs)
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_ref() {
-            "kvs" => Ok(EngineOpt::KVS),
-            other => Err(format!("unknown engine `{}`", other).into()),
+    /// Load limits, with bursts of the rate limit by default.
+    pub fn load_limits(&self) -> LoadLimits {
+        let defaults = LoadLimits::default();
+        LoadLimits {
+            max_connections: self.load.max_connections.or(defaults.max_connections),
+            max_connections_per_ip: self.load.max_connections_per_ip,
+            rate: self.load.rate_limit.map(|per_second| RateLimit {
+                per_second,
+                burst: self.load.rate_burst.unwrap_or(per_second).max(1.0),
+            }),
+            max_in_flight: self.load.max_in_flight.unwrap_or(defaults.max_in_flight),
+            max_queued_writes: self
+                .load
+                .max_queued_writes
+                .unwrap_or(defaults.max_queued_writes),
         }
     }
 }
 
-impl fmt::Display for EngineOpt {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            EngineOpt::KVS => write!(f, "kvs"),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = ServerConfig::parse(
+            r#"
+            addr = "127.0.0.1:4100"
+            log_level = "debug"
+
+            [engine_options]
+            cache_capacity = "10"
+
+            [limits]
+            max_value_size = 100
+
+            [timeouts]
+            idle = 0
+            lifetime = 60
+
+            [load]
+            rate_limit = 5.0
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.addr, vec!["127.0.0.1:4100".to_owned()]);
+        assert_eq!(config.log_level().unwrap(), slog::Level::Debug);
+        assert_eq!(config.engine_options["cache_capacity"], "10");
+        assert_eq!(config.limits().max_value_size, 100);
+        assert_eq!(config.limits().max_key_size, Limits::default().max_key_size);
+        assert_eq!(
+            config.timeouts(),
+            Timeouts {
+                idle: None,
+                request: Timeouts::default().request,
+                lifetime: Some(Duration::from_secs(60)),
+            }
+        );
+        assert_eq!(
+            config.load_limits().rate,
+            Some(RateLimit {
+                per_second: 5.0,
+                burst: 5.0,
+            })
+        );
+
+        let config = ServerConfig::parse("addr = [\"127.0.0.1:4100\", \"unix:kvs.sock\"]").unwrap();
+        assert_eq!(config.addr, ["127.0.0.1:4100", "unix:kvs.sock"]);
+        assert!(matches!(
+            ServerConfig::parse("unknown = 1"),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            ServerConfig::parse("[limits]\nmax_value_size = \"big\""),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_from_file_resolves_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kvs.toml");
+        fs::write(
+            &path,
+            concat!(
+                "auth_config = \"users.toml\"\n",
+                "backup_root = \"backups\"\n",
+                "[tls]\ncert = \"/etc/kvs/cert.pem\"\nkey = \"tls/key.pem\"\n",
+            ),
+        )
+        .unwrap();
+
+        let config = ServerConfig::from_file(&path).unwrap();
+        assert_eq!(config.auth_config, Some(dir.path().join("users.toml")));
+        assert_eq!(config.backup_root, Some(dir.path().join("backups")));
+        assert_eq!(config.tls.cert, Some(PathBuf::from("/etc/kvs/cert.pem")));
+        assert_eq!(config.tls.key, Some(dir.path().join("tls/key.pem")));
+        assert_eq!(config.tls.client_ca, None);
+    }
+
+    #[test]
+    fn test_merge() {
+        let file = ServerConfig::parse(
+            r#"
+            addr = "127.0.0.1:4100"
+            engine = "sled"
+
+            [engine_options]
+            a = "1"
+            b = "2"
+
+            [limits]
+            max_key_size = 10
+            max_value_size = 100
+            "#,
+        )
+        .unwrap();
+        let overrides = ServerConfig {
+            addr: vec!["127.0.0.1:4200".to_owned(), "unix:kvs.sock".to_owned()],
+            engine_options: vec![("b".to_owned(), "3".to_owned())].into_iter().collect(),
+            limits: LimitsConfig {
+                max_value_size: Some(200),
+                ..LimitsConfig::default()
+            },
+            ..ServerConfig::default()
+        };
+
+        let config = file.merge(overrides);
+        assert_eq!(config.addr, ["127.0.0.1:4200", "unix:kvs.sock"]);
+        assert_eq!(config.engine.as_deref(), Some("sled"));
+        assert_eq!(config.engine_options["a"], "1");
+        assert_eq!(config.engine_options["b"], "3");
+        assert_eq!(config.limits.max_key_size, Some(10));
+        assert_eq!(config.limits.max_value_size, Some(200));
+    }
+
+    #[test]
+    fn test_validate() {
+        let invalid = [
+            "log_level = \"loud\"",
+            "socket_mode = \"999\"",
+            "[load]\nrate_limit = 0.0",
+            "[load]\nrate_limit = 1.0\nrate_burst = 0.5",
+            "[load]\nmax_in_flight = 0",
+            "[tls]\ncert = \"server.pem\"",
+            "[engine_options]\nmax_value_size = \"10\"",
+            "[engine_options]\nmax_key_size = \"10\"\n[limits]\nmax_key_size = 20",
+        ];
+        for config in invalid.iter() {
+            let config = ServerConfig::parse(config).unwrap();
+            assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
         }
+
+        let config = ServerConfig::parse(
+            "[engine_options]\nmax_key_size = \"10\"\n[limits]\nmax_key_size = 10",
+        )
+        .unwrap();
+        config.validate().unwrap();
     }
 }
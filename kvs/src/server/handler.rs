@@ -1,11 +1,32 @@
 use crate::{
-    protocol::{Request, Response},
+    protocol::{ErrorKind, Grant, Request, Response, MAX_SCAN_LIMIT},
     KvsEngine, KvsEngineError,
 };
-use slog::{debug, error, Logger};
+use slog::{debug, error, info, Logger};
+use std::path::Path;
+
+/// Failure response describing an engine error.
+fn failure(err: &KvsEngineError) -> Response {
+    let kind = match err {
+        KvsEngineError::EntryNotFound { .. } => ErrorKind::NotFound,
+        KvsEngineError::Unsupported(_) => ErrorKind::InvalidRequest,
+        KvsEngineError::TooLarge(_) => ErrorKind::TooLarge,
+        KvsEngineError::Io(_) | KvsEngineError::Corruption(_) | KvsEngineError::Other(_) => {
+            ErrorKind::Storage
+        }
+    };
+    Response::failure(kind, err.to_string())
+}
+
+/// Outcome of handling a request.
+pub enum Handled {
+    Done(Response),
+    /// Work left to produce the response, to run once the handler is released.
+    Deferred(Box<dyn FnOnce() -> Response + Send>),
+}
 
 pub trait HandleRequest {
-    fn handle(&mut self, log: &Logger, request: Request) -> Result<Response, KvsEngineError>;
+    fn handle(&mut self, log: &Logger, request: Request) -> Result<Handled, KvsEngineError>;
 }
 
 // I love how composable Rust is.
@@ -13,13 +34,13 @@ impl<T> HandleRequest for T
 where
     T: KvsEngine + ?Sized,
 {
-    fn handle(&mut self, log: &Logger, request: Request) -> Result<Response, KvsEngineError> {
-        match request {
+    fn handle(&mut self, log: &Logger, request: Request) -> Result<Handled, KvsEngineError> {
+        let response = match request {
             Request::Set { key, value } => {
                 let result = self.set(key, value);
                 let response = match result {
                     Ok(_) => Response::Success(None),
-                    Err(e) => Response::Failure(e.to_string()),
+                    Err(e) => failure(&e),
                 };
                 Ok(response)
             }
@@ -28,7 +49,7 @@ where
                 let result = self.get(&key);
                 let response = match result {
                     Ok(v) => Response::Success(v),
-                    Err(e) => Response::Failure(e.to_string()),
+                    Err(e) => failure(&e),
                 };
                 Ok(response)
             }
@@ -41,15 +62,79 @@ where
                     }
                     Err(KvsEngineError::EntryNotFound { .. }) => {
                         debug!(log, "entry not found"; "key" => key);
-                        Response::Failure("Key not found".to_owned())
+                        Response::failure(ErrorKind::NotFound, "Key not found")
                     }
                     Err(e) => {
                         error!(log, "error on removing entry"; "error" => ?e, "key" => key);
-                        Response::Failure(e.to_string())
+                        failure(&e)
                     }
                 };
                 Ok(response)
             }
-        }
+            Request::Scan {
+                prefix,
+                after,
+                limit,
+            } => {
+                let entries = match &after {
+                    Some(after) => self.scan_after(&prefix, after),
+                    None => self.scan(&prefix),
+                };
+                let result = entries.and_then(|entries| {
+                    entries
+                        .take(limit.min(MAX_SCAN_LIMIT) as usize)
+                        .collect::<Result<Vec<_>, _>>()
+                });
+                let response = match result {
+                    Ok(entries) => Response::Entries(entries),
+                    Err(e) => {
+                        error!(log, "error on scanning entries"; "error" => ?e, "prefix" => prefix);
+                        failure(&e)
+                    }
+                };
+                Ok(response)
+            }
+            // Only the start of a backup needs the engine, the copy mustn't hold up other requests.
+            Request::Backup { dir } => {
+                let job = match self.start_backup(Path::new(&dir)) {
+                    Ok(job) => job,
+                    Err(e) => {
+                        error!(log, "error on starting backup"; "error" => ?e, "dir" => dir);
+                        return Ok(Handled::Done(failure(&e)));
+                    }
+                };
+                let log = log.clone();
+                let finish = move || match job() {
+                    Ok(_) => {
+                        info!(log, "backup written"; "dir" => dir);
+                        Response::Success(None)
+                    }
+                    Err(e) => {
+                        error!(log, "error on writing backup"; "error" => ?e, "dir" => dir);
+                        failure(&e)
+                    }
+                };
+                return Ok(Handled::Deferred(Box::new(finish)));
+            }
+            Request::Restore { dir } => {
+                let response = match self.restore(Path::new(&dir)) {
+                    Ok(_) => {
+                        info!(log, "backup restored"; "dir" => dir);
+                        Response::Success(None)
+                    }
+                    Err(e) => {
+                        error!(log, "error on restoring backup"; "error" => ?e, "dir" => dir);
+                        failure(&e)
+                    }
+                };
+                Ok(response)
+            }
+            Request::Ping => Ok(Response::Success(None)),
+            // Connections are authenticated by the server, before requests reach the handler.
+            Request::Auth { .. } => Ok(Response::Success(None)),
+            // Without authentication everyone may read and write, admin requests are refused.
+            Request::Permissions { .. } => Ok(Response::Permissions(vec![Grant::read_write()])),
+        };
+        response.map(Handled::Done)
     }
 }
@@ -1,8 +1,17 @@
+mod auth;
 mod config;
 mod handler;
+#[allow(clippy::module_inception)]
 mod server;
+mod stats;
+mod throttle;
+mod timeouts;
 
-use handler::HandleRequest;
+use handler::{HandleRequest, Handled};
 
-pub use config::EngineOpt;
-pub use server::KvsServer;
+pub use auth::{AuthConfig, AuthError, Authenticator, UserConfig};
+pub use config::{ConfigError, LimitsConfig, LoadConfig, ServerConfig, TimeoutsConfig, TlsConfig};
+pub use server::{KvsServer, ServerError, ShutdownHandle};
+pub use stats::ServerStats;
+pub use throttle::{LoadLimits, RateLimit};
+pub use timeouts::Timeouts;
@@ -1,25 +1,48 @@
-use super::HandleRequest;
+use super::{
+    stats::Stats,
+    throttle::{Admission, LoadLimits, Permit, Semaphore, TokenBucket},
+    timeouts::{DeadlineReader, Timeouts},
+    Authenticator, HandleRequest, Handled, ServerStats,
+};
 use crate::{
-    protocol::{Request, Response, Serialization, SerializationError},
+    limits::{LimitError, Limits},
+    protocol::{
+        Envelope, ErrorKind, FrameError, Request, Response, Serialization, SerializationError,
+    },
+    transport::{Listener, Stream},
     KvsEngineError,
 };
 use nix::{
+    errno::Errno,
     sys::{
         epoll::{epoll_create, epoll_ctl, epoll_wait, EpollEvent, EpollFlags, EpollOp},
         eventfd::*,
+        signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
     },
     unistd,
 };
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-use slog::{debug, error, info, o, Discard, Logger};
+use slog::{debug, error, info, o, warn, Discard, Logger};
 use std::{
-    io,
-    net::{SocketAddr, TcpListener},
+    collections::HashMap,
+    io::{self, BufReader, BufWriter, Read, Write},
+    iter, mem,
+    net::{Shutdown, SocketAddr, TcpListener},
     os::unix::io::{AsRawFd, RawFd},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        mpsc, Arc, Condvar, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 
+#[cfg(feature = "tls")]
+use crate::tls::{TlsServerConfig, TlsStream};
+
 #[derive(Error, Debug)]
 pub enum ServerError {
     #[error("failed to bind socket, caused by {0}")]
@@ -34,55 +57,314 @@ pub enum ServerError {
     ResponseError(#[from] SerializationError),
 }
 
+/// Longest wait for a client to complete the TLS handshake.
+#[cfg(feature = "tls")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default for how long in-flight requests get to finish once shutting down.
+pub(super) const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest time spent discarding the rest of an oversized request before hanging up.
+const DISCARD_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Longest wait for the failure sent to a connection turned away to go out.
+const REJECT_TIMEOUT: Duration = Duration::from_millis(100);
+
 #[derive(FromPrimitive, Debug)]
 enum PollId {
     Listener,
     Signal,
 }
 
-pub struct KvsServer {
-    log: Logger,
-    listener: TcpListener,
-    signal_fd: RawFd,
+/// Epoll data of an event, the index of the listener it's for is kept in the upper half.
+fn poll_data(id: PollId, index: usize) -> u64 {
+    id as u64 | (index as u64) << 32
 }
 
-impl Drop for KvsServer {
+/// Eventfd waking the accept loop up to shut down. Closed once the server and its handles are
+/// gone.
+#[derive(Debug)]
+struct EventFd(RawFd);
+
+impl Drop for EventFd {
     fn drop(&mut self) {
-        unistd::close(self.signal_fd).expect("failed to close signal file descriptor");
+        unistd::close(self.0).expect("failed to close signal file descriptor");
+    }
+}
+
+/// Eventfd the signal handler installed by [`ShutdownHandle::on_signals`] writes to, or `-1`.
+static SIGNAL_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(_: nix::libc::c_int) {
+    let fd = SIGNAL_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        // Only async-signal-safe calls in here, `write` is one.
+        let _ = unistd::write(fd, &1u64.to_ne_bytes());
+    }
+}
+
+/// Shuts a [`KvsServer`] down from anywhere, even before it listens or after it's gone.
+///
+/// The server stops accepting connections and stops reading requests, gives requests in flight
+/// until its shutdown timeout to finish, then hangs up on every client and returns from
+/// [`listen`](KvsServer::listen).
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    fd: Arc<EventFd>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) -> Result<(), ServerError> {
+        unistd::write(self.fd.0, &1u64.to_ne_bytes())?;
+        Ok(())
+    }
+
+    /// Shut down on SIGINT and SIGTERM.
+    ///
+    /// Signal handlers are process wide, so this is for the server owning the process. A later
+    /// call moves the handlers to another server.
+    pub fn on_signals(&self) -> Result<(), ServerError> {
+        // Keep the eventfd open for as long as a signal might write to it.
+        mem::forget(self.fd.clone());
+        SIGNAL_FD.store(self.fd.0, Ordering::SeqCst);
+
+        let action = SigAction::new(
+            SigHandler::Handler(on_signal),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        // The handler only touches an atomic and writes to the eventfd.
+        unsafe {
+            sigaction(Signal::SIGINT, &action)?;
+            sigaction(Signal::SIGTERM, &action)?;
+        }
+        Ok(())
     }
 }
 
+/// Settings that can change while the server runs.
+#[derive(Clone, Default)]
+struct Settings {
+    auth: Option<Arc<Authenticator>>,
+    limits: Limits,
+    timeouts: Timeouts,
+    load: LoadLimits,
+}
+
+pub struct KvsServer {
+    log: Logger,
+    listeners: Vec<Listener>,
+    signal_fd: Arc<EventFd>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsServerConfig>,
+    /// Requests use the settings current when they're read, connections the timeouts current when
+    /// they're accepted.
+    settings: RwLock<Arc<Settings>>,
+    /// Permits for writes waiting on the engine.
+    queued_writes: Semaphore,
+    /// Rate limits of authenticated users, shared by their connections.
+    user_buckets: Mutex<HashMap<String, TokenBucket>>,
+    /// Directory backup and restore requests are confined to.
+    backup_root: Option<PathBuf>,
+    shutdown_timeout: Duration,
+    stats: Stats,
+}
+
 impl KvsServer {
     pub fn new(
         log: impl Into<Option<Logger>>,
         address: impl Into<SocketAddr>,
     ) -> Result<Self, ServerError> {
         let log = log.into().unwrap_or_else(|| Logger::root(Discard, o!()));
+        let listener = bind_tcp(&log, address.into())?;
+        Self::with_listener(log, listener)
+    }
 
-        debug!(log, "binding TCP listener");
-        let addr = address.into();
-        let listener = {
-            fn error_mapper(error: io::Error) -> ServerError {
-                ServerError::BindSocketError(error)
-            }
-            let listener = TcpListener::bind(addr).map_err(error_mapper)?;
-            // Blocking for request mechanism is handled by epoll.
-            listener.set_nonblocking(true).map_err(error_mapper)?;
-            listener
-        };
+    /// Listen on a Unix domain socket at `path`, created with permissions `mode`, like `0o660`.
+    pub fn unix(
+        log: impl Into<Option<Logger>>,
+        path: impl AsRef<Path>,
+        mode: u32,
+    ) -> Result<Self, ServerError> {
+        let log = log.into().unwrap_or_else(|| Logger::root(Discard, o!()));
+        let listener = bind_unix(&log, path.as_ref(), mode)?;
+        Self::with_listener(log, listener)
+    }
+
+    /// Also listen on `address`, connections from every listener are served alike.
+    pub fn add_address(self, address: impl Into<SocketAddr>) -> Result<Self, ServerError> {
+        let listener = bind_tcp(&self.log, address.into())?;
+        self.add_listener(listener)
+    }
+
+    /// Also listen on a Unix domain socket at `path`, see [`unix`](Self::unix).
+    pub fn add_unix(self, path: impl AsRef<Path>, mode: u32) -> Result<Self, ServerError> {
+        let listener = bind_unix(&self.log, path.as_ref(), mode)?;
+        self.add_listener(listener)
+    }
+
+    fn add_listener(mut self, listener: Listener) -> Result<Self, ServerError> {
+        // Blocking for request mechanism is handled by epoll.
+        listener
+            .set_nonblocking(true)
+            .map_err(ServerError::BindSocketError)?;
+        self.listeners.push(listener);
+        Ok(self)
+    }
 
+    fn with_listener(log: Logger, listener: Listener) -> Result<Self, ServerError> {
         debug!(log, "creating signal eventfd");
-        let signal_fd = eventfd(0, EfdFlags::empty())?;
+        let signal_fd = Arc::new(EventFd(eventfd(0, EfdFlags::empty())?));
 
         let server = Self {
             log,
-            listener,
+            listeners: Vec::new(),
             signal_fd,
+            #[cfg(feature = "tls")]
+            tls: None,
+            settings: RwLock::default(),
+            queued_writes: Semaphore::new(LoadLimits::default().max_queued_writes),
+            user_buckets: Mutex::new(HashMap::new()),
+            backup_root: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            stats: Stats::default(),
         };
-        Ok(server)
+        server.add_listener(listener)
     }
 
-    pub fn listen(&self, handler: &mut impl HandleRequest) -> Result<(), ServerError> {
+    /// Require connections to authenticate before any request but pings.
+    pub fn with_auth(self, auth: Authenticator) -> Self {
+        self.set_auth(Some(auth));
+        self
+    }
+
+    /// Answer requests over `limits` with a "too large" failure, then hang up.
+    pub fn with_limits(self, limits: Limits) -> Self {
+        self.set_limits(limits);
+        self
+    }
+
+    /// Close connections running into `timeouts`.
+    pub fn with_timeouts(self, timeouts: Timeouts) -> Self {
+        self.set_timeouts(timeouts);
+        self
+    }
+
+    /// Cap connections and the rate of requests, and hold off reading requests while too many are
+    /// waiting on the engine.
+    pub fn with_load_limits(mut self, load: LoadLimits) -> Self {
+        self.queued_writes = Semaphore::new(load.max_queued_writes.max(1));
+        self.set_load_limits(load);
+        self
+    }
+
+    /// Replace the authenticator while the server runs, `None` to stop requiring authentication.
+    /// Connections stay authenticated as the user they were, with the permissions `auth` gives.
+    pub fn set_auth(&self, auth: Option<Authenticator>) {
+        self.update(|x| x.auth = auth.map(Arc::new));
+    }
+
+    /// Replace the size limits while the server runs.
+    pub fn set_limits(&self, limits: Limits) {
+        self.update(|x| x.limits = limits);
+    }
+
+    /// Replace the timeouts while the server runs, for connections accepted from then on.
+    pub fn set_timeouts(&self, timeouts: Timeouts) {
+        self.update(|x| x.timeouts = timeouts);
+    }
+
+    /// Replace the load limits while the server runs. `max_queued_writes` only changes through
+    /// [`with_load_limits`](Self::with_load_limits).
+    pub fn set_load_limits(&self, load: LoadLimits) {
+        self.update(|x| x.load = load);
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Settings)) {
+        let mut settings = self.settings.write().unwrap();
+        change(Arc::make_mut(&mut settings));
+    }
+
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Counts since the server was created.
+    pub fn stats(&self) -> ServerStats {
+        self.stats.snapshot()
+    }
+
+    /// Serve backup and restore requests, their directories being relative to `root`. They're
+    /// refused without one.
+    pub fn with_backup_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.backup_root = Some(root.into());
+        self
+    }
+
+    /// Give requests in flight `timeout` to finish once shutting down, 10 seconds by default.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Handle to shut the server down with, from another thread or a signal.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            fd: self.signal_fd.clone(),
+        }
+    }
+
+    /// Require TLS on TCP connections. Connections over a Unix domain socket stay plaintext.
+    ///
+    /// TCP connections turned away at admission are then closed without a "busy" failure, see
+    /// [`LoadLimits::max_connections`].
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: TlsServerConfig) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Wrap a freshly accepted connection in TLS when it's enabled, adding the identity of the
+    /// client certificate to the logging context.
+    fn secure(&self, stream: Stream, log: Logger) -> io::Result<(Stream, Logger)> {
+        #[cfg(feature = "tls")]
+        if let (Some(config), Stream::Tcp(tcp)) = (&self.tls, &stream) {
+            let tls = TlsStream::accept(tcp.try_clone()?, config, TLS_HANDSHAKE_TIMEOUT)?;
+            let log = match tls.peer_identity() {
+                Some(identity) => log.new(o!("client" => identity)),
+                None => log,
+            };
+            return Ok((Stream::Tls(Arc::new(tls)), log));
+        }
+        Ok((stream, log))
+    }
+
+    /// Tell a connection turned away at admission that the server is busy, so clients can back
+    /// off and retry. Connections waiting for a TLS handshake are closed without a word, the
+    /// accept loop won't spend a handshake on them.
+    fn reject(&self, stream: &Stream, reason: &str) {
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() && matches!(stream, Stream::Tcp(_)) {
+            return;
+        }
+        let response =
+            Response::failure(ErrorKind::Busy, format!("connection rejected, {}", reason));
+        // Written right away into a fresh socket buffer, the timeout only guards odd peers.
+        let _ = stream.set_write_timeout(Some(REJECT_TIMEOUT));
+        let _ = Envelope::new(0, response).to_writer(&mut &*stream);
+        let _ = stream.shutdown(Shutdown::Write);
+    }
+
+    /// Serve connections until shut down.
+    ///
+    /// Every connection is served on its own thread, with a worker thread passing its requests to
+    /// the handler in the order they arrive. Calls into the handler are serialized, work left once
+    /// it's released, like copying a backup, runs on a thread of its own and may be overtaken by
+    /// later requests of the same connection.
+    pub fn listen<H>(&self, handler: &mut H) -> Result<(), ServerError>
+    where
+        H: HandleRequest + Send + ?Sized,
+    {
         // Alias self.log so it's easier to cascade logger.
         let log = &self.log;
 
@@ -99,122 +381,530 @@ impl KvsServer {
             },
             PollId::Signal as _,
         );
-        epoll_ctl(epfd, EpollOp::EpollCtlAdd, self.signal_fd, &mut signal_ev)?;
+        epoll_ctl(epfd, EpollOp::EpollCtlAdd, self.signal_fd.0, &mut signal_ev)?;
 
-        let mut listener_ev = EpollEvent::new(
-            {
-                let mut flags = EpollFlags::empty();
-                flags.set(EpollFlags::EPOLLIN, true);
-                flags
-            },
-            PollId::Listener as _,
-        );
-        epoll_ctl(
-            epfd,
-            EpollOp::EpollCtlAdd,
-            self.listener.as_raw_fd(),
-            &mut listener_ev,
-        )?;
-
-        let mut shutdown = false;
-        loop {
-            const EPOLL_MAXEVENTS: usize = 2;
-            const EPOLL_TIMEOUT: isize = -1;
+        for (index, listener) in self.listeners.iter().enumerate() {
+            let mut listener_ev = EpollEvent::new(
+                {
+                    let mut flags = EpollFlags::empty();
+                    flags.set(EpollFlags::EPOLLIN, true);
+                    flags
+                },
+                poll_data(PollId::Listener, index),
+            );
+            epoll_ctl(
+                epfd,
+                EpollOp::EpollCtlAdd,
+                listener.as_raw_fd(),
+                &mut listener_ev,
+            )?;
+        }
 
-            let log = log.new(o!(
-                "epoll_maxevents" => EPOLL_MAXEVENTS,
-                "epoll_timeout" => EPOLL_TIMEOUT
-            ));
+        let handler = &Mutex::new(handler);
+        let connections = &Mutex::new(HashMap::<u64, Stream>::new());
+        let closed = &Condvar::new();
+        let admission = &Mutex::new(Admission::default());
+        let mut next_connection = 0u64;
+        thread::scope(|scope| {
+            let mut shutdown = false;
+            let result = 'accept: loop {
+                const EPOLL_MAXEVENTS: usize = 2;
+                const EPOLL_TIMEOUT: isize = -1;
 
-            let mut events = [EpollEvent::empty(); EPOLL_MAXEVENTS];
-
-            info!(log, "waiting for incoming connection");
-
-            debug!(log, "epoll wait");
-            let count = epoll_wait(epfd, &mut events, EPOLL_TIMEOUT)?;
-
-            for event in events.iter().take(count) {
-                match PollId::from_u64(event.data()) {
-                    Some(PollId::Listener) => {
-                        debug!(log, "incoming connection received");
-                        let (mut stream, peer) = self
-                            .listener
-                            .accept()
-                            .map_err(|x| ServerError::AcceptConnectionError(x))?;
-                        let log = log.new(o!("peer" => peer));
-
-                        info!(log, "connected");
-                        let mut quit = false;
-                        loop {
-                            let request = Request::from_reader(&mut stream);
-
-                            match request {
-                                Ok(Some(request)) => {
-                                    info!(log, "received request"; "request" => ?request);
-                                    let response = handler.handle(&log, request)?;
-                                    info!(log, "sending response"; "response" => ?response);
-                                    response.to_writer(&mut stream)?;
-                                }
-                                Ok(None) => {
-                                    debug!(log, "received eof");
-                                    quit = true;
-                                }
+                let log = log.new(o!(
+                    "epoll_maxevents" => EPOLL_MAXEVENTS,
+                    "epoll_timeout" => EPOLL_TIMEOUT
+                ));
+
+                let mut events = [EpollEvent::empty(); EPOLL_MAXEVENTS];
+
+                info!(log, "waiting for incoming connection");
+
+                debug!(log, "epoll wait");
+                let count = match epoll_wait(epfd, &mut events, EPOLL_TIMEOUT) {
+                    Ok(x) => x,
+                    // Interrupted by a signal, likely the one shutting us down.
+                    Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                    Err(err) => break Err(err.into()),
+                };
+
+                for event in events.iter().take(count) {
+                    match PollId::from_u64(event.data() & u64::from(u32::MAX)) {
+                        Some(PollId::Listener) => {
+                            debug!(log, "incoming connection received");
+                            let listener = &self.listeners[(event.data() >> 32) as usize];
+                            let (stream, peer) = match listener.accept() {
+                                Ok(x) => x,
                                 Err(err) => {
-                                    error!(log, "received invalid request"; "error" => %err);
-                                    let response = Response::Failure("invalid request".to_owned());
-                                    info!(log, "sending response"; "response" => ?response);
-                                    response.to_writer(&mut stream)?;
+                                    break 'accept Err(ServerError::AcceptConnectionError(err))
                                 }
                             };
-
-                            if quit {
-                                info!(log, "closing connection");
-                                break;
+                            let ip = stream.peer_ip();
+                            let load = self.settings().load;
+                            let admitted = admission.lock().unwrap().admit(&load, ip);
+                            if let Err(reason) = admitted {
+                                warn!(log, "connection rejected"; "peer" => peer, "reason" => reason);
+                                self.stats.rejected_connection();
+                                self.reject(&stream, reason);
+                                continue;
+                            }
+                            let connection = next_connection;
+                            next_connection += 1;
+                            self.stats.connection();
+                            if let Ok(stream) = stream.try_clone() {
+                                connections.lock().unwrap().insert(connection, stream);
                             }
+                            let log = log.new(o!("peer" => peer, "connection" => connection));
+                            scope.spawn(move || {
+                                match self.secure(stream, log.clone()) {
+                                    Ok((stream, log)) => {
+                                        info!(log, "connected");
+                                        if let Err(err) = serve(self, &log, &stream, handler) {
+                                            error!(log, "connection failed"; "error" => %err);
+                                        }
+                                    }
+                                    Err(err) => error!(log, "handshake failed"; "error" => %err),
+                                }
+                                admission.lock().unwrap().release(ip);
+                                connections.lock().unwrap().remove(&connection);
+                                closed.notify_all();
+                                info!(log, "closing connection");
+                            });
                         }
+                        Some(PollId::Signal) => {
+                            debug!(log, "shutdown signal receieved");
+                            shutdown = true;
+                        }
+                        None => unimplemented!(),
                     }
-                    Some(PollId::Signal) => {
-                        debug!(log, "shutdown signal receieved");
-                        shutdown = true;
-                    }
-                    None => unimplemented!(),
                 }
+                if shutdown {
+                    info!(log, "shutting down");
+                    break Ok(());
+                }
+            };
+
+            // Stop reading requests, connections close once their requests in flight are answered.
+            let connections = connections.lock().unwrap();
+            for stream in connections.values() {
+                let _ = stream.shutdown(Shutdown::Read);
             }
-            if shutdown {
-                info!(log, "shutting down");
-                break;
+            let (connections, wait) = closed
+                .wait_timeout_while(connections, self.shutdown_timeout, |x| !x.is_empty())
+                .unwrap();
+            if wait.timed_out() {
+                warn!(log, "shutdown timed out, hanging up"; "connections" => connections.len());
+                for stream in connections.values() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
             }
-        }
-        Ok(())
-    }
-
-    /// Set signal to shutdown the server.
-    ///
-    /// Probably only used in testing. Required to cleanup test.
-    #[cfg(test)]
-    fn shutdown(&self) -> Result<(), ServerError> {
-        unistd::write(self.signal_fd, &1u64.to_ne_bytes())?;
-        Ok(())
+            drop(connections);
+            result
+        })
     }
 
-    /// Returns address where server is bound to.
+    /// Returns address where server is bound to, the first one when it listens on several.
     ///
     /// Probably only used in testing. Helpful when server address port is set to zero.
     #[cfg(test)]
     fn address(&self) -> io::Result<SocketAddr> {
-        self.listener.local_addr()
+        self.listeners[0].local_addr()
+    }
+}
+
+fn bind_tcp(log: &Logger, address: SocketAddr) -> Result<Listener, ServerError> {
+    debug!(log, "binding TCP listener"; "address" => %address);
+    let listener = TcpListener::bind(address).map_err(ServerError::BindSocketError)?;
+    Ok(Listener::Tcp(listener))
+}
+
+fn bind_unix(log: &Logger, path: &Path, mode: u32) -> Result<Listener, ServerError> {
+    debug!(log, "binding Unix domain socket listener"; "path" => %path.display());
+    Listener::bind_unix(path.to_owned(), mode).map_err(ServerError::BindSocketError)
+}
+
+/// Directory `dir` inside `root`, as long as it doesn't lead out of it.
+///
+/// Absolute paths are accepted when they're under `root`. Symbolic links aren't followed, those
+/// inside `root` are trusted.
+fn backup_dir(root: &Path, dir: &str) -> Option<PathBuf> {
+    let dir = Path::new(dir);
+    let relative = if dir.is_absolute() {
+        dir.strip_prefix(root).ok()?
+    } else {
+        dir
+    };
+    let mut named = false;
+    for component in relative.components() {
+        match component {
+            Component::Normal(_) => named = true,
+            Component::CurDir => {}
+            _ => return None,
+        }
     }
+    named.then(|| root.join(relative))
+}
+
+/// Rewrite the directory of a backup or restore request to its place under `root`. Other
+/// requests are left as they are.
+fn confine_backup(root: Option<&Path>, request: Request) -> Result<Request, Response> {
+    let resolve = |dir: String| {
+        let root = root.ok_or_else(|| {
+            Response::failure(
+                ErrorKind::InvalidRequest,
+                "backups are disabled on this server",
+            )
+        })?;
+        backup_dir(root, &dir)
+            .and_then(|path| path.to_str().map(str::to_owned))
+            .ok_or_else(|| {
+                let message = format!("backup directory `{}` is outside the backup root", dir);
+                Response::failure(ErrorKind::InvalidRequest, message)
+            })
+    };
+    match request {
+        Request::Backup { dir } => Ok(Request::Backup { dir: resolve(dir)? }),
+        Request::Restore { dir } => Ok(Request::Restore { dir: resolve(dir)? }),
+        request => Ok(request),
+    }
+}
+
+/// Check the keys and values of a request against `limits`.
+fn check_limits(limits: &Limits, request: &Request) -> Result<(), LimitError> {
+    match request {
+        Request::Set { key, value } => limits.check_entry(key, value),
+        Request::Get { key } | Request::Rm { key } => limits.check_key(key),
+        Request::Scan { prefix, after, .. } => {
+            limits.check_key(prefix)?;
+            after.as_deref().map_or(Ok(()), |key| limits.check_key(key))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Take a token from the rate limit of `user`, or of the connection when not authenticated.
+/// Always succeeds without a rate limit.
+fn take_token(
+    server: &KvsServer,
+    settings: &Settings,
+    user: &Option<String>,
+    bucket: &mut Option<TokenBucket>,
+) -> bool {
+    let rate = match &settings.load.rate {
+        Some(rate) => rate,
+        None => return true,
+    };
+    match user {
+        Some(user) => server
+            .user_buckets
+            .lock()
+            .unwrap()
+            .entry(user.clone())
+            .or_insert_with(|| TokenBucket::new(rate))
+            .try_take(rate),
+        None => bucket
+            .get_or_insert_with(|| TokenBucket::new(rate))
+            .try_take(rate),
+    }
+}
+
+/// Read and drop what the peer is still sending, for at most `DISCARD_TIMEOUT`.
+///
+/// Closing a socket with unread data resets the connection, which can destroy the last response
+/// before the peer reads it.
+fn discard_input(stream: &Stream) {
+    let deadline = Instant::now() + DISCARD_TIMEOUT;
+    let mut buf = [0; 8192];
+    while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+        // Zero timeouts are rejected, which ends the loop too.
+        if stream.set_read_timeout(Some(timeout)).is_err() {
+            break;
+        }
+        match (&*stream).read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Request read off a connection, waiting for its turn with the handler.
+struct Queued<'a> {
+    id: u64,
+    request: Request,
+    log: Logger,
+    /// Held until the response is on its way to the writer.
+    in_flight: Permit<'a>,
+    write_permit: Option<Permit<'a>>,
+}
+
+/// Serve requests of one connection until the peer hangs up.
+///
+/// Requests reach the handler in the order they arrive, so each sees the writes before it. Work
+/// left once the handler is released, like copying a backup, runs on a thread of its own while
+/// the next requests are handled, and may finish after them. Responses go out as they finish,
+/// tagged with the request ID, those finishing together in a single write. A request over the
+/// server limits is answered with a "too large" failure and ends the connection, so does running
+/// into a timeout.
+fn serve<H>(
+    server: &KvsServer,
+    log: &Logger,
+    stream: &Stream,
+    handler: &Mutex<&mut H>,
+) -> Result<(), ServerError>
+where
+    H: HandleRequest + Send + ?Sized,
+{
+    let settings = server.settings();
+    let max_in_flight = settings.load.max_in_flight.max(1);
+    let in_flight = Semaphore::new(max_in_flight);
+    // Bounded too, so a peer not reading its responses holds up the handling of its requests,
+    // and in turn the reading of new ones.
+    let (sender, receiver) = mpsc::sync_channel::<Envelope<Response>>(max_in_flight);
+    let (queue, requests) = mpsc::sync_channel::<Queued>(max_in_flight);
+    thread::scope(|scope| {
+        let writer = scope.spawn(move || -> Result<(), SerializationError> {
+            let mut writer = BufWriter::new(stream);
+            while let Ok(response) = receiver.recv() {
+                for response in iter::once(response).chain(receiver.try_iter()) {
+                    info!(log, "sending response"; "id" => response.id, "response" => ?response.body);
+                    response.to_writer(&mut writer)?;
+                }
+                writer.flush()?;
+            }
+            Ok(())
+        });
+
+        let worker = {
+            let sender = sender.clone();
+            scope.spawn(move || {
+                for queued in requests {
+                    let Queued {
+                        id,
+                        request,
+                        log,
+                        in_flight,
+                        write_permit,
+                    } = queued;
+                    let result = handler.lock().unwrap().handle(&log, request);
+                    drop(write_permit);
+                    let response = match result {
+                        Ok(Handled::Done(response)) => response,
+                        // The handler is released, other requests go on meanwhile.
+                        Ok(Handled::Deferred(finish)) => {
+                            let sender = sender.clone();
+                            scope.spawn(move || {
+                                let _ = sender.send(Envelope::new(id, finish()));
+                                drop(in_flight);
+                            });
+                            continue;
+                        }
+                        Err(err) => {
+                            error!(log, "error on handling request"; "error" => ?err);
+                            Response::failure(ErrorKind::Storage, err.to_string())
+                        }
+                    };
+                    let _ = sender.send(Envelope::new(id, response));
+                    drop(in_flight);
+                }
+            })
+        };
+
+        let mut log = log.clone();
+        // Authenticated user, when the server has authentication enabled.
+        let mut user: Option<String> = None;
+        let mut too_large = false;
+        // Rate limit of the connection while it's not authenticated.
+        let mut bucket = None;
+        let mut reader = BufReader::new(DeadlineReader::new(stream, settings.timeouts));
+        loop {
+            let settings = server.settings();
+            let limits = &settings.limits;
+            let auth = settings.auth.as_deref();
+            let buffered = !reader.buffer().is_empty();
+            reader.get_mut().next_request(buffered);
+            let result =
+                Envelope::<Request>::from_reader_with_limit(&mut reader, limits.max_frame_size);
+            if let Some(expiry) = reader.get_ref().expired() {
+                info!(log, "connection expired"; "timeout" => ?expiry);
+                server.stats.expired(expiry);
+                break;
+            }
+            match result {
+                Ok(Some(Envelope { id, body: request })) => {
+                    info!(log, "received request"; "id" => id, "request" => ?request);
+                    if let Err(err) = check_limits(limits, &request) {
+                        warn!(log, "request too large"; "id" => id, "error" => %err);
+                        let response = Response::failure(ErrorKind::TooLarge, err.to_string());
+                        let _ = sender.send(Envelope::new(id, response));
+                        too_large = true;
+                        break;
+                    }
+                    if !take_token(server, &settings, &user, &mut bucket) {
+                        debug!(log, "request throttled"; "id" => id);
+                        server.stats.throttled_request();
+                        let response =
+                            Response::failure(ErrorKind::Busy, "rate limited, retry later");
+                        let _ = sender.send(Envelope::new(id, response));
+                        continue;
+                    }
+                    // Handled in order, so requests after an `Auth` see its outcome.
+                    let response = match (&request, auth) {
+                        (Request::Auth { user: name, token }, Some(auth)) => {
+                            if !auth.authenticate(&log, name, &token.0) {
+                                let response = Response::failure(
+                                    ErrorKind::Unauthorized,
+                                    "invalid credentials",
+                                );
+                                let _ = sender.send(Envelope::new(id, response));
+                                break;
+                            }
+                            user = Some(name.clone());
+                            log = log.new(o!("user" => name.clone()));
+                            Some(Response::Success(None))
+                        }
+                        // Backups are read and written on the server host, so only admins may
+                        // ask for them, and there are none without authentication.
+                        (Request::Backup { .. } | Request::Restore { .. }, None) => {
+                            warn!(log, "admin request without authentication"; "id" => id);
+                            Some(Response::failure(
+                                ErrorKind::Unauthorized,
+                                "admin requests need authentication",
+                            ))
+                        }
+                        (Request::Ping, _) | (_, None) => None,
+                        (_, Some(auth)) => match &user {
+                            None => Some(Response::failure(
+                                ErrorKind::Unauthorized,
+                                "authentication required",
+                            )),
+                            Some(user) if !auth.authorize(&log, user, &request) => Some(
+                                Response::failure(ErrorKind::Unauthorized, "permission denied"),
+                            ),
+                            Some(user) => match &request {
+                                Request::Permissions { user: other } => {
+                                    let grants = auth.grants(other.as_deref().unwrap_or(user));
+                                    Some(Response::Permissions(grants.to_vec()))
+                                }
+                                _ => None,
+                            },
+                        },
+                    };
+                    if let Some(response) = response {
+                        let _ = sender.send(Envelope::new(id, response));
+                        continue;
+                    }
+                    // Backup directories come from clients, they mustn't reach outside the root.
+                    let request = match confine_backup(server.backup_root.as_deref(), request) {
+                        Ok(request) => request,
+                        Err(response) => {
+                            warn!(log, "backup directory refused"; "id" => id);
+                            let _ = sender.send(Envelope::new(id, response));
+                            continue;
+                        }
+                    };
+                    // Stop reading requests while too many are waiting, so clients slow down rather
+                    // than queues growing.
+                    let in_flight = in_flight.acquire();
+                    let write_permit = request.is_write().then(|| server.queued_writes.acquire());
+                    let queued = Queued {
+                        id,
+                        request,
+                        log: log.new(o!("id" => id)),
+                        in_flight,
+                        write_permit,
+                    };
+                    if queue.send(queued).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => {
+                    debug!(log, "received eof");
+                    break;
+                }
+                Err(err @ FrameError::TooLarge { id, .. }) => {
+                    warn!(log, "request too large"; "id" => id, "error" => %err);
+                    let response = Response::failure(ErrorKind::TooLarge, err.to_string());
+                    let _ = sender.send(Envelope::new(id, response));
+                    too_large = true;
+                    break;
+                }
+                Err(FrameError::Invalid(err)) => {
+                    // There's no telling where the next request starts, give up on the connection.
+                    error!(log, "received invalid request"; "error" => %err);
+                    let response = Response::failure(ErrorKind::InvalidRequest, "invalid request");
+                    let _ = sender.send(Envelope::new(0, response));
+                    break;
+                }
+            }
+        }
+
+        drop(queue);
+        worker.join().expect("request worker panicked");
+        drop(sender);
+        writer.join().expect("response writer panicked")?;
+        if too_large {
+            // Let the peer read the failure before the connection goes away.
+            let _ = stream.shutdown(Shutdown::Write);
+            discard_input(stream);
+        }
+        Ok(())
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{
-        protocol::{Request, Response},
-        KvStore,
+        protocol::{Request, Response, Secret},
+        server::{AuthConfig, Authenticator, RateLimit},
+        BackupJob, Entries, KvsEngine, MemoryEngine,
     };
     use slog::{o, Discard};
-    use std::{net::TcpStream, sync::Arc, thread::spawn, time::Duration};
+    use std::{
+        net::TcpStream, os::unix::net::UnixStream, sync::Arc, thread::spawn, time::Duration,
+    };
+
+    /// Engine taking its time on every write.
+    struct SlowEngine(MemoryEngine, Duration);
+
+    impl KvsEngine for SlowEngine {
+        fn set(&mut self, key: String, value: String) -> Result<(), KvsEngineError> {
+            thread::sleep(self.1);
+            self.0.set(key, value)
+        }
+
+        fn get(&mut self, key: &str) -> Result<Option<String>, KvsEngineError> {
+            self.0.get(key)
+        }
+
+        fn remove(&mut self, key: &str) -> Result<(), KvsEngineError> {
+            self.0.remove(key)
+        }
+
+        fn scan(&mut self, prefix: &str) -> Result<Entries<'_>, KvsEngineError> {
+            self.0.scan(prefix)
+        }
+
+        fn start_backup(&mut self, _dir: &Path) -> Result<BackupJob, KvsEngineError> {
+            let delay = self.1;
+            Ok(Box::new(move || {
+                thread::sleep(delay);
+                Ok(())
+            }))
+        }
+    }
+
+    /// Bind a server on any port, configured by `configure`, and connect to it.
+    fn bind(configure: impl FnOnce(KvsServer) -> KvsServer) -> (Arc<KvsServer>, TcpStream) {
+        let log = Logger::root(Discard, o!());
+        let address = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+        let server = Arc::new(configure(KvsServer::new(log, address).unwrap()));
+        let client = {
+            let address = server.address().unwrap();
+            TcpStream::connect_timeout(&address, Duration::from_millis(100)).unwrap()
+        };
+        (server, client)
+    }
 
     #[test]
     fn test_can_be_shutdown() {
@@ -228,29 +918,50 @@ mod tests {
         let handle = {
             let server = server.clone();
             spawn(move || {
-                let dir = tempfile::tempdir().unwrap().into_path();
-                let mut engine = KvStore::open(&dir).unwrap();
+                let mut engine = MemoryEngine::new();
                 server.listen(&mut engine).unwrap();
             })
         };
 
         // Shutdown.
-        server.shutdown().unwrap();
+        server.shutdown_handle().shutdown().unwrap();
 
         handle.join().unwrap();
     }
 
+    #[test]
+    fn test_backup_dir() {
+        let root = Path::new("/var/backups/kvs");
+        assert_eq!(backup_dir(root, "nightly/1"), Some(root.join("nightly/1")));
+        assert_eq!(
+            backup_dir(root, "/var/backups/kvs/./nightly"),
+            Some(root.join("./nightly"))
+        );
+        for dir in [
+            "",
+            ".",
+            "../kvs2",
+            "nightly/../..",
+            "/etc",
+            "/var/backups/kvs/../x",
+        ]
+        .iter()
+        {
+            assert_eq!(backup_dir(root, dir), None, "{}", dir);
+        }
+    }
+
     macro_rules! request {
         ($writer:expr, $request:expr) => {
-            $request.to_writer(&mut $writer).unwrap();
+            Envelope::new(1, $request).to_writer(&mut $writer).unwrap();
         };
     }
 
     macro_rules! response {
         ($reader:expr, $response:expr) => {
             assert_eq!(
-                Some($response),
-                Response::from_reader(&mut $reader).unwrap()
+                Some(Envelope::new(1, $response)),
+                Envelope::from_reader(&mut $reader).unwrap()
             );
         };
     }
@@ -270,8 +981,7 @@ mod tests {
         let handle = {
             let server = server.clone();
             spawn(move || {
-                let dir = tempfile::tempdir().unwrap().into_path();
-                let mut engine = KvStore::open(&dir).unwrap();
+                let mut engine = MemoryEngine::new();
                 server.listen(&mut engine).unwrap();
             })
         };
@@ -298,10 +1008,404 @@ mod tests {
         );
         response!(client, Response::Success(Some("value1".to_owned())));
 
+        request!(
+            client,
+            Request::Rm {
+                key: "key2".to_owned(),
+            }
+        );
+        response!(
+            client,
+            Response::failure(ErrorKind::NotFound, "Key not found")
+        );
+
+        request!(
+            client,
+            Request::Scan {
+                prefix: "key".to_owned(),
+                after: None,
+                limit: 10,
+            }
+        );
+        response!(
+            client,
+            Response::Entries(vec![("key1".to_owned(), "value1".to_owned())])
+        );
+
         // Disconnect.
         drop(client);
 
-        server.shutdown().unwrap();
+        server.shutdown_handle().shutdown().unwrap();
+        handle.join().unwrap();
+    }
+
+    /// Requests in flight when shutting down should still be answered.
+    #[test]
+    fn test_shutdown_finishes_requests() {
+        let (server, mut client) = bind(|x| x.with_shutdown_timeout(Duration::from_secs(5)));
+        let handle = {
+            let server = server.clone();
+            spawn(move || {
+                let mut engine = SlowEngine(MemoryEngine::new(), Duration::from_millis(200));
+                server.listen(&mut engine).unwrap();
+            })
+        };
+
+        request!(
+            client,
+            Request::Set {
+                key: "key1".to_owned(),
+                value: "value1".to_owned(),
+            }
+        );
+        thread::sleep(Duration::from_millis(50));
+        server.shutdown_handle().shutdown().unwrap();
+
+        response!(client, Response::Success(None));
+        assert_eq!(
+            Envelope::<Response>::from_reader(&mut client).unwrap(),
+            None
+        );
+        handle.join().unwrap();
+    }
+
+    /// Requests of one connection should be handled in order, a read sees the slow write sent
+    /// before it.
+    #[test]
+    fn test_requests_handled_in_order() {
+        let (server, mut client) = bind(|x| x);
+        let handle = {
+            let server = server.clone();
+            spawn(move || {
+                let mut engine = SlowEngine(MemoryEngine::new(), Duration::from_millis(100));
+                server.listen(&mut engine).unwrap();
+            })
+        };
+
+        let set = Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        };
+        let get = Request::Get {
+            key: "key1".to_owned(),
+        };
+        Envelope::new(1, set).to_writer(&mut client).unwrap();
+        Envelope::new(2, get).to_writer(&mut client).unwrap();
+        assert_eq!(
+            Envelope::from_reader(&mut client).unwrap(),
+            Some(Envelope::new(1, Response::Success(None)))
+        );
+        assert_eq!(
+            Envelope::from_reader(&mut client).unwrap(),
+            Some(Envelope::new(
+                2,
+                Response::Success(Some("value1".to_owned()))
+            ))
+        );
+
+        server.shutdown_handle().shutdown().unwrap();
+        handle.join().unwrap();
+    }
+
+    /// Connections to every listener should be served.
+    #[test]
+    fn test_several_listeners() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kvs.sock");
+        let (server, mut tcp) = bind(|x| x.add_unix(&path, 0o600).unwrap());
+        let handle = {
+            let server = server.clone();
+            spawn(move || {
+                let mut engine = MemoryEngine::new();
+                server.listen(&mut engine).unwrap();
+            })
+        };
+        let mut unix = UnixStream::connect(&path).unwrap();
+
+        request!(
+            tcp,
+            Request::Set {
+                key: "key1".to_owned(),
+                value: "value1".to_owned(),
+            }
+        );
+        response!(tcp, Response::Success(None));
+        request!(
+            unix,
+            Request::Get {
+                key: "key1".to_owned(),
+            }
+        );
+        response!(unix, Response::Success(Some("value1".to_owned())));
+
+        server.shutdown_handle().shutdown().unwrap();
+        handle.join().unwrap();
+    }
+
+    /// Pings should count against the rate limit like any other request.
+    #[test]
+    fn test_pings_are_rate_limited() {
+        let load = LoadLimits {
+            rate: Some(RateLimit {
+                per_second: 0.1,
+                burst: 1.0,
+            }),
+            ..LoadLimits::default()
+        };
+        let (server, mut client) = bind(|x| x.with_load_limits(load));
+        let handle = {
+            let server = server.clone();
+            spawn(move || {
+                let mut engine = MemoryEngine::new();
+                server.listen(&mut engine).unwrap();
+            })
+        };
+
+        request!(client, Request::Ping);
+        response!(client, Response::Success(None));
+        request!(client, Request::Ping);
+        response!(
+            client,
+            Response::failure(ErrorKind::Busy, "rate limited, retry later")
+        );
+
+        server.shutdown_handle().shutdown().unwrap();
+        handle.join().unwrap();
+    }
+
+    /// A request should be answered while an earlier one of the connection is still copying a
+    /// backup.
+    #[test]
+    fn test_slow_request_is_overtaken() {
+        let config = AuthConfig::parse(
+            r#"
+            [users.root]
+            token = "r"
+            permissions = [{ prefix = "", access = ["admin"] }]
+            "#,
+        )
+        .unwrap();
+        let (server, mut client) = bind(|x| {
+            x.with_auth(Authenticator::new(&config))
+                .with_backup_root("backups")
+        });
+        let handle = {
+            let server = server.clone();
+            spawn(move || {
+                let mut engine = SlowEngine(MemoryEngine::new(), Duration::from_millis(500));
+                server.listen(&mut engine).unwrap();
+            })
+        };
+        let auth = Request::Auth {
+            user: "root".to_owned(),
+            token: Secret("r".to_owned()),
+        };
+        request!(client, auth);
+        response!(client, Response::Success(None));
+
+        let backup = Request::Backup {
+            dir: "backup".to_owned(),
+        };
+        let get = Request::Get {
+            key: "key".to_owned(),
+        };
+        Envelope::new(2, backup).to_writer(&mut client).unwrap();
+        Envelope::new(3, get).to_writer(&mut client).unwrap();
+        let start = Instant::now();
+        assert_eq!(
+            Envelope::from_reader(&mut client).unwrap(),
+            Some(Envelope::new(3, Response::Success(None)))
+        );
+        assert!(start.elapsed() < Duration::from_millis(300));
+        assert_eq!(
+            Envelope::from_reader(&mut client).unwrap(),
+            Some(Envelope::new(2, Response::Success(None)))
+        );
+
+        server.shutdown_handle().shutdown().unwrap();
+        handle.join().unwrap();
+    }
+
+    /// Other connections should be served while a backup is copying.
+    #[test]
+    fn test_backup_doesnt_block_other_connections() {
+        let config = AuthConfig::parse(
+            r#"
+            [users.root]
+            token = "r"
+            permissions = [{ prefix = "", access = ["admin"] }]
+            "#,
+        )
+        .unwrap();
+        let (server, mut admin) = bind(|x| {
+            x.with_auth(Authenticator::new(&config))
+                .with_backup_root("backups")
+        });
+        let handle = {
+            let server = server.clone();
+            spawn(move || {
+                let mut engine = SlowEngine(MemoryEngine::new(), Duration::from_millis(500));
+                server.listen(&mut engine).unwrap();
+            })
+        };
+        let auth = Request::Auth {
+            user: "root".to_owned(),
+            token: Secret("r".to_owned()),
+        };
+        let mut other = TcpStream::connect(server.address().unwrap()).unwrap();
+        for mut client in [&admin, &other].iter().copied() {
+            request!(client, auth.clone());
+            response!(client, Response::Success(None));
+        }
+
+        request!(
+            admin,
+            Request::Backup {
+                dir: "backup".to_owned()
+            }
+        );
+        thread::sleep(Duration::from_millis(50));
+        let start = Instant::now();
+        request!(
+            other,
+            Request::Get {
+                key: "key".to_owned()
+            }
+        );
+        response!(other, Response::Success(None));
+        assert!(start.elapsed() < Duration::from_millis(300));
+        response!(admin, Response::Success(None));
+
+        server.shutdown_handle().shutdown().unwrap();
+        handle.join().unwrap();
+    }
+
+    /// Requests still running at the shutdown deadline should lose their connection.
+    #[test]
+    fn test_shutdown_timeout() {
+        let (server, mut client) = bind(|x| x.with_shutdown_timeout(Duration::from_millis(50)));
+        let handle = {
+            let server = server.clone();
+            spawn(move || {
+                let mut engine = SlowEngine(MemoryEngine::new(), Duration::from_secs(1));
+                server.listen(&mut engine).unwrap();
+            })
+        };
+
+        request!(
+            client,
+            Request::Set {
+                key: "key1".to_owned(),
+                value: "value1".to_owned(),
+            }
+        );
+        thread::sleep(Duration::from_millis(50));
+        server.shutdown_handle().shutdown().unwrap();
+
+        assert!(!matches!(
+            Envelope::<Response>::from_reader(&mut client),
+            Ok(Some(_))
+        ));
+        handle.join().unwrap();
+    }
+
+    /// Serve with a memory engine until shut down, then return the server stats.
+    fn listen(server: &Arc<KvsServer>) -> thread::JoinHandle<ServerStats> {
+        let server = server.clone();
+        spawn(move || {
+            let mut engine = MemoryEngine::new();
+            server.listen(&mut engine).unwrap();
+            server.stats()
+        })
+    }
+
+    /// Connections should be closed once they run into a timeout.
+    #[test]
+    fn test_timeouts() {
+        let timeouts = Timeouts {
+            idle: Some(Duration::from_millis(200)),
+            request: Some(Duration::from_millis(100)),
+            lifetime: Some(Duration::from_millis(500)),
+        };
+        let (server, mut idle) = bind(|x| x.with_timeouts(timeouts));
+        let handle = listen(&server);
+        let address = server.address().unwrap();
+
+        // Half a request.
+        let mut slow = TcpStream::connect(address).unwrap();
+        slow.write_all(&1u64.to_le_bytes()).unwrap();
+
+        // Keeps busy past its lifetime.
+        let mut busy = TcpStream::connect(address).unwrap();
+        let started = Instant::now();
+        let expired = loop {
+            let ping = Envelope::new(1, Request::Ping);
+            if ping.to_writer(&mut busy).is_err()
+                || !matches!(Envelope::<Response>::from_reader(&mut busy), Ok(Some(_)))
+            {
+                break started.elapsed();
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        assert!(expired >= Duration::from_millis(500));
+        assert!(expired < Duration::from_secs(2));
+
+        for client in [&mut idle, &mut slow] {
+            assert!(!matches!(
+                Envelope::<Response>::from_reader(client),
+                Ok(Some(_))
+            ));
+        }
+
+        server.shutdown_handle().shutdown().unwrap();
+        let stats = handle.join().unwrap();
+        assert_eq!(stats.connections, 3);
+        assert_eq!(stats.idle_timeouts, 1);
+        assert_eq!(stats.request_timeouts, 1);
+        assert_eq!(stats.lifetime_expirations, 1);
+    }
+
+    /// A length prefix over the frame limit should get a "too large" failure, not an allocation.
+    #[test]
+    fn test_huge_length_prefix() {
+        let server = {
+            let log = Logger::root(Discard, o!());
+            let address = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+            let server = KvsServer::new(log, address).unwrap();
+            Arc::new(server)
+        };
+
+        let handle = {
+            let server = server.clone();
+            spawn(move || {
+                let mut engine = MemoryEngine::new();
+                server.listen(&mut engine).unwrap();
+            })
+        };
+
+        let mut client = {
+            let address = server.address().unwrap();
+            TcpStream::connect_timeout(&address, Duration::from_millis(100)).unwrap()
+        };
+
+        // Set with a key claiming to be 1 TiB long.
+        1u64.to_writer(&mut client).unwrap();
+        0u32.to_writer(&mut client).unwrap();
+        (1u64 << 40).to_writer(&mut client).unwrap();
+        match Envelope::<Response>::from_reader(&mut client).unwrap() {
+            Some(Envelope {
+                id: 1,
+                body: Response::Failure { kind, .. },
+            }) => assert_eq!(kind, ErrorKind::TooLarge),
+            response => panic!("unexpected response {:?}", response),
+        }
+        // Then the server hangs up.
+        assert_eq!(
+            Envelope::<Response>::from_reader(&mut client).unwrap(),
+            None
+        );
+
+        server.shutdown_handle().shutdown().unwrap();
         handle.join().unwrap();
     }
 }
@@ -0,0 +1,65 @@
+//! Counters of what a server went through.
+
+use super::timeouts::Expiry;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counts since a server was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ServerStats {
+    /// Connections accepted.
+    pub connections: u64,
+    /// Connections closed right away for going over the connection limits.
+    pub rejected_connections: u64,
+    /// Requests answered with "busy" for going over the rate limit.
+    pub throttled_requests: u64,
+    /// Connections closed for waiting too long between requests.
+    pub idle_timeouts: u64,
+    /// Connections closed for taking too long to send a request.
+    pub request_timeouts: u64,
+    /// Connections closed for being open too long.
+    pub lifetime_expirations: u64,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Stats {
+    connections: AtomicU64,
+    rejected_connections: AtomicU64,
+    throttled_requests: AtomicU64,
+    idle_timeouts: AtomicU64,
+    request_timeouts: AtomicU64,
+    lifetime_expirations: AtomicU64,
+}
+
+impl Stats {
+    pub(super) fn connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn rejected_connection(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn throttled_request(&self) {
+        self.throttled_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn expired(&self, expiry: Expiry) {
+        let counter = match expiry {
+            Expiry::Idle => &self.idle_timeouts,
+            Expiry::Request => &self.request_timeouts,
+            Expiry::Lifetime => &self.lifetime_expirations,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn snapshot(&self) -> ServerStats {
+        ServerStats {
+            connections: self.connections.load(Ordering::Relaxed),
+            rejected_connections: self.rejected_connections.load(Ordering::Relaxed),
+            throttled_requests: self.throttled_requests.load(Ordering::Relaxed),
+            idle_timeouts: self.idle_timeouts.load(Ordering::Relaxed),
+            request_timeouts: self.request_timeouts.load(Ordering::Relaxed),
+            lifetime_expirations: self.lifetime_expirations.load(Ordering::Relaxed),
+        }
+    }
+}
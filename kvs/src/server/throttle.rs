@@ -0,0 +1,216 @@
+//! Caps on how much load clients can put on a server.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Condvar, Mutex},
+    time::Instant,
+};
+
+/// Requests allowed per second, with bursts of up to `burst` requests.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+/// Caps on connections and requests, `None` for no cap.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoadLimits {
+    /// Connections open at once. Every connection takes three threads, plus one for each backup
+    /// it's copying.
+    ///
+    /// Connections over this or `max_connections_per_ip` are answered with a "busy" failure and
+    /// closed. With TLS, TCP connections are closed without an answer instead, it would take a
+    /// handshake on the thread accepting connections.
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    /// Rate of requests of a connection, or of an authenticated user across their connections,
+    /// pings included. Requests over it are answered with a "busy" failure.
+    pub rate: Option<RateLimit>,
+    /// Requests of a connection queued or being handled, at least one. No more requests are read
+    /// off the connection until one is answered.
+    pub max_in_flight: usize,
+    /// Writes waiting on the engine at once across connections, at least one. No more requests are
+    /// read off a connection with a write to queue until one finishes.
+    pub max_queued_writes: usize,
+}
+
+impl Default for LoadLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: Some(256),
+            max_connections_per_ip: None,
+            rate: None,
+            max_in_flight: 64,
+            max_queued_writes: 256,
+        }
+    }
+}
+
+/// Token bucket, full when created.
+#[derive(Debug)]
+pub(super) struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub(super) fn new(rate: &RateLimit) -> Self {
+        Self {
+            tokens: rate.burst,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take a token if there's one left.
+    pub(super) fn try_take(&mut self, rate: &RateLimit) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_second).min(rate.burst);
+        self.updated = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Connections open, overall and by client address.
+#[derive(Debug, Default)]
+pub(super) struct Admission {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+impl Admission {
+    /// Count a new connection in, or say why it's over the limits.
+    pub(super) fn admit(
+        &mut self,
+        limits: &LoadLimits,
+        ip: Option<IpAddr>,
+    ) -> Result<(), &'static str> {
+        if limits.max_connections.is_some_and(|max| self.total >= max) {
+            return Err("too many connections");
+        }
+        if let Some(ip) = ip {
+            let count = self.by_ip.entry(ip).or_default();
+            if limits
+                .max_connections_per_ip
+                .is_some_and(|max| *count >= max)
+            {
+                return Err("too many connections from this address");
+            }
+            *count += 1;
+        }
+        self.total += 1;
+        Ok(())
+    }
+
+    /// Count out a connection previously admitted.
+    pub(super) fn release(&mut self, ip: Option<IpAddr>) {
+        self.total -= 1;
+        if let Some(ip) = ip {
+            if let Some(count) = self.by_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    self.by_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+/// Counting semaphore.
+#[derive(Debug)]
+pub(super) struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    pub(super) fn new(permits: usize) -> Self {
+        Self {
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait for a permit, held until the returned guard is dropped.
+    pub(super) fn acquire(&self) -> Permit<'_> {
+        let available = self.available.lock().unwrap();
+        let mut available = self.released.wait_while(available, |x| *x == 0).unwrap();
+        *available -= 1;
+        Permit(self)
+    }
+}
+
+#[must_use]
+pub(super) struct Permit<'a>(&'a Semaphore);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.available.lock().unwrap() += 1;
+        self.0.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread, time::Duration};
+
+    #[test]
+    fn test_token_bucket() {
+        let rate = RateLimit {
+            per_second: 10.0,
+            burst: 3.0,
+        };
+        let mut bucket = TokenBucket::new(&rate);
+        assert!((0..3).all(|_| bucket.try_take(&rate)));
+        assert!(!bucket.try_take(&rate));
+
+        thread::sleep(Duration::from_millis(150));
+        assert!(bucket.try_take(&rate));
+    }
+
+    #[test]
+    fn test_admission() {
+        let limits = LoadLimits {
+            max_connections: Some(3),
+            max_connections_per_ip: Some(2),
+            ..LoadLimits::default()
+        };
+        let a = Some("10.0.0.1".parse().unwrap());
+        let b = Some("10.0.0.2".parse().unwrap());
+        let mut admission = Admission::default();
+
+        assert!(admission.admit(&limits, a).is_ok());
+        assert!(admission.admit(&limits, a).is_ok());
+        assert!(admission.admit(&limits, a).is_err());
+        assert!(admission.admit(&limits, b).is_ok());
+        assert!(admission.admit(&limits, None).is_err());
+
+        admission.release(a);
+        assert!(admission.admit(&limits, a).is_ok());
+    }
+
+    #[test]
+    fn test_semaphore() {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let permit = semaphore.acquire();
+
+        let waiter = {
+            let semaphore = semaphore.clone();
+            thread::spawn(move || {
+                let _permit = semaphore.acquire();
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+
+        drop(permit);
+        waiter.join().unwrap();
+    }
+}
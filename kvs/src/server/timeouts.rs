@@ -0,0 +1,126 @@
+//! Deadlines on reading requests off a connection.
+
+use crate::transport::Stream;
+use std::{
+    io::{self, Read},
+    time::{Duration, Instant},
+};
+
+/// How long connections may take, `None` for no limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timeouts {
+    /// Longest wait for the next request to start.
+    pub idle: Option<Duration>,
+    /// Longest time from the first to the last byte of a request.
+    pub request: Option<Duration>,
+    /// Longest a connection stays open. Requests already read are still answered.
+    pub lifetime: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            idle: Some(Duration::from_secs(5 * 60)),
+            request: Some(Duration::from_secs(30)),
+            lifetime: None,
+        }
+    }
+}
+
+/// Which timeout a connection ran into.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Expiry {
+    Idle,
+    Request,
+    Lifetime,
+}
+
+/// Reads a connection, failing once the deadline of the current phase passes.
+pub(super) struct DeadlineReader<'a> {
+    stream: &'a Stream,
+    timeouts: Timeouts,
+    opened: Instant,
+    /// When the first byte of the request being read came in, `None` between requests.
+    request_started: Option<Instant>,
+    expired: Option<Expiry>,
+}
+
+impl<'a> DeadlineReader<'a> {
+    pub(super) fn new(stream: &'a Stream, timeouts: Timeouts) -> Self {
+        Self {
+            stream,
+            timeouts,
+            opened: Instant::now(),
+            request_started: None,
+            expired: None,
+        }
+    }
+
+    /// Start reading the next request, `started` when some of it is buffered already.
+    pub(super) fn next_request(&mut self, started: bool) {
+        self.request_started = if started { Some(Instant::now()) } else { None };
+    }
+
+    /// Timeout the last failed read ran into, if that's why it failed.
+    pub(super) fn expired(&self) -> Option<Expiry> {
+        self.expired
+    }
+
+    fn deadline(&self) -> Option<(Instant, Expiry)> {
+        let phase = match self.request_started {
+            Some(started) => self
+                .timeouts
+                .request
+                .map(|x| (started + x, Expiry::Request)),
+            None => self
+                .timeouts
+                .idle
+                .map(|x| (Instant::now() + x, Expiry::Idle)),
+        };
+        let lifetime = self
+            .timeouts
+            .lifetime
+            .map(|x| (self.opened + x, Expiry::Lifetime));
+        match (phase, lifetime) {
+            (Some(phase), Some(lifetime)) if lifetime.0 < phase.0 => Some(lifetime),
+            (phase, lifetime) => phase.or(lifetime),
+        }
+    }
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = self.deadline();
+        let timeout = match deadline {
+            Some((at, expiry)) => match at.checked_duration_since(Instant::now()) {
+                Some(timeout) if !timeout.is_zero() => Some(timeout),
+                _ => {
+                    self.expired = Some(expiry);
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+            },
+            None => None,
+        };
+        self.stream.set_read_timeout(timeout)?;
+
+        let mut stream = self.stream;
+        match stream.read(buf) {
+            Ok(n) => {
+                if n > 0 && self.request_started.is_none() {
+                    self.request_started = Some(Instant::now());
+                }
+                Ok(n)
+            }
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                self.expired = deadline.map(|(_, expiry)| expiry);
+                Err(err)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Copying logs out of and back into a store directory.
+//!
+//! The log is append-only between compactions, so every prefix of it ending on a record boundary
+//! is a consistent state of the store. A backup captures the log length once and copies up to it,
+//! whatever gets appended meanwhile is left out.
+
+use super::{log_path, maintenance, KvStoreError};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read},
+    path::Path,
+};
+
+/// Copy the first `len` bytes of `src` to the log file of `dir`.
+///
+/// Written to a temporary file first and renamed once synced, so `dir` never holds a partial log.
+fn copy_log(src: impl Read, len: u64, dir: &Path) -> Result<(), KvStoreError> {
+    fs::create_dir_all(dir)?;
+    let path = log_path(dir);
+    let tmp_path = path.with_extension("kvs.tmp");
+    let mut tmp_file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    let copied = io::copy(&mut src.take(len), &mut tmp_file)?;
+    if copied != len {
+        return Err(
+            io::Error::new(io::ErrorKind::UnexpectedEof, "log shrank while copying").into(),
+        );
+    }
+    tmp_file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+/// Fail unless the log in `dir` passes verification.
+fn check(dir: &Path) -> Result<(), KvStoreError> {
+    let report = maintenance::verify(dir)?;
+    match report.corrupt.first() {
+        Some(&(offset, _)) => Err(KvStoreError::Corrupted { offset }),
+        None => Ok(()),
+    }
+}
+
+/// Copy the first `len` bytes of the store log opened as `log_file` into `backup_dir`.
+pub(super) fn backup(log_file: File, len: u64, backup_dir: &Path) -> Result<(), KvStoreError> {
+    copy_log(log_file, len, backup_dir)?;
+    check(backup_dir)
+}
+
+/// Replace the log of the store in `store_dir` with the one in `backup_dir`.
+///
+/// The backup is verified before anything in `store_dir` is touched. The store must not be open,
+/// use [`KvStore::restore_from`](super::KvStore::restore_from) to restore an open store.
+pub fn restore(backup_dir: &Path, store_dir: &Path) -> Result<(), KvStoreError> {
+    if !backup_dir.is_dir() {
+        return Err(KvStoreError::InvalidPath);
+    }
+    check(backup_dir)?;
+    let log_file = File::open(log_path(backup_dir))?;
+    let len = log_file.metadata()?.len();
+    copy_log(log_file, len, store_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::KvStore;
+
+    #[test]
+    fn test_backup_excludes_later_writes() {
+        let store_dir = tempfile::tempdir().unwrap();
+        let backup_dir = tempfile::tempdir().unwrap();
+
+        let mut store = KvStore::open(store_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        store.backup_to(backup_dir.path()).unwrap();
+        store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+        store.remove("key1".to_owned()).unwrap();
+
+        store.restore_from(backup_dir.path()).unwrap();
+        assert_eq!(
+            store.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+        assert_eq!(store.get("key2".to_owned()).unwrap(), None);
+
+        // The restored log should survive reopening.
+        drop(store);
+        let store = KvStore::open(store_dir.path()).unwrap();
+        assert_eq!(
+            store.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_backup_survives_compaction_while_copying() {
+        let store_dir = tempfile::tempdir().unwrap();
+        let backup_dir = tempfile::tempdir().unwrap();
+
+        let mut store = KvStore::open(store_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        let job = store.start_backup(backup_dir.path()).unwrap();
+        store.remove("key1".to_owned()).unwrap();
+        store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+        store.compact().unwrap();
+        job().unwrap();
+
+        store.restore_from(backup_dir.path()).unwrap();
+        assert_eq!(
+            store.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+        assert_eq!(store.get("key2".to_owned()).unwrap(), None);
+    }
+}
@@ -1,21 +1,25 @@
 use super::serialization::Serializable;
-use super::{command::Command, KvStoreError};
+use super::{command::Command, index::Index, KvStoreError};
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::Seek,
+    fs::{self, File, OpenOptions},
     io::{BufReader, BufWriter, SeekFrom},
+    io::{Seek, Write},
+    path::Path,
 };
 
 /// Compact log.
 ///
-/// Compact log by overwriting the log file with known valid entries from log index.
-/// `log_file` must have read and write access.
+/// Compact log by writing known valid entries from log index into a new file and renaming it over
+/// `log_path`. Returns the new log file opened for read and write.
+///
+/// The old file isn't overwritten, handles opened on it before the rename, like the ones held by
+/// snapshots, keep reading the old content. Its space is reclaimed once the last of them closes.
 pub fn compact(
-    log_file: &mut File,
-    log_index: &HashMap<String, u64>,
-) -> Result<(), super::KvStoreError> {
-    let mut reader = BufReader::new(&*log_file);
+    log_file: &File,
+    log_index: &Index,
+    log_path: &Path,
+) -> Result<File, super::KvStoreError> {
+    let mut reader = BufReader::new(log_file);
     let mut commands = Vec::with_capacity(log_index.len());
     for (_, &offset) in log_index.iter() {
         reader.seek(SeekFrom::Start(offset))?;
@@ -28,17 +32,24 @@ pub fn compact(
         }
     }
 
-    let mut writer = BufWriter::new(&*log_file);
-    writer.seek(SeekFrom::Start(0))?;
+    let compacted_path = log_path.with_extension("kvs.compact");
+    let compacted_file = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .read(true)
+        .write(true)
+        .open(&compacted_path)?;
+    let mut writer = BufWriter::new(&compacted_file);
     for cmd in commands.iter() {
         cmd.serialize_into(&mut writer)?;
     }
-    let size = writer.seek(SeekFrom::Current(0))?;
+    writer.flush()?;
+    drop(writer);
+    compacted_file.sync_data()?;
 
-    log_file.set_len(size)?;
-    log_file.sync_data()?;
+    fs::rename(&compacted_path, log_path)?;
 
-    Ok(())
+    Ok(compacted_file)
 }
 
 #[cfg(test)]
@@ -49,7 +60,7 @@ mod tests {
 
     #[test]
     fn test_compact() {
-        let commands = vec![
+        let commands = [
             Command::Set(Set {
                 key: "key0".to_owned(),
                 value: "value0".to_owned(),
@@ -63,7 +74,15 @@ mod tests {
             }),
         ];
 
-        let mut log_file = tempfile::tempfile().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("log.kvs");
+        let log_file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&log_path)
+            .unwrap();
         for cmd in commands.iter() {
             cmd.serialize_into(&log_file).unwrap();
         }
@@ -71,13 +90,12 @@ mod tests {
         let mut reader = BufReader::new(&log_file);
         let log_index = build_index(&mut reader).unwrap();
 
-        compact(&mut log_file, &log_index).unwrap();
+        let log_file = compact(&log_file, &log_index, &log_path).unwrap();
 
         let mut reader = BufReader::new(&log_file);
-        reader.seek(SeekFrom::Start(0)).unwrap();
         let log_index = build_index(&mut reader).unwrap();
 
-        let mut expected = HashMap::new();
+        let mut expected = Index::new();
         expected.insert("key1".to_owned(), 0);
         assert_eq!(log_index, expected);
     }
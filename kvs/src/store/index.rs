@@ -1,18 +1,21 @@
 use super::command::Command;
 use super::serialization::Serializable;
 use std::{
-    collections::HashMap,
+    collections::BTreeMap,
     io::{BufRead, Seek, SeekFrom},
 };
 
-pub fn build_index<T>(reader: &mut T) -> Result<HashMap<String, u64>, super::KvStoreError>
+/// Log offset of the last set of every live key, sorted by key so scans can start anywhere.
+pub type Index = BTreeMap<String, u64>;
+
+pub fn build_index<T>(reader: &mut T) -> Result<Index, super::KvStoreError>
 where
     T: BufRead + Seek,
 {
     reader.seek(SeekFrom::Start(0))?;
-    let mut index = HashMap::new();
+    let mut index = Index::new();
     loop {
-        let offset = reader.seek(SeekFrom::Current(0))?;
+        let offset = reader.stream_position()?;
 
         // Check EOF
         let buf = reader.fill_buf()?;
@@ -79,7 +82,7 @@ mod tests {
 
         let size = {
             let mut buf = Vec::new();
-            commands.get(0).unwrap().serialize_into(&mut buf).unwrap();
+            commands.first().unwrap().serialize_into(&mut buf).unwrap();
             buf.len() as u64
         };
         assert_eq!(index.get("key1"), Some(&size));
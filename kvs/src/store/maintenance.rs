@@ -0,0 +1,310 @@
+//! Offline maintenance of a store directory.
+//!
+//! These work on the raw log file instead of going through [`KvStore`](super::KvStore), so they
+//! are still usable when the log is too damaged for the store to open.
+
+use super::{command::Command, log_path, serialization::Serializable, KvStoreError};
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+/// A region of the log.
+#[derive(Debug)]
+pub enum Entry {
+    /// A readable command.
+    Record {
+        offset: u64,
+        size: u64,
+        command: Command,
+    },
+    /// Bytes that don't deserialize into a command.
+    Corrupt { offset: u64, size: u64 },
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Number of readable records.
+    pub records: u64,
+    /// Offset and size of every corrupted region.
+    pub corrupt: Vec<(u64, u64)>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupt.is_empty()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Number of records in the log, live or not.
+    pub records: u64,
+    pub live_keys: u64,
+    /// Bytes taken by records the index points to.
+    pub live_bytes: u64,
+    /// Bytes compaction would reclaim, corrupted regions included.
+    pub dead_bytes: u64,
+    pub log_file_size: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// Number of records written to the rebuilt log.
+    pub records: u64,
+    /// Number of corrupted bytes left out of the rebuilt log.
+    pub dropped_bytes: u64,
+}
+
+fn read_log(dir: &Path) -> Result<Vec<u8>, KvStoreError> {
+    if !dir.is_dir() {
+        return Err(KvStoreError::InvalidPath);
+    }
+    match fs::read(log_path(dir)) {
+        Ok(data) => Ok(data),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Decode the record at the start of `data`.
+fn decode(data: &[u8]) -> Option<(Command, u64)> {
+    Command::deserialize_from_slice(data).ok()
+}
+
+/// Split log content into records and corrupted regions.
+///
+/// Bincode doesn't put markers between records, so after a corrupted record we move forward one
+/// byte at a time. Bytes inside a corrupted region can happen to deserialize, so reading only
+/// resumes at a record followed by the end of the log or by another record that deserializes.
+fn scan(data: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    let mut corrupt_since = None;
+    while offset < data.len() {
+        let record = decode(&data[offset..]).filter(|(_, size)| {
+            let next = offset + *size as usize;
+            corrupt_since.is_none() || next == data.len() || decode(&data[next..]).is_some()
+        });
+        match record {
+            Some((command, size)) => {
+                if let Some(start) = corrupt_since.take() {
+                    entries.push(Entry::Corrupt {
+                        offset: start as u64,
+                        size: (offset - start) as u64,
+                    });
+                }
+                entries.push(Entry::Record {
+                    offset: offset as u64,
+                    size,
+                    command,
+                });
+                offset += size as usize;
+            }
+            None => {
+                corrupt_since.get_or_insert(offset);
+                offset += 1;
+            }
+        }
+    }
+    if let Some(start) = corrupt_since {
+        entries.push(Entry::Corrupt {
+            offset: start as u64,
+            size: (data.len() - start) as u64,
+        });
+    }
+    entries
+}
+
+/// Read every entry in the log.
+pub fn dump(dir: &Path) -> Result<Vec<Entry>, KvStoreError> {
+    let data = read_log(dir)?;
+    Ok(scan(&data))
+}
+
+/// Walk every record in the log and report corrupted regions.
+pub fn verify(dir: &Path) -> Result<VerifyReport, KvStoreError> {
+    let data = read_log(dir)?;
+    let mut report = VerifyReport::default();
+    for entry in scan(&data) {
+        match entry {
+            Entry::Record { .. } => report.records += 1,
+            Entry::Corrupt { offset, size } => report.corrupt.push((offset, size)),
+        }
+    }
+    Ok(report)
+}
+
+/// Replay the log and account for live and dead bytes.
+pub fn stats(dir: &Path) -> Result<Stats, KvStoreError> {
+    let data = read_log(dir)?;
+    let mut stats = Stats {
+        log_file_size: data.len() as u64,
+        ..Stats::default()
+    };
+    let mut live = HashMap::new();
+    for entry in scan(&data) {
+        if let Entry::Record { size, command, .. } = entry {
+            stats.records += 1;
+            match command {
+                Command::Set(set) => {
+                    live.insert(set.key, size);
+                }
+                Command::Rm(rm) => {
+                    live.remove(&rm.key);
+                }
+            }
+        }
+    }
+    stats.live_keys = live.len() as u64;
+    stats.live_bytes = live.values().sum();
+    stats.dead_bytes = stats.log_file_size - stats.live_bytes;
+    Ok(stats)
+}
+
+/// Rebuild the log from its readable records, dropping corrupted regions.
+///
+/// Records are kept in their original order so the replayed state stays the same. The rebuilt log
+/// is written next to the old one and renamed over it once synced.
+pub fn repair(dir: &Path) -> Result<RepairReport, KvStoreError> {
+    let data = read_log(dir)?;
+    let entries = scan(&data);
+
+    let mut report = RepairReport::default();
+    for entry in entries.iter() {
+        match entry {
+            Entry::Record { .. } => report.records += 1,
+            Entry::Corrupt { size, .. } => report.dropped_bytes += size,
+        }
+    }
+    if report.dropped_bytes == 0 {
+        return Ok(report);
+    }
+
+    let path = log_path(dir);
+    let repaired_path = path.with_extension("kvs.repair");
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&repaired_path)?;
+    let mut writer = BufWriter::new(&file);
+    for entry in entries.iter() {
+        if let Entry::Record { command, .. } = entry {
+            command.serialize_into(&mut writer)?;
+        }
+    }
+    writer.flush()?;
+    drop(writer);
+    file.sync_all()?;
+    fs::rename(&repaired_path, &path)?;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::store::command::*;
+
+    fn write_log(dir: &Path, commands: &[Command]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for cmd in commands {
+            cmd.serialize_into(&mut data).unwrap();
+        }
+        fs::write(log_path(dir), &data).unwrap();
+        data
+    }
+
+    fn set(key: &str, value: &str) -> Command {
+        Command::Set(Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        })
+    }
+
+    #[test]
+    fn test_verify_and_repair_corrupted_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut data = write_log(
+            dir.path(),
+            &[
+                set("key0", "value0"),
+                set("key1", "value1"),
+                set("key2", "value2"),
+            ],
+        );
+
+        let report = verify(dir.path()).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.records, 3);
+
+        // Clobber the enum tag of the second record.
+        let record_size = data.len() / 3;
+        data[record_size] = 0xff;
+        fs::write(log_path(dir.path()), &data).unwrap();
+
+        let report = verify(dir.path()).unwrap();
+        assert_eq!(report.records, 2);
+        assert_eq!(
+            report.corrupt,
+            vec![(record_size as u64, record_size as u64)]
+        );
+
+        let report = repair(dir.path()).unwrap();
+        assert_eq!(report.records, 2);
+        assert_eq!(report.dropped_bytes, record_size as u64);
+
+        let report = verify(dir.path()).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.records, 2);
+    }
+
+    #[test]
+    fn test_resync_skips_lone_records_in_corrupted_region() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut data = Vec::new();
+        set("key0", "value0").serialize_into(&mut data).unwrap();
+        let corrupt_start = data.len();
+        data.push(0xff);
+        // Deserializes, but only garbage follows it.
+        set("junk", "junk").serialize_into(&mut data).unwrap();
+        data.push(0xff);
+        let corrupt_end = data.len();
+        set("key1", "value1").serialize_into(&mut data).unwrap();
+        set("key2", "value2").serialize_into(&mut data).unwrap();
+        fs::write(log_path(dir.path()), &data).unwrap();
+
+        let report = verify(dir.path()).unwrap();
+        assert_eq!(report.records, 3);
+        assert_eq!(
+            report.corrupt,
+            vec![(corrupt_start as u64, (corrupt_end - corrupt_start) as u64)]
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = write_log(
+            dir.path(),
+            &[
+                set("key0", "value0"),
+                set("key0", "value1"),
+                Command::Rm(Rm {
+                    key: "key0".to_owned(),
+                }),
+                set("key1", "value1"),
+            ],
+        );
+
+        let stats = stats(dir.path()).unwrap();
+        assert_eq!(stats.records, 4);
+        assert_eq!(stats.live_keys, 1);
+        assert_eq!(stats.log_file_size, data.len() as u64);
+        assert_eq!(stats.live_bytes + stats.dead_bytes, stats.log_file_size);
+    }
+}
@@ -1,22 +1,44 @@
+pub mod backup;
 mod command;
 mod compaction;
 mod index;
+pub mod maintenance;
 mod serialization;
+mod snapshot;
 
+use crate::engine::{prefix_range, BackupJob, Entries};
+use crate::limits::{LimitError, Limits};
 use crate::KvsEngine;
 use crate::KvsEngineError;
-use command::*;
-use index::build_index;
+use index::{build_index, Index};
+use maintenance::VerifyReport;
 use serialization::Serializable;
 use std::{
-    collections::HashMap,
     fs::{File, OpenOptions},
     io::{self, BufReader, SeekFrom},
     io::{BufWriter, Seek},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 use thiserror::Error;
 
+pub use command::{Command, Rm, Set};
+pub use snapshot::Snapshot;
+
+const LOG_FILE_NAME: &str = "log.kvs";
+
+fn log_path(dir: &Path) -> PathBuf {
+    dir.join(LOG_FILE_NAME)
+}
+
+fn open_log(dir: &Path) -> Result<File, io::Error> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(log_path(dir))
+}
+
 #[derive(Error, Debug)]
 pub enum KvStoreError {
     #[error(transparent)]
@@ -34,6 +56,12 @@ pub enum KvStoreError {
     #[error("Index is desynced/corrupted")]
     IndexDesynced,
 
+    #[error("Log is corrupted at offset {offset}")]
+    Corrupted { offset: u64 },
+
+    #[error(transparent)]
+    TooLarge(#[from] LimitError),
+
     #[error("TODO")]
     TODO,
 }
@@ -58,7 +86,8 @@ pub enum KvStoreError {
 pub struct KvStore {
     directory: PathBuf,
     log_file: File,
-    index: HashMap<String, u64>,
+    index: Index,
+    limits: Limits,
 }
 
 impl KvStore {
@@ -69,16 +98,7 @@ impl KvStore {
             return Err(KvStoreError::InvalidPath);
         }
 
-        let log_file_path: PathBuf = {
-            let mut p = directory.clone();
-            p.push("log.kvs");
-            p
-        };
-        let log_file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(&log_file_path)?;
+        let log_file = open_log(&directory)?;
 
         let mut reader = BufReader::new(&log_file);
         let index = build_index(&mut reader)?;
@@ -86,14 +106,23 @@ impl KvStore {
             directory,
             log_file,
             index,
+            limits: Limits::default(),
         };
         Ok(store)
     }
 
+    /// Reject keys and values larger than `limits` allow on [`set`](Self::set).
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
     /// Set value for a key.
     ///
     /// If the key already exists, it will replace the value.
     pub fn set(&mut self, key: String, value: String) -> Result<(), KvStoreError> {
+        self.limits.check_entry(&key, &value)?;
+
         let command = Command::Set(Set {
             key: key.clone(),
             value,
@@ -117,14 +146,68 @@ impl KvStore {
         let size = self.log_file.seek(SeekFrom::End(0))?;
         let one_mb = 1024 * 1024;
         if size > one_mb {
-            compaction::compact(&mut self.log_file, &self.index)?;
-            let mut reader = BufReader::new(&self.log_file);
-            self.index = build_index(&mut reader)?;
+            self.compact()?;
         }
 
         Ok(())
     }
 
+    /// Compact log.
+    ///
+    /// Called by `set` once the log grows past its threshold, but can be forced at any time.
+    pub fn compact(&mut self) -> Result<(), KvStoreError> {
+        self.log_file =
+            compaction::compact(&self.log_file, &self.index, &log_path(&self.directory))?;
+        let mut reader = BufReader::new(&self.log_file);
+        self.index = build_index(&mut reader)?;
+        Ok(())
+    }
+
+    /// Take a consistent read-only view of the store.
+    ///
+    /// The snapshot keeps seeing the store as it is now while sets, removes and compactions go on.
+    /// Taking one copies the index.
+    pub fn snapshot(&self) -> Result<Snapshot, KvStoreError> {
+        let log_len = self.log_file.metadata()?.len();
+        let log_file = self.log_file.try_clone()?;
+        Ok(Snapshot::new(log_file, log_len, self.index.clone()))
+    }
+
+    /// Walk every record in the log and report corrupted regions.
+    pub fn verify(&self) -> Result<VerifyReport, KvStoreError> {
+        maintenance::verify(&self.directory)
+    }
+
+    /// Copy the store as it is now into `dir`, then verify the copy.
+    ///
+    /// Only the log up to its current length is copied, so writes made while the copy is running
+    /// don't end up in the backup.
+    pub fn backup_to(&self, dir: impl AsRef<Path>) -> Result<(), KvStoreError> {
+        self.start_backup(dir.as_ref())?()
+    }
+
+    /// Like [`backup_to`](Self::backup_to), only capturing the log length now and leaving the copy
+    /// to the returned job, which doesn't borrow the store.
+    pub fn start_backup(
+        &self,
+        dir: &Path,
+    ) -> Result<impl FnOnce() -> Result<(), KvStoreError> + Send, KvStoreError> {
+        let len = self.log_file.metadata()?.len();
+        // Opened now, so a compaction replacing the log later leaves the file being copied alone.
+        let log_file = File::open(log_path(&self.directory))?;
+        let dir = dir.to_owned();
+        Ok(move || backup::backup(log_file, len, &dir))
+    }
+
+    /// Replace the store content with a backup made by [`backup_to`](Self::backup_to).
+    pub fn restore_from(&mut self, dir: impl AsRef<Path>) -> Result<(), KvStoreError> {
+        backup::restore(dir.as_ref(), &self.directory)?;
+        self.log_file = open_log(&self.directory)?;
+        let mut reader = BufReader::new(&self.log_file);
+        self.index = build_index(&mut reader)?;
+        Ok(())
+    }
+
     /// Get value of a key.
     ///
     /// Returns None when entry doesn't exist.
@@ -169,6 +252,37 @@ impl KvStore {
         Ok(())
     }
 
+    /// Iterate entries whose key starts with `prefix`, ordered by key.
+    ///
+    /// Values are read as the iterator advances.
+    pub fn scan<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> impl Iterator<Item = Result<(String, String), KvStoreError>> + 'a {
+        self.scan_range(prefix, None)
+    }
+
+    /// Like [`scan`](Self::scan), starting right after the key `after`.
+    pub fn scan_after<'a>(
+        &'a self,
+        prefix: &str,
+        after: &str,
+    ) -> impl Iterator<Item = Result<(String, String), KvStoreError>> + 'a {
+        self.scan_range(prefix, Some(after))
+    }
+
+    fn scan_range<'a>(
+        &'a self,
+        prefix: &str,
+        after: Option<&str>,
+    ) -> impl Iterator<Item = Result<(String, String), KvStoreError>> + 'a {
+        prefix_range(&self.index, prefix, after).filter_map(move |(key, _)| {
+            self.get(key.to_owned())
+                .map(|value| value.map(|value| (key.to_owned(), value)))
+                .transpose()
+        })
+    }
+
     /// List all entries.
     ///
     /// Only used for testing & debugging.
@@ -197,12 +311,38 @@ impl KvsEngine for KvStore {
         // TODO(kfj): Change store::remove signature to accept borrowed string.
         Ok((self as &mut KvStore).remove(key.to_owned())?)
     }
+
+    fn backup(&mut self, dir: &Path) -> Result<(), KvsEngineError> {
+        Ok(self.backup_to(dir)?)
+    }
+
+    fn start_backup(&mut self, dir: &Path) -> Result<BackupJob, KvsEngineError> {
+        let job = KvStore::start_backup(self, dir)?;
+        Ok(Box::new(move || Ok(job()?)))
+    }
+
+    fn restore(&mut self, dir: &Path) -> Result<(), KvsEngineError> {
+        Ok(self.restore_from(dir)?)
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Entries<'_>, KvsEngineError> {
+        let entries = (self as &KvStore).scan(prefix).map(|result| Ok(result?));
+        Ok(Box::new(entries))
+    }
+
+    fn scan_after(&mut self, prefix: &str, after: &str) -> Result<Entries<'_>, KvsEngineError> {
+        let entries = (self as &KvStore)
+            .scan_after(prefix, after)
+            .map(|result| Ok(result?));
+        Ok(Box::new(entries))
+    }
 }
 
 impl From<KvStoreError> for KvsEngineError {
     fn from(value: KvStoreError) -> Self {
         match value {
             KvStoreError::KeyNotFound { key } => KvsEngineError::EntryNotFound { key },
+            KvStoreError::TooLarge(err) => KvsEngineError::TooLarge(err),
             _ => KvsEngineError::Other(Box::new(value)),
         }
     }
@@ -1,3 +1,4 @@
+use bincode::Options;
 use serde::{de::DeserializeOwned, Serialize};
 use std::convert::From;
 use thiserror::Error;
@@ -38,4 +39,19 @@ pub trait Serializable<'a>: DeserializeOwned + Serialize {
         let command: Self = bincode::deserialize_from(reader)?;
         Ok(command)
     }
+
+    /// Deserialize command from the head of a byte slice.
+    ///
+    /// Returns the command and its serialized size. Never reads past the end of `bytes`, so a
+    /// garbage length prefix fails early instead of asking for a huge allocation.
+    fn deserialize_from_slice(bytes: &[u8]) -> Result<(Self, u64)> {
+        let mut reader = bytes;
+        let command: Self = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(bytes.len() as u64)
+            .deserialize_from(&mut reader)?;
+        let size = (bytes.len() - reader.len()) as u64;
+        Ok((command, size))
+    }
 }
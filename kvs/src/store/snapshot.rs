@@ -0,0 +1,134 @@
+//! Point-in-time read views of a store.
+
+use super::{command::Command, index::Index, serialization::Serializable, KvStoreError};
+use crate::engine::prefix_range;
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+    os::unix::fs::FileExt,
+};
+
+/// Reads a file from an offset without moving its cursor, so readers can share one handle.
+struct ReadAt<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+impl Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// Read-only view of a store pinned to the moment it was taken.
+///
+/// Holds its own handle on the log file and a copy of the index. Later writes are appended past
+/// the pinned log length, and compaction renames a new file into place instead of rewriting this
+/// one, so neither changes what the snapshot sees. The old log file is only freed once every
+/// snapshot pinned to it is dropped.
+#[derive(Debug)]
+pub struct Snapshot {
+    log_file: File,
+    log_len: u64,
+    index: Index,
+}
+
+impl Snapshot {
+    pub(super) fn new(log_file: File, log_len: u64, index: Index) -> Self {
+        Self {
+            log_file,
+            log_len,
+            index,
+        }
+    }
+
+    /// Length of the log when the snapshot was taken.
+    pub fn log_len(&self) -> u64 {
+        self.log_len
+    }
+
+    /// Number of entries in the snapshot.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Get value of a key as it was when the snapshot was taken.
+    pub fn get(&self, key: &str) -> Result<Option<String>, KvStoreError> {
+        let offset = match self.index.get(key) {
+            Some(x) => *x,
+            None => return Ok(None),
+        };
+
+        let reader = BufReader::new(ReadAt {
+            file: &self.log_file,
+            offset,
+        });
+        let command = Command::deserialize_from(reader)?;
+        match command {
+            Command::Set(set) => Ok(Some(set.value)),
+            _ => Err(KvStoreError::IndexDesynced),
+        }
+    }
+
+    /// Iterate entries whose key starts with `prefix`, ordered by key.
+    pub fn scan<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> impl Iterator<Item = Result<(String, String), KvStoreError>> + 'a {
+        prefix_range(&self.index, prefix, None).filter_map(move |(key, _)| {
+            self.get(key)
+                .map(|value| value.map(|value| (key.to_owned(), value)))
+                .transpose()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KvStore;
+
+    #[test]
+    fn test_snapshot_is_isolated_from_writes_and_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = KvStore::open(dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+
+        let snapshot = store.snapshot().unwrap();
+
+        store.set("key1".to_owned(), "changed".to_owned()).unwrap();
+        store.remove("key2".to_owned()).unwrap();
+        store.set("key3".to_owned(), "value3".to_owned()).unwrap();
+        store.compact().unwrap();
+
+        assert_eq!(snapshot.get("key1").unwrap(), Some("value1".to_owned()));
+        assert_eq!(snapshot.get("key2").unwrap(), Some("value2".to_owned()));
+        assert_eq!(snapshot.get("key3").unwrap(), None);
+        let entries: Vec<_> = snapshot.scan("").collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                ("key1".to_owned(), "value1".to_owned()),
+                ("key2".to_owned(), "value2".to_owned()),
+            ]
+        );
+
+        assert_eq!(
+            store.get("key1".to_owned()).unwrap(),
+            Some("changed".to_owned())
+        );
+        assert_eq!(store.get("key2".to_owned()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_snapshot_impls() {
+        static_assertions::assert_impl_all!(Snapshot: Send, Sync);
+    }
+}
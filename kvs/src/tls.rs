@@ -0,0 +1,283 @@
+//! TLS for connections between [`KvsClient`](crate::KvsClient) and
+//! [`KvsServer`](crate::KvsServer), available with the `tls` feature.
+//!
+//! Clients only trust the certificate authorities they're given, so a server can use certificates
+//! of a private authority. Servers can require client certificates signed by a given authority
+//! too, the common name of the client certificate then shows up in the server logs.
+
+use nix::poll::{poll, PollFd, PollFlags};
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection,
+};
+use std::{
+    convert::TryFrom,
+    io::{self, BufReader, Read, Write},
+    net::TcpStream,
+    os::unix::io::AsRawFd,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("no certificate found in PEM data")]
+    NoCertificate,
+    #[error("no private key found in PEM data")]
+    NoPrivateKey,
+    #[error("invalid server name `{0}`")]
+    InvalidServerName(String),
+    #[error("failed to read PEM data, caused by {0}")]
+    Pem(#[from] io::Error),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+    #[error("invalid client certificate authority, caused by {0}")]
+    ClientVerifier(#[from] rustls::server::VerifierBuilderError),
+}
+
+/// TLS settings of a server.
+#[derive(Clone, Debug)]
+pub struct TlsServerConfig {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsServerConfig {
+    /// Serve the certificate chain `cert_pem` with private key `key_pem`.
+    ///
+    /// With `client_ca_pem`, clients must present a certificate signed by one of its authorities.
+    pub fn new(
+        cert_pem: &[u8],
+        key_pem: &[u8],
+        client_ca_pem: Option<&[u8]>,
+    ) -> Result<Self, TlsError> {
+        let provider = provider();
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match client_ca_pem {
+            Some(pem) => {
+                let roots = Arc::new(root_store(pem)?);
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(roots, provider).build()?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_single_cert(certs(cert_pem)?, private_key(key_pem)?)?;
+        // Tickets would be written by whichever thread reads next, keep writes to the writers.
+        config.send_tls13_tickets = 0;
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+}
+
+/// TLS settings of a client.
+#[derive(Clone, Debug)]
+pub struct TlsClientConfig {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+}
+
+impl TlsClientConfig {
+    /// Trust servers with a certificate for `server_name` signed by an authority of `ca_pem`.
+    pub fn new(server_name: &str, ca_pem: &[u8]) -> Result<Self, TlsError> {
+        let config = Self::builder(ca_pem)?.with_no_client_auth();
+        Self::with_config(server_name, config)
+    }
+
+    /// Like [`new`](Self::new), presenting the certificate chain `cert_pem` with private key
+    /// `key_pem` to servers requiring client certificates.
+    pub fn with_client_cert(
+        server_name: &str,
+        ca_pem: &[u8],
+        cert_pem: &[u8],
+        key_pem: &[u8],
+    ) -> Result<Self, TlsError> {
+        let config = Self::builder(ca_pem)?
+            .with_client_auth_cert(certs(cert_pem)?, private_key(key_pem)?)?;
+        Self::with_config(server_name, config)
+    }
+
+    fn builder(
+        ca_pem: &[u8],
+    ) -> Result<rustls::ConfigBuilder<ClientConfig, rustls::client::WantsClientCert>, TlsError>
+    {
+        Ok(ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(root_store(ca_pem)?))
+    }
+
+    fn with_config(server_name: &str, config: ClientConfig) -> Result<Self, TlsError> {
+        let server_name = ServerName::try_from(server_name.to_owned())
+            .map_err(|_| TlsError::InvalidServerName(server_name.to_owned()))?;
+        Ok(Self {
+            config: Arc::new(config),
+            server_name,
+        })
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn certs(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(pem)).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate);
+    }
+    Ok(certs)
+}
+
+fn private_key(pem: &[u8]) -> Result<PrivateKeyDer<'static>, TlsError> {
+    rustls_pemfile::private_key(&mut BufReader::new(pem))?.ok_or(TlsError::NoPrivateKey)
+}
+
+fn root_store(pem: &[u8]) -> Result<RootCertStore, TlsError> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(pem)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+/// TLS connection over a TCP stream, readable and writable from different threads at once.
+///
+/// rustls connections can't be split in halves, so readers only lock the connection once the
+/// socket has data, and writers encrypt under the lock but write to the socket outside of it.
+/// Otherwise a reader blocked on the socket would stall every writer.
+pub(crate) struct TlsStream {
+    sock: TcpStream,
+    conn: Mutex<Connection>,
+    /// Held while writing to the socket, so records go out in order.
+    write: Mutex<()>,
+}
+
+impl TlsStream {
+    /// Connect as a client, failing when the handshake takes longer than `timeout`.
+    pub(crate) fn connect(
+        sock: TcpStream,
+        config: &TlsClientConfig,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        let conn = ClientConnection::new(config.config.clone(), config.server_name.clone())
+            .map_err(invalid_data)?;
+        Self::handshake(sock, conn.into(), timeout)
+    }
+
+    /// Accept a client, failing when the handshake takes longer than `timeout`.
+    pub(crate) fn accept(
+        sock: TcpStream,
+        config: &TlsServerConfig,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        let conn = ServerConnection::new(config.config.clone()).map_err(invalid_data)?;
+        Self::handshake(sock, conn.into(), timeout)
+    }
+
+    fn handshake(sock: TcpStream, mut conn: Connection, timeout: Duration) -> io::Result<Self> {
+        let read_timeout = sock.read_timeout()?;
+        let write_timeout = sock.write_timeout()?;
+        sock.set_read_timeout(Some(timeout))?;
+        sock.set_write_timeout(Some(timeout))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut &sock)?;
+        }
+        while conn.wants_write() {
+            conn.write_tls(&mut &sock)?;
+        }
+        sock.set_read_timeout(read_timeout)?;
+        sock.set_write_timeout(write_timeout)?;
+        Ok(Self {
+            sock,
+            conn: Mutex::new(conn),
+            write: Mutex::new(()),
+        })
+    }
+
+    pub(crate) fn sock(&self) -> &TcpStream {
+        &self.sock
+    }
+
+    /// Common name of the certificate the peer presented, if any.
+    pub(crate) fn peer_identity(&self) -> Option<String> {
+        let conn = self.conn.lock().unwrap();
+        let cert = conn.peer_certificates()?.first()?;
+        let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+        let name = cert.subject().iter_common_name().next()?;
+        name.as_str().ok().map(str::to_owned)
+    }
+
+    /// Write the records `conn` has queued. Must be called with `self.write` held.
+    fn send_records(&self, mut conn: MutexGuard<'_, Connection>) -> io::Result<()> {
+        let mut records = Vec::new();
+        while conn.wants_write() {
+            conn.write_tls(&mut records)?;
+        }
+        drop(conn);
+        (&self.sock).write_all(&records)
+    }
+
+    /// Block until the socket has data, or its read timeout expires.
+    fn wait_readable(&self) -> io::Result<()> {
+        let timeout = match self.sock.read_timeout()? {
+            Some(x) => x.as_millis().clamp(1, i32::MAX as u128) as i32,
+            None => -1,
+        };
+        let mut fds = [PollFd::new(self.sock.as_raw_fd(), PollFlags::POLLIN)];
+        loop {
+            match poll(&mut fds, timeout) {
+                Ok(0) => return Err(io::ErrorKind::WouldBlock.into()),
+                Ok(_) => return Ok(()),
+                Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                Err(err) => return Err(io::Error::other(err)),
+            }
+        }
+    }
+}
+
+impl Read for &TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.lock().unwrap().reader().read(buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            self.wait_readable()?;
+            let mut conn = self.conn.lock().unwrap();
+            if conn.read_tls(&mut &self.sock)? == 0 {
+                // Peer hung up, with or without saying goodbye.
+                return Ok(0);
+            }
+            conn.process_new_packets().map_err(invalid_data)?;
+            if conn.wants_write() {
+                drop(conn);
+                let _write = self.write.lock().unwrap();
+                self.send_records(self.conn.lock().unwrap())?;
+            }
+        }
+    }
+}
+
+impl Write for &TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _write = self.write.lock().unwrap();
+        let mut conn = self.conn.lock().unwrap();
+        let written = conn.writer().write(buf)?;
+        self.send_records(conn)?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Every write goes out right away.
+        Ok(())
+    }
+}
+
+fn invalid_data(err: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
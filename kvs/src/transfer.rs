@@ -0,0 +1,439 @@
+//! Moving the keyspace between engines and in and out of JSON Lines or CSV.
+//!
+//! Everything here streams: entries are written as they come out of the source iterator and
+//! imported in batches of bounded size.
+
+use crate::KvsEngine;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::DefaultHasher,
+    error, fmt, fs,
+    hash::{Hash, Hasher},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+    str,
+};
+use thiserror::Error;
+
+/// Number of entries handed to the sink per import batch.
+pub const DEFAULT_BATCH_SIZE: usize = 1000;
+
+/// Number of exported entries between progress reports.
+const EXPORT_PROGRESS_INTERVAL: u64 = 1000;
+
+#[derive(Error, Debug)]
+pub enum TransferError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid JSON record, caused by {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid CSV record, caused by {0}")]
+    Csv(#[from] csv::Error),
+    #[error("{0}")]
+    Source(Box<dyn error::Error>),
+    #[error("batch size must be at least 1")]
+    InvalidBatchSize,
+    #[error("verification failed, source has {src_count} entries with checksum {src_checksum:016x}, destination has {dst_count} entries with checksum {dst_checksum:016x}")]
+    VerificationFailed {
+        src_count: u64,
+        src_checksum: u64,
+        dst_count: u64,
+        dst_checksum: u64,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// One `{"key": .., "value": ..}` object per line.
+    JsonLines,
+    /// `key,value` header followed by one record per row.
+    Csv,
+}
+
+impl str::FromStr for Format {
+    type Err = Box<dyn error::Error + Send + Sync>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "jsonl" | "json-lines" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            other => Err(format!("unknown format `{}`", other).into()),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::JsonLines => write!(f, "jsonl"),
+            Format::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: String,
+    value: String,
+}
+
+/// Write entries in the given format.
+///
+/// `progress` is called with the running count every thousand entries and once more at the end.
+/// Returns the number of exported entries.
+pub fn export<E>(
+    entries: impl Iterator<Item = Result<(String, String), E>>,
+    writer: impl Write,
+    format: Format,
+    mut progress: impl FnMut(u64),
+) -> Result<u64, TransferError>
+where
+    E: error::Error + 'static,
+{
+    let mut count = 0;
+    match format {
+        Format::JsonLines => {
+            let mut writer = BufWriter::new(writer);
+            for entry in entries {
+                let (key, value) = entry.map_err(|e| TransferError::Source(Box::new(e)))?;
+                serde_json::to_writer(&mut writer, &Record { key, value })?;
+                writer.write_all(b"\n")?;
+                count += 1;
+                if count % EXPORT_PROGRESS_INTERVAL == 0 {
+                    progress(count);
+                }
+            }
+            writer.flush()?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            for entry in entries {
+                let (key, value) = entry.map_err(|e| TransferError::Source(Box::new(e)))?;
+                writer.serialize(Record { key, value })?;
+                count += 1;
+                if count % EXPORT_PROGRESS_INTERVAL == 0 {
+                    progress(count);
+                }
+            }
+            writer.flush()?;
+        }
+    }
+    progress(count);
+    Ok(count)
+}
+
+/// Read entries in the given format and hand them to `apply` in batches of `batch_size`.
+///
+/// Entries whose key doesn't start with `prefix` are skipped. `progress` is called with the
+/// running count after every batch. Returns the number of imported entries, or
+/// [`TransferError::InvalidBatchSize`] when `batch_size` is 0.
+pub fn import<E>(
+    reader: impl Read,
+    format: Format,
+    prefix: &str,
+    batch_size: usize,
+    mut apply: impl FnMut(Vec<(String, String)>) -> Result<(), E>,
+    mut progress: impl FnMut(u64),
+) -> Result<u64, TransferError>
+where
+    E: error::Error + 'static,
+{
+    if batch_size == 0 {
+        return Err(TransferError::InvalidBatchSize);
+    }
+    let records: Box<dyn Iterator<Item = Result<Record, TransferError>>> = match format {
+        Format::JsonLines => Box::new(
+            BufReader::new(reader)
+                .lines()
+                .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|line| Ok(serde_json::from_str(&line?)?)),
+        ),
+        Format::Csv => Box::new(
+            csv::Reader::from_reader(reader)
+                .into_deserialize()
+                .map(|record| Ok(record?)),
+        ),
+    };
+
+    let mut count = 0;
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut flush = |batch: &mut Vec<(String, String)>, count: &mut u64| {
+        *count += batch.len() as u64;
+        let batch = std::mem::replace(batch, Vec::with_capacity(batch_size));
+        apply(batch).map_err(|e| TransferError::Source(Box::new(e)))?;
+        progress(*count);
+        Ok::<_, TransferError>(())
+    };
+    for record in records {
+        let Record { key, value } = record?;
+        if !key.starts_with(prefix) {
+            continue;
+        }
+        batch.push((key, value));
+        if batch.len() >= batch_size {
+            flush(&mut batch, &mut count)?;
+        }
+    }
+    if !batch.is_empty() {
+        flush(&mut batch, &mut count)?;
+    }
+    Ok(count)
+}
+
+/// Export entries of an engine whose key starts with `prefix`.
+pub fn export_engine(
+    engine: &mut (impl KvsEngine + ?Sized),
+    prefix: &str,
+    writer: impl Write,
+    format: Format,
+    progress: impl FnMut(u64),
+) -> Result<u64, TransferError> {
+    let entries = engine
+        .scan(prefix)
+        .map_err(|e| TransferError::Source(Box::new(e)))?;
+    export(entries, writer, format, progress)
+}
+
+/// Import entries whose key starts with `prefix` into an engine.
+pub fn import_engine(
+    engine: &mut (impl KvsEngine + ?Sized),
+    reader: impl Read,
+    format: Format,
+    prefix: &str,
+    batch_size: usize,
+    progress: impl FnMut(u64),
+) -> Result<u64, TransferError> {
+    let apply = |batch: Vec<(String, String)>| {
+        for (key, value) in batch {
+            engine.set(key, value)?;
+        }
+        Ok::<_, crate::KvsEngineError>(())
+    };
+    import(reader, format, prefix, batch_size, apply, progress)
+}
+
+#[derive(Debug, Default)]
+pub struct MigrateReport {
+    /// Entries copied by this run.
+    pub copied: u64,
+    /// Entries skipped because an interrupted run already copied them.
+    pub resumed: u64,
+    /// Entries in both engines after migration.
+    pub entries: u64,
+    /// Checksum of the whole keyspace, only comparable with checksums computed by the same build.
+    pub checksum: u64,
+}
+
+/// Count entries of an engine and hash them in key order.
+fn checksum(engine: &mut (impl KvsEngine + ?Sized)) -> Result<(u64, u64), TransferError> {
+    let entries = engine
+        .scan("")
+        .map_err(|e| TransferError::Source(Box::new(e)))?;
+    let mut hasher = DefaultHasher::new();
+    let mut count = 0;
+    for entry in entries {
+        let entry = entry.map_err(|e| TransferError::Source(Box::new(e)))?;
+        entry.hash(&mut hasher);
+        count += 1;
+    }
+    Ok((count, hasher.finish()))
+}
+
+/// Copy every entry from `src` to `dst`, then verify both hold the same keyspace.
+///
+/// The last copied key is saved to `checkpoint` after every batch. If `checkpoint` exists when
+/// called, entries up to and including that key are assumed to be copied already. The checkpoint
+/// is removed once verification passes. A `batch_size` of 0 is rejected with
+/// [`TransferError::InvalidBatchSize`].
+pub fn migrate(
+    src: &mut (impl KvsEngine + ?Sized),
+    dst: &mut (impl KvsEngine + ?Sized),
+    checkpoint: &Path,
+    batch_size: usize,
+    mut progress: impl FnMut(u64),
+) -> Result<MigrateReport, TransferError> {
+    if batch_size == 0 {
+        return Err(TransferError::InvalidBatchSize);
+    }
+    let resume_after = match fs::read_to_string(checkpoint) {
+        Ok(key) => Some(key),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut report = MigrateReport::default();
+    {
+        let entries = src
+            .scan("")
+            .map_err(|e| TransferError::Source(Box::new(e)))?;
+        let mut last_key = None;
+        for entry in entries {
+            let (key, value) = entry.map_err(|e| TransferError::Source(Box::new(e)))?;
+            if matches!(&resume_after, Some(after) if &key <= after) {
+                report.resumed += 1;
+                continue;
+            }
+            dst.set(key.clone(), value)
+                .map_err(|e| TransferError::Source(Box::new(e)))?;
+            report.copied += 1;
+            if report.copied % batch_size as u64 == 0 {
+                write_checkpoint(checkpoint, &key)?;
+                progress(report.resumed + report.copied);
+            }
+            last_key = Some(key);
+        }
+        if let Some(key) = last_key {
+            write_checkpoint(checkpoint, &key)?;
+        }
+        progress(report.resumed + report.copied);
+    }
+
+    let (src_count, src_checksum) = checksum(src)?;
+    let (dst_count, dst_checksum) = checksum(dst)?;
+    if src_count != dst_count || src_checksum != dst_checksum {
+        return Err(TransferError::VerificationFailed {
+            src_count,
+            src_checksum,
+            dst_count,
+            dst_checksum,
+        });
+    }
+    report.entries = src_count;
+    report.checksum = src_checksum;
+
+    match fs::remove_file(checkpoint) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+
+    Ok(report)
+}
+
+/// Replace checkpoint content without ever leaving it half written.
+fn write_checkpoint(path: &Path, key: &str) -> Result<(), TransferError> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, key)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KvStore, MemoryEngine, SledKvsEngine};
+
+    fn round_trip(format: Format) {
+        let src_dir = tempfile::tempdir().unwrap();
+        let mut src = KvStore::open(src_dir.path()).unwrap();
+        src.set("a/1".to_owned(), "one, \"quoted\"".to_owned())
+            .unwrap();
+        src.set("a/2".to_owned(), "two\nlines".to_owned()).unwrap();
+        src.set("b/1".to_owned(), "ünïcödé".to_owned()).unwrap();
+
+        let mut buf = Vec::new();
+        let count = export_engine(&mut src, "", &mut buf, format, |_| {}).unwrap();
+        assert_eq!(count, 3);
+
+        let dst_dir = tempfile::tempdir().unwrap();
+        let mut dst = KvStore::open(dst_dir.path()).unwrap();
+        let mut batches = 0;
+        let count = import_engine(&mut dst, &buf[..], format, "a/", 1, |_| batches += 1).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(batches, 2);
+
+        assert_eq!(
+            dst.list().unwrap().len(),
+            2,
+            "entries outside prefix shouldn't be imported"
+        );
+        assert_eq!(
+            dst.get("a/1".to_owned()).unwrap(),
+            Some("one, \"quoted\"".to_owned())
+        );
+        assert_eq!(
+            dst.get("a/2".to_owned()).unwrap(),
+            Some("two\nlines".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_json_lines_round_trip() {
+        round_trip(Format::JsonLines);
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        round_trip(Format::Csv);
+    }
+
+    #[test]
+    fn test_migrate_resumes_from_checkpoint() {
+        let src_dir = tempfile::tempdir().unwrap();
+        let mut src = KvStore::open(src_dir.path()).unwrap();
+        for i in 0..5 {
+            src.set(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+
+        // Pretend an earlier run copied up to `key2` before being interrupted.
+        let dst_dir = tempfile::tempdir().unwrap();
+        let mut dst = SledKvsEngine::open(dst_dir.path()).unwrap();
+        for i in 0..3 {
+            KvsEngine::set(&mut dst, format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        let checkpoint = dst_dir.path().join("migrate.checkpoint");
+        fs::write(&checkpoint, "key2").unwrap();
+
+        let report = migrate(&mut src, &mut dst, &checkpoint, 2, |_| {}).unwrap();
+        assert_eq!(report.resumed, 3);
+        assert_eq!(report.copied, 2);
+        assert_eq!(report.entries, 5);
+        assert!(!checkpoint.exists());
+        assert_eq!(
+            KvsEngine::get(&mut dst, "key4").unwrap(),
+            Some("value4".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_migrate_detects_mismatch() {
+        let src_dir = tempfile::tempdir().unwrap();
+        let mut src = KvStore::open(src_dir.path()).unwrap();
+        src.set("key0".to_owned(), "value0".to_owned()).unwrap();
+
+        let dst_dir = tempfile::tempdir().unwrap();
+        let mut dst = KvStore::open(dst_dir.path()).unwrap();
+        dst.set("stray".to_owned(), "value".to_owned()).unwrap();
+
+        let checkpoint = dst_dir.path().join("migrate.checkpoint");
+        let result = migrate(&mut src, &mut dst, &checkpoint, 10, |_| {});
+        assert!(matches!(
+            result,
+            Err(TransferError::VerificationFailed {
+                src_count: 1,
+                dst_count: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_zero_batch_size_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = KvStore::open(dir.path()).unwrap();
+        let input = "{\"key\":\"a\",\"value\":\"b\"}\n";
+        let result = import_engine(
+            &mut store,
+            input.as_bytes(),
+            Format::JsonLines,
+            "",
+            0,
+            |_| {},
+        );
+        assert!(matches!(result, Err(TransferError::InvalidBatchSize)));
+
+        let mut other = MemoryEngine::new();
+        let checkpoint = dir.path().join("migrate.checkpoint");
+        let result = migrate(&mut store, &mut other, &checkpoint, 0, |_| {});
+        assert!(matches!(result, Err(TransferError::InvalidBatchSize)));
+    }
+}
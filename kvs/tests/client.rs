@@ -0,0 +1,539 @@
+use kvs::{
+    client::{Access, ClientError, Grant, PoolConfig, PoolError, RetryPolicy, MAX_SCAN_LIMIT},
+    server::{AuthConfig, Authenticator, LoadLimits, RateLimit},
+    KvsClient, KvsClientPool, KvsServer, Limits, MemoryEngine,
+};
+use std::{
+    fs, io,
+    net::{SocketAddr, TcpListener},
+    os::unix::{fs::PermissionsExt, net::UnixListener},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Start a server on `addr` for the rest of the test process.
+fn start_server(addr: &str) -> SocketAddr {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let server = KvsServer::new(None, addr).unwrap();
+    thread::spawn(move || {
+        let mut engine = MemoryEngine::new();
+        server.listen(&mut engine).unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    addr
+}
+
+// Pipelined requests should get their responses back in order.
+#[test]
+fn client_pipeline() {
+    let addr = start_server("127.0.0.1:4010");
+    let client = KvsClient::new(None, addr).unwrap();
+
+    let mut pipeline = client.pipeline();
+    for i in 0..100 {
+        pipeline.set(format!("key{}", i), format!("value{}", i));
+    }
+    pipeline
+        .get("key42".to_owned())
+        .rm("key0".to_owned())
+        .rm("missing".to_owned());
+    assert_eq!(pipeline.len(), 103);
+
+    let results = pipeline.execute().unwrap();
+    assert_eq!(results.len(), 103);
+    assert!(results[..100].iter().all(|x| matches!(x, Ok(None))));
+    assert_eq!(results[100].as_ref().unwrap(), &Some("value42".to_owned()));
+    assert!(matches!(results[101], Ok(None)));
+    assert!(matches!(results[102], Err(ClientError::NotFound(_))));
+
+    assert_eq!(client.get("key0".to_owned()).unwrap(), None);
+    assert_eq!(
+        client.get("key99".to_owned()).unwrap(),
+        Some("value99".to_owned())
+    );
+}
+
+// One client shared by several threads should get every response to the right caller.
+#[test]
+fn client_shared_between_threads() {
+    let addr = start_server("127.0.0.1:4011");
+    let client = KvsClient::new(None, addr).unwrap();
+
+    thread::scope(|scope| {
+        for t in 0..8 {
+            let client = &client;
+            scope.spawn(move || {
+                for i in 0..50 {
+                    let key = format!("key{}-{}", t, i);
+                    client.set(key.clone(), format!("{}", i)).unwrap();
+                    assert_eq!(client.get(key).unwrap(), Some(format!("{}", i)));
+                }
+            });
+        }
+    });
+
+    assert_eq!(client.scan("key").count(), 400);
+}
+
+// A scan page should never be larger than the server cap, whatever the requested limit.
+#[test]
+fn client_scan_page_is_capped() {
+    let addr = start_server("127.0.0.1:4019");
+    let client = KvsClient::new(None, addr).unwrap();
+
+    let mut pipeline = client.pipeline();
+    for i in 0..MAX_SCAN_LIMIT + 10 {
+        pipeline.set(format!("key{:05}", i), "value".to_owned());
+    }
+    pipeline.execute().unwrap();
+
+    let page = client.scan_page(String::new(), None, u32::MAX).unwrap();
+    assert_eq!(page.len(), MAX_SCAN_LIMIT as usize);
+    assert_eq!(client.scan("key").count(), MAX_SCAN_LIMIT as usize + 10);
+}
+
+// Pool should cap open connections and time out checkouts past the cap.
+#[test]
+fn client_pool() {
+    let addr = start_server("127.0.0.1:4012");
+    let config = PoolConfig {
+        max_size: 2,
+        health_check_after: Duration::from_millis(0),
+        checkout_timeout: Duration::from_millis(100),
+        ..PoolConfig::default()
+    };
+    let pool = KvsClientPool::new(KvsClient::builder(addr), config).unwrap();
+
+    thread::scope(|scope| {
+        for t in 0..4 {
+            let pool = &pool;
+            scope.spawn(move || {
+                for i in 0..20 {
+                    let client = pool.get().unwrap();
+                    client
+                        .set(format!("key{}-{}", t, i), "value".to_owned())
+                        .unwrap();
+                }
+            });
+        }
+    });
+    assert!(pool.size() <= 2);
+
+    let first = pool.get().unwrap();
+    let second = pool.get().unwrap();
+    assert!(matches!(pool.get(), Err(PoolError::Timeout)));
+    drop(first);
+    assert_eq!(
+        pool.get().unwrap().get("key3-19".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    second.ping().unwrap();
+}
+
+// A health check on a server that never answers should give up at the checkout timeout, and
+// replace the connection.
+#[test]
+fn client_pool_health_check_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let _connections: Vec<_> = listener.incoming().collect();
+    });
+
+    let config = PoolConfig {
+        health_check_after: Duration::from_millis(0),
+        checkout_timeout: Duration::from_millis(100),
+        ..PoolConfig::default()
+    };
+    let pool = KvsClientPool::new(KvsClient::builder(addr), config).unwrap();
+    drop(pool.get().unwrap());
+
+    let start = Instant::now();
+    let client = pool.get().unwrap();
+    assert!(start.elapsed() < Duration::from_millis(500));
+    assert!(!client.is_closed());
+    assert_eq!(pool.size(), 1);
+}
+
+// A server that never answers should fail requests after the read timeout, and retry gets only.
+#[test]
+fn client_read_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let _connections: Vec<_> = listener.incoming().collect();
+    });
+
+    let client = KvsClient::builder(addr)
+        .read_timeout(Duration::from_millis(100))
+        .retry(RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+        })
+        .connect()
+        .unwrap();
+
+    let start = Instant::now();
+    let result = client.get("key".to_owned());
+    assert!(matches!(result, Err(ClientError::Timeout)));
+    assert!(start.elapsed() >= Duration::from_millis(300));
+
+    let start = Instant::now();
+    let result = client.set("key".to_owned(), "value".to_owned());
+    assert!(matches!(result, Err(ClientError::Timeout)));
+    assert!(start.elapsed() < Duration::from_millis(300));
+}
+
+// Client should connect to the first reachable endpoint, resolving host names.
+#[test]
+fn client_failover() {
+    start_server("127.0.0.1:4013");
+    let client = KvsClient::builder("127.0.0.1:4098")
+        .endpoint("localhost:4013")
+        .connect()
+        .unwrap();
+    client.ping().unwrap();
+
+    assert!(KvsClient::new(None, "127.0.0.1:4098").is_err());
+    assert!(KvsClient::new(None, "not a host").is_err());
+}
+
+// Server and client should talk over a Unix domain socket, replacing a stale socket file.
+#[test]
+fn client_unix_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kvs.sock");
+    drop(UnixListener::bind(&path).unwrap());
+
+    let server = KvsServer::unix(None, &path, 0o600).unwrap();
+    thread::spawn(move || {
+        let mut engine = MemoryEngine::new();
+        server.listen(&mut engine).unwrap();
+    });
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    assert!(KvsServer::unix(None, &path, 0o600).is_err());
+    // Nothing is left of the directory the socket was bound in.
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+    let client = KvsClient::unix(None, &path).unwrap();
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+// Server with authentication should refuse requests until the connection authenticates.
+#[test]
+fn client_authentication() {
+    let addr: SocketAddr = "127.0.0.1:4014".parse().unwrap();
+    let config = AuthConfig::parse("[users.alice]\ntoken = \"s3cret\"\n").unwrap();
+    let server = KvsServer::new(None, addr)
+        .unwrap()
+        .with_auth(Authenticator::new(&config));
+    thread::spawn(move || {
+        let mut engine = MemoryEngine::new();
+        server.listen(&mut engine).unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let anonymous = KvsClient::new(None, addr).unwrap();
+    anonymous.ping().unwrap();
+    let result = anonymous.get("key".to_owned());
+    assert!(matches!(result, Err(ClientError::Unauthorized(_))));
+
+    let err = KvsClient::builder(addr)
+        .credentials("alice", "secret")
+        .connect()
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+    let alice = KvsClient::builder(addr)
+        .credentials("alice", "s3cret")
+        .connect()
+        .unwrap();
+    alice.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(
+        alice.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+// Users should only reach keys their permissions cover.
+#[test]
+fn client_access_control() {
+    let addr: SocketAddr = "127.0.0.1:4015".parse().unwrap();
+    let config = AuthConfig::parse(
+        r#"
+        [users.alice]
+        token = "a"
+        permissions = [{ prefix = "alice/", access = ["read", "write"] }]
+
+        [users.root]
+        token = "r"
+        permissions = [{ prefix = "", access = ["admin"] }]
+        "#,
+    )
+    .unwrap();
+    let backup_root = tempfile::tempdir().unwrap();
+    let server = KvsServer::new(None, addr)
+        .unwrap()
+        .with_auth(Authenticator::new(&config))
+        .with_backup_root(backup_root.path());
+    thread::spawn(move || {
+        let mut engine = MemoryEngine::new();
+        server.listen(&mut engine).unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let alice = KvsClient::builder(addr)
+        .credentials("alice", "a")
+        .connect()
+        .unwrap();
+    let root = KvsClient::builder(addr)
+        .credentials("root", "r")
+        .connect()
+        .unwrap();
+
+    root.set("root/key".to_owned(), "value".to_owned()).unwrap();
+    alice
+        .set("alice/key".to_owned(), "value".to_owned())
+        .unwrap();
+    assert!(matches!(
+        alice.get("root/key".to_owned()),
+        Err(ClientError::Unauthorized(_))
+    ));
+    assert!(matches!(
+        alice.set("key".to_owned(), "value".to_owned()),
+        Err(ClientError::Unauthorized(_))
+    ));
+    assert_eq!(alice.scan("alice/").count(), 1);
+    assert!(alice.scan("").next().unwrap().is_err());
+    assert_eq!(
+        root.get("alice/key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+
+    assert_eq!(
+        alice.permissions(None).unwrap(),
+        vec![Grant {
+            prefix: "alice/".to_owned(),
+            access: vec![Access::Read, Access::Write],
+        }]
+    );
+    assert!(alice.permissions(Some("root".to_owned())).is_err());
+    assert_eq!(
+        root.permissions(Some("root".to_owned())).unwrap(),
+        vec![Grant::all()]
+    );
+
+    assert!(matches!(
+        alice.backup("nightly".to_owned()),
+        Err(ClientError::Unauthorized(_))
+    ));
+    root.backup("nightly".to_owned()).unwrap();
+    root.restore("nightly".to_owned()).unwrap();
+    assert!(backup_root.path().join("nightly").is_dir());
+}
+
+// Backup directories should stay inside the backup root of the server.
+#[test]
+fn client_backup_dir_outside_root() {
+    let addr: SocketAddr = "127.0.0.1:4024".parse().unwrap();
+    let config = AuthConfig::parse(
+        r#"
+        [users.root]
+        token = "r"
+        permissions = [{ prefix = "", access = ["admin"] }]
+        "#,
+    )
+    .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let backup_root = dir.path().join("backups");
+    let server = KvsServer::new(None, addr)
+        .unwrap()
+        .with_auth(Authenticator::new(&config))
+        .with_backup_root(&backup_root);
+    thread::spawn(move || {
+        let mut engine = MemoryEngine::new();
+        server.listen(&mut engine).unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let root = KvsClient::builder(addr)
+        .credentials("root", "r")
+        .connect()
+        .unwrap();
+    let outside = dir.path().join("outside");
+    for request in [
+        "../outside".to_owned(),
+        outside.to_string_lossy().into_owned(),
+    ]
+    .iter()
+    {
+        assert!(matches!(
+            root.backup(request.clone()),
+            Err(ClientError::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            root.restore(request.clone()),
+            Err(ClientError::InvalidRequest(_))
+        ));
+    }
+    assert!(!outside.exists());
+}
+
+// Without authentication there are no admins, so backups can't be requested over the wire.
+#[test]
+fn client_admin_requests_need_auth() {
+    let addr = start_server("127.0.0.1:4023");
+    let client = KvsClient::new(None, addr).unwrap();
+    let backup_dir = tempfile::tempdir().unwrap();
+    let dir = backup_dir.path().to_string_lossy().into_owned();
+
+    assert!(matches!(
+        client.backup(dir.clone()),
+        Err(ClientError::Unauthorized(_))
+    ));
+    assert!(matches!(
+        client.restore(dir),
+        Err(ClientError::Unauthorized(_))
+    ));
+    assert_eq!(fs::read_dir(backup_dir.path()).unwrap().count(), 0);
+}
+
+// Requests over the server limits should fail as too large, later requests reconnect.
+#[test]
+fn client_size_limits() {
+    let addr: SocketAddr = "127.0.0.1:4016".parse().unwrap();
+    let limits = Limits {
+        max_key_size: 16,
+        max_value_size: 1024,
+        max_frame_size: 4096,
+    };
+    let server = KvsServer::new(None, addr).unwrap().with_limits(limits);
+    thread::spawn(move || {
+        let mut engine = MemoryEngine::new();
+        server.listen(&mut engine).unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let client = KvsClient::builder(addr)
+        .read_timeout(Duration::from_secs(5))
+        .connect()
+        .unwrap();
+    assert!(matches!(
+        client.set("k".repeat(17), "value".to_owned()),
+        Err(ClientError::TooLarge(_))
+    ));
+    assert!(matches!(
+        client.set("key".to_owned(), "v".repeat(1025)),
+        Err(ClientError::TooLarge(_))
+    ));
+    // Caught while decoding, the rest of the request is never read into memory.
+    assert!(matches!(
+        client.set("key".to_owned(), "v".repeat(1024 * 1024)),
+        Err(ClientError::TooLarge(_))
+    ));
+
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+/// Start a server on `addr` with `load` limits for the rest of the test process.
+fn start_limited_server(addr: &str, load: LoadLimits) -> SocketAddr {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let server = KvsServer::new(None, addr).unwrap().with_load_limits(load);
+    thread::spawn(move || {
+        let mut engine = MemoryEngine::new();
+        server.listen(&mut engine).unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    addr
+}
+
+// Requests over the rate limit should be turned away as busy, and succeed once retried later.
+#[test]
+fn client_rate_limit() {
+    let rate = RateLimit {
+        per_second: 10.0,
+        burst: 2.0,
+    };
+    let load = LoadLimits {
+        rate: Some(rate),
+        ..LoadLimits::default()
+    };
+    let addr = start_limited_server("127.0.0.1:4017", load);
+
+    let client = KvsClient::builder(addr)
+        .retry(RetryPolicy::none())
+        .connect()
+        .unwrap();
+    let results: Vec<_> = (0..5)
+        .map(|_| client.set("key".to_owned(), "value".to_owned()))
+        .collect();
+    assert!(results[..2].iter().all(Result::is_ok));
+    assert!(matches!(results[4], Err(ClientError::Busy(_))));
+
+    // Retried with backoff until the bucket refills.
+    let client = KvsClient::builder(addr)
+        .retry(RetryPolicy {
+            max_retries: 10,
+            ..RetryPolicy::default()
+        })
+        .connect()
+        .unwrap();
+    for _ in 0..5 {
+        client.set("key".to_owned(), "value".to_owned()).unwrap();
+    }
+}
+
+// Connections over the per address limit should be turned away as busy right away.
+#[test]
+fn client_connection_limit() {
+    let load = LoadLimits {
+        max_connections_per_ip: Some(1),
+        ..LoadLimits::default()
+    };
+    let addr = start_limited_server("127.0.0.1:4018", load);
+    let connect = || {
+        KvsClient::builder(addr)
+            .retry(RetryPolicy::none())
+            .connect()
+            .unwrap()
+    };
+
+    let first = connect();
+    first.ping().unwrap();
+    assert!(matches!(connect().ping(), Err(ClientError::Busy(_))));
+    assert!(matches!(
+        connect().set("key".to_owned(), "value".to_owned()),
+        Err(ClientError::Busy(_))
+    ));
+
+    drop(first);
+    thread::sleep(Duration::from_millis(100));
+    let first = connect();
+    first.ping().unwrap();
+
+    // Even writes are retried, the server never saw them.
+    let client = KvsClient::builder(addr)
+        .retry(RetryPolicy {
+            max_retries: 10,
+            ..RetryPolicy::default()
+        })
+        .connect()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        drop(first);
+    });
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    handle.join().unwrap();
+}
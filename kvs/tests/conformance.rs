@@ -0,0 +1,27 @@
+use kvs::{conformance::run_conformance, KvStore, LsmStore, MemoryEngine, SledKvsEngine};
+
+#[test]
+fn kvs_engine() {
+    let dir = tempfile::tempdir().unwrap();
+    run_conformance(dir.path(), |dir| KvStore::open(dir).unwrap());
+}
+
+#[test]
+fn sled_engine() {
+    let dir = tempfile::tempdir().unwrap();
+    run_conformance(dir.path(), |dir| SledKvsEngine::open(dir).unwrap());
+}
+
+#[test]
+fn lsm_engine() {
+    let dir = tempfile::tempdir().unwrap();
+    run_conformance(dir.path(), |dir| LsmStore::open(dir).unwrap());
+}
+
+#[test]
+fn memory_engine() {
+    let dir = tempfile::tempdir().unwrap();
+    run_conformance(dir.path(), |dir| {
+        MemoryEngine::with_snapshot(dir.join("snapshot")).unwrap()
+    });
+}
@@ -1,4 +1,5 @@
-use kvs::{KvStore, Result};
+use kvs::{KvStore, KvsEngine, Limits, LsmStore, Result};
+use std::path::Path;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -79,12 +80,30 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Should reject keys and values over the limits, keeping the previous value
+#[test]
+fn set_over_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let limits = Limits {
+        max_key_size: 8,
+        max_value_size: 16,
+        ..Limits::default()
+    };
+    let mut store = KvStore::open(temp_dir.path())?.with_limits(limits);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.set("k".repeat(9), "value".to_owned()).is_err());
+    assert!(store.set("key1".to_owned(), "v".repeat(17)).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
-#[test]
-fn compaction() -> Result<()> {
+fn compaction<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,13 +133,23 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let store = KvStore::open(temp_dir.path())?;
+        let mut store = open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+            assert_eq!(store.get(&key)?, Some(format!("{}", iter)));
         }
         return Ok(());
     }
 
     panic!("No compaction detected");
 }
+
+#[test]
+fn kv_store_compaction() -> Result<()> {
+    compaction(|dir| Ok(KvStore::open(dir)?))
+}
+
+#[test]
+fn lsm_store_compaction() -> Result<()> {
+    compaction(|dir| Ok(LsmStore::open(dir)?))
+}
@@ -17,7 +17,7 @@ fn cli_no_args() {
 #[test]
 fn cli_version() {
     cli()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -26,7 +26,7 @@ fn cli_version() {
 #[test]
 fn cli_get_non_existent_key() {
     cli()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .assert()
         .success()
         .stdout(eq("Key not found").trim());
@@ -36,7 +36,7 @@ fn cli_get_non_existent_key() {
 #[test]
 fn cli_rm_non_existent_key() {
     cli()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .assert()
         .failure()
         .stdout(eq("Key not found").trim());
@@ -46,7 +46,7 @@ fn cli_rm_non_existent_key() {
 #[test]
 fn cli_set() {
     cli()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .assert()
         .success()
         .stdout(is_empty());
@@ -62,14 +62,14 @@ fn cli_get_stored() -> Result<()> {
     drop(store);
 
     cli()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(eq("value1").trim());
 
     cli()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -88,14 +88,14 @@ fn cli_rm_stored() -> Result<()> {
     drop(store);
 
     cli()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
     cli()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -106,33 +106,216 @@ fn cli_rm_stored() -> Result<()> {
 
 #[test]
 fn cli_invalid_get() {
-    cli().args(&["get"]).assert().failure();
+    cli().args(["get"]).assert().failure();
 
-    cli().args(&["get", "extra", "field"]).assert().failure();
+    cli().args(["get", "extra", "field"]).assert().failure();
 }
 
 #[test]
 fn cli_invalid_set() {
-    cli().args(&["set"]).assert().failure();
+    cli().args(["set"]).assert().failure();
 
-    cli().args(&["set", "missing_field"]).assert().failure();
+    cli().args(["set", "missing_field"]).assert().failure();
 
     cli()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
 
 #[test]
 fn cli_invalid_rm() {
-    cli().args(&["rm"]).assert().failure();
+    cli().args(["rm"]).assert().failure();
 
-    cli().args(&["rm", "extra", "field"]).assert().failure();
+    cli().args(["rm", "extra", "field"]).assert().failure();
 }
 
 #[test]
 fn cli_invalid_subcommand() {
-    cli().args(&["unknown", "subcommand"]).assert().failure();
+    cli().args(["unknown", "subcommand"]).assert().failure();
+}
+
+// Maintenance subcommands should work on a healthy store and keep its content.
+#[test]
+fn cli_maintenance() -> Result<()> {
+    let temp_dir = tempfile::tempdir().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let run = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(&temp_dir).assert().success()
+    };
+
+    run(&["dump"])
+        .stdout(contains("0\tSet\tkey1\tvalue1"))
+        .stdout(contains("Rm\tkey2"));
+    run(&["verify"]).stdout(contains("4 records, 0 corrupted"));
+    run(&["stats"])
+        .stdout(contains("records: 4"))
+        .stdout(contains("live keys: 1"));
+    run(&["repair"]).stdout(contains("4 records recovered, 0 corrupted bytes dropped"));
+    run(&["compact"]);
+    run(&["stats"])
+        .stdout(contains("records: 1"))
+        .stdout(contains("dead bytes: 0"));
+    run(&["get", "key1"]).stdout(eq("value2").trim());
+
+    Ok(())
+}
+
+// `kvs verify` should exit with non-zero code on a corrupted log.
+#[test]
+fn cli_verify_corrupted() -> Result<()> {
+    let temp_dir = tempfile::tempdir().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // Simulate a torn write.
+    let log_path = temp_dir.path().join("log.kvs");
+    let mut data = std::fs::read(&log_path)?;
+    data.extend_from_slice(&[0, 0, 0]);
+    std::fs::write(&log_path, &data)?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("corrupted record at offset"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1 records recovered, 3 corrupted bytes dropped"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Ok(())
+}
+
+// `kvs export` output should be accepted by `kvs import`.
+#[test]
+fn cli_export_import() -> Result<()> {
+    let src_dir = tempfile::tempdir().expect("unable to create temporary working directory");
+    let dst_dir = tempfile::tempdir().expect("unable to create temporary working directory");
+    let dump_path = dst_dir.path().join("dump.csv");
+
+    let mut store = KvStore::open(src_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("other".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "csv", "--prefix", "key"])
+        .arg(&dump_path)
+        .current_dir(&src_dir)
+        .assert()
+        .success()
+        .stderr(contains("exported 2 entries"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "--format", "csv"])
+        .arg(&dump_path)
+        .current_dir(&dst_dir)
+        .assert()
+        .success()
+        .stderr(contains("imported 2 entries"));
+
+    let store = KvStore::open(dst_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, None);
+
+    Ok(())
+}
+
+// `kvs migrate` should copy every entry into the other engine.
+#[test]
+fn cli_migrate() -> Result<()> {
+    let temp_dir = tempfile::tempdir().expect("unable to create temporary working directory");
+    let src_dir = temp_dir.path().join("src");
+    let dst_dir = temp_dir.path().join("dst");
+    std::fs::create_dir(&src_dir)?;
+
+    let mut store = KvStore::open(&src_dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled", "--src"])
+        .arg(&src_dir)
+        .arg("--dst")
+        .arg(&dst_dir)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(
+            "2 entries copied, 0 already copied, 2 entries verified",
+        ));
+
+    let db = sled::open(&dst_dir)?;
+    assert_eq!(db.get("key2")?.as_deref(), Some(&b"value2"[..]));
+
+    Ok(())
+}
+
+// `kvs restore` should bring back the state saved by `kvs backup`.
+#[test]
+fn cli_backup_restore() -> Result<()> {
+    let temp_dir = tempfile::tempdir().expect("unable to create temporary working directory");
+    let backup_dir = tempfile::tempdir().expect("unable to create temporary backup directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("backup")
+        .arg(backup_dir.path())
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("restore")
+        .arg(backup_dir.path())
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
 }
 
 // Should get previously stored value.
@@ -0,0 +1,158 @@
+#![cfg(feature = "tls")]
+
+use assert_cmd::prelude::*;
+use kvs::{
+    client::KvsClientBuilder,
+    tls::{TlsClientConfig, TlsServerConfig},
+    KvsServer, MemoryEngine,
+};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use std::{fs, net::SocketAddr, process::Command, thread, time::Duration};
+use tempfile::TempDir;
+
+struct Authority {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl Authority {
+    fn new(name: &str) -> Self {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, name);
+        let key = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        Self { cert, key }
+    }
+
+    fn pem(&self) -> Vec<u8> {
+        self.cert.pem().into_bytes()
+    }
+
+    /// Sign a certificate, returning it and its private key as PEM.
+    fn issue(&self, name: &str, usage: ExtendedKeyUsagePurpose) -> (Vec<u8>, Vec<u8>) {
+        let mut params = CertificateParams::new(vec!["localhost".to_owned()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![usage];
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+        (cert.pem().into_bytes(), key.serialize_pem().into_bytes())
+    }
+}
+
+fn start_server(addr: &str, config: TlsServerConfig) -> SocketAddr {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let server = KvsServer::new(None, addr).unwrap().with_tls(config);
+    thread::spawn(move || {
+        let mut engine = MemoryEngine::new();
+        server.listen(&mut engine).unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    addr
+}
+
+fn builder(addr: SocketAddr, config: TlsClientConfig) -> KvsClientBuilder {
+    KvsClientBuilder::new()
+        .endpoint(addr)
+        .connect_timeout(Duration::from_secs(5))
+        .read_timeout(Duration::from_secs(5))
+        .tls(config)
+}
+
+// Client should talk to a server whose certificate is signed by the pinned authority only.
+#[test]
+fn tls_server_authentication() {
+    let ca = Authority::new("kvs ca");
+    let (cert, key) = ca.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+    let addr = start_server(
+        "127.0.0.1:4020",
+        TlsServerConfig::new(&cert, &key, None).unwrap(),
+    );
+
+    let client = builder(addr, TlsClientConfig::new("localhost", &ca.pem()).unwrap())
+        .connect()
+        .unwrap();
+    let value = "x".repeat(1024 * 1024);
+    client.set("key".to_owned(), value.clone()).unwrap();
+    assert_eq!(client.get("key".to_owned()).unwrap(), Some(value));
+
+    let other = Authority::new("other ca");
+    let config = TlsClientConfig::new("localhost", &other.pem()).unwrap();
+    assert!(builder(addr, config).connect().is_err());
+
+    let config = TlsClientConfig::new("kvs.example.com", &ca.pem()).unwrap();
+    assert!(builder(addr, config).connect().is_err());
+}
+
+// Server requiring client certificates should reject clients without one.
+#[test]
+fn tls_client_authentication() {
+    let ca = Authority::new("kvs ca");
+    let (cert, key) = ca.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+    let addr = start_server(
+        "127.0.0.1:4021",
+        TlsServerConfig::new(&cert, &key, Some(&ca.pem())).unwrap(),
+    );
+
+    let (client_cert, client_key) = ca.issue("client1", ExtendedKeyUsagePurpose::ClientAuth);
+    let config =
+        TlsClientConfig::with_client_cert("localhost", &ca.pem(), &client_cert, &client_key)
+            .unwrap();
+    let client = builder(addr, config).connect().unwrap();
+    client.ping().unwrap();
+
+    // TLS 1.3 clients only learn of the rejection once they read.
+    let config = TlsClientConfig::new("localhost", &ca.pem()).unwrap();
+    if let Ok(client) = builder(addr, config).connect() {
+        assert!(client.ping().is_err());
+    }
+}
+
+// `kvs-client` should reach a TLS `kvs-server` given the authority, and fail without it.
+#[test]
+fn tls_cli() {
+    let dir = TempDir::new().unwrap();
+    let ca = Authority::new("kvs ca");
+    let (cert, key) = ca.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+    fs::write(dir.path().join("ca.pem"), ca.pem()).unwrap();
+    fs::write(dir.path().join("cert.pem"), cert).unwrap();
+    fs::write(dir.path().join("key.pem"), key).unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", "127.0.0.1:4022"])
+        .args(["--tls-cert", "cert.pem", "--tls-key", "key.pem"])
+        .current_dir(&dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let tls = ["--tls-ca", "ca.pem", "--tls-server-name", "localhost"];
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4022"])
+        .args(tls)
+        .current_dir(&dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4022"])
+        .args(tls)
+        .current_dir(&dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4022"])
+        .current_dir(&dir)
+        .assert()
+        .failure();
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}